@@ -0,0 +1,124 @@
+//! Typed k-mer anchors and chains
+//!
+//! The k-mer matching functions return parallel vectors and `lcskpp_graph` takes them as
+//! positional arguments. `AnchorSet` keeps one `KmerAnchor` per match instead, a `Chainer`
+//! turns it into a `Chain`, and an `Anchorer` owns everything needed to go from a query to
+//! the chain that bands the alignment: k, the seeding, the path index, the topological map
+//! and the chainer.
+use crate::chaining::SharedChainer;
+use crate::error::Result;
+use crate::lcsk::{better_find_kmer_matches_seeded, KmerMatches, PathIndex};
+use crate::packed_kmer::check_match_kmer_size;
+use crate::seeding::Seeding;
+
+/// A k-mer shared by the query and a path of the graph
+#[derive(Default, Clone, Debug, PartialEq, Eq)]
+pub struct KmerAnchor {
+    pub query_pos: u32, // first base of the k-mer in the query
+    pub ranks: Vec<u32>, // rank of every node of the k-mer
+    pub paths: Vec<usize>, // paths the k-mer is on
+    pub previous_ranks: Vec<u32>, // rank before the k-mer on each of those paths, u32::MAX at the start of a path
+}
+
+impl KmerAnchor {
+    /// Rank of the first node
+    pub fn first_rank(&self) -> u32 {
+        self.ranks[0]
+    }
+
+    /// Rank of the last node
+    pub fn last_rank(&self) -> u32 {
+        *self.ranks.last().unwrap()
+    }
+}
+
+/// The anchors of a query, sorted by query position and first rank
+#[derive(Default, Clone, Debug, PartialEq, Eq)]
+pub struct AnchorSet {
+    pub k: usize,
+    pub num_of_paths: usize, // number of paths the anchors can be on
+    pub anchors: Vec<KmerAnchor>,
+}
+
+impl AnchorSet {
+    /// Anchors from the parallel vectors of `better_find_kmer_matches`
+    pub fn from_matches(matches: KmerMatches, k: usize, num_of_paths: usize) -> Self {
+        let (kmer_pos_vec, kmer_path_vec, kmers_previous_node_in_paths, kmer_graph_path) = matches;
+        let anchors = kmer_pos_vec.into_iter().zip(kmer_path_vec).zip(kmers_previous_node_in_paths).zip(kmer_graph_path).map(|(((position, paths), previous_ranks), ranks)| {
+            KmerAnchor { query_pos: position.0, ranks, paths, previous_ranks }
+        }).collect();
+        AnchorSet { k, num_of_paths, anchors }
+    }
+
+    /// Back to the parallel vectors `lcskpp_graph` takes
+    pub fn to_matches(&self) -> KmerMatches {
+        let mut matches: KmerMatches = (vec![], vec![], vec![], vec![]);
+        for anchor in &self.anchors {
+            matches.0.push((anchor.query_pos, anchor.first_rank()));
+            matches.1.push(anchor.paths.clone());
+            matches.2.push(anchor.previous_ranks.clone());
+            matches.3.push(anchor.ranks.clone());
+        }
+        matches
+    }
+
+    /// (query position, first rank) of every anchor, sorted
+    pub fn positions(&self) -> Vec<(u32, u32)> {
+        self.anchors.iter().map(|anchor| (anchor.query_pos, anchor.first_rank())).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.anchors.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.anchors.is_empty()
+    }
+}
+
+/// A chain of anchors as the (query position, node index) pairs `custom_simd_indirect_address` takes
+#[derive(Default, Clone, Debug, PartialEq, Eq)]
+pub struct Chain {
+    pub path: Vec<(usize, usize)>,
+    pub score: u32,
+}
+
+/// Seeds, matches and chains queries against a snapshot of a graph, see `Poa::anchorer`
+pub struct Anchorer {
+    k: usize,
+    seeding: Seeding,
+    index: PathIndex, // paths of the graph by rank and their sequences
+    topo_map: Vec<usize>, // rank -> node index
+    chainer: SharedChainer,
+}
+
+impl Anchorer {
+    pub fn new(k: usize, seeding: Seeding, index: PathIndex, topo_map: Vec<usize>, chainer: SharedChainer) -> Self {
+        Anchorer { k, seeding, index, topo_map, chainer }
+    }
+
+    pub fn k(&self) -> usize {
+        self.k
+    }
+
+    /// Rank -> node index of the graph the anchorer was made for
+    pub fn topo_map(&self) -> &Vec<usize> {
+        &self.topo_map
+    }
+
+    /// k-mer matches of the query against the paths of the index
+    pub fn find_anchors(&self, query: &[u8]) -> Result<AnchorSet> {
+        check_match_kmer_size(self.k)?;
+        let matches = better_find_kmer_matches_seeded(query, &self.index.sequences, &self.index.paths, self.k, self.seeding);
+        Ok(AnchorSet::from_matches(matches, self.k, self.index.paths.len()))
+    }
+
+    pub fn chain(&self, anchors: &AnchorSet) -> Result<Chain> {
+        self.chainer.chain_anchors(anchors)
+    }
+
+    /// Find the anchors of the query and chain them
+    pub fn anchor(&self, query: &[u8]) -> Result<Chain> {
+        self.chain(&self.find_anchors(query)?)
+    }
+}
@@ -0,0 +1,310 @@
+//! Benchmark and accuracy harness
+//!
+//! Every dataset is added read by read to a fresh graph with every aligner variant: the
+//! scalar kernel, the full SIMD kernel and the banded SIMD kernel for each k and band
+//! width. A row per dataset and variant reports throughput, DP cells computed against the
+//! full matrices, peak heap memory (with the `alloc-counter` feature), consensus identity to the truth and the band
+//! escapes (with `--escapes`, a scalar alignment of every read outside the measured time).
+//! Datasets are simulated (see `simulate`) or read from FASTA. Reads are added on the
+//! strand they are given.
+use std::fmt;
+use std::time::Instant;
+use crate::alloc_counter::{counting, live_bytes, peak_bytes, reset_peak};
+use crate::error::{PoaError, Result};
+use crate::poa::{check_sequence, Aligner, AlignerWorkspace};
+use crate::simulate::{identity, simulate, ErrorProfile, SimulationConfig};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Variant {
+    Scalar,
+    Simd,
+    Banded { k: usize, band: usize },
+}
+
+impl fmt::Display for Variant {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Variant::Scalar => write!(f, "scalar"),
+            Variant::Simd => write!(f, "simd"),
+            Variant::Banded { .. } => write!(f, "banded"),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Dataset {
+    pub name: String,
+    pub reads: Vec<Vec<u8>>, // the first valid one starts the graph
+    pub truth: Option<Vec<u8>>,
+}
+
+/// What one variant did on one dataset
+#[derive(Clone, Debug)]
+pub struct BenchRow {
+    pub dataset: String,
+    pub variant: Variant,
+    pub reads: usize, // aligned, all but the first
+    pub bases: usize,
+    pub seconds: f64, // in the aligner, anchoring included for the banded kernel
+    pub cells: usize, // DP cells computed
+    pub full_cells: usize, // cells of the full matrices, graph nodes times read length
+    pub peak_bytes: Option<usize>, // heap peak while aligning over what was live before the first read, with the alloc-counter feature
+    pub identity: Option<f64>, // of the consensus to the truth
+    pub band_escapes: Option<usize>, // banded alignments scoring below the scalar one, or rejected, when checked
+    pub rejected: usize, // reads `Poa::check_alignable` refused and alignments the graph did not take
+}
+
+impl BenchRow {
+    pub const TSV_HEADER: &'static str = "dataset\tvariant\tk\tband\treads\tbases\tseconds\tbases_per_second\tcells\tfull_cells\tcell_fraction\tpeak_bytes\tidentity\tband_escape_rate\trejected";
+
+    pub fn to_tsv(&self) -> String {
+        let (k, band) = self.k_band("NA");
+        let identity = self.identity.map_or("NA".to_string(), |identity| format!("{:.4}", identity));
+        let peak_bytes = self.peak_bytes.map_or("NA".to_string(), |peak_bytes| peak_bytes.to_string());
+        let band_escape_rate = self.band_escape_rate().map_or("NA".to_string(), |rate| format!("{:.4}", rate));
+        format!("{}\t{}\t{}\t{}\t{}\t{}\t{:.6}\t{:.0}\t{}\t{}\t{:.4}\t{}\t{}\t{}\t{}", self.dataset, self.variant, k, band, self.reads, self.bases, self.seconds, self.bases_per_second(), self.cells, self.full_cells, self.cell_fraction(), peak_bytes, identity, band_escape_rate, self.rejected)
+    }
+
+    pub fn to_json(&self) -> String {
+        let (k, band) = self.k_band("null");
+        let identity = self.identity.map_or("null".to_string(), |identity| format!("{:.4}", identity));
+        let peak_bytes = self.peak_bytes.map_or("null".to_string(), |peak_bytes| peak_bytes.to_string());
+        let band_escape_rate = self.band_escape_rate().map_or("null".to_string(), |rate| format!("{:.4}", rate));
+        format!("{{\"dataset\": \"{}\", \"variant\": \"{}\", \"k\": {}, \"band\": {}, \"reads\": {}, \"bases\": {}, \"seconds\": {:.6}, \"bases_per_second\": {:.0}, \"cells\": {}, \"full_cells\": {}, \"cell_fraction\": {:.4}, \"peak_bytes\": {}, \"identity\": {}, \"band_escape_rate\": {}, \"rejected\": {}}}", self.dataset.replace('"', "\\\""), self.variant, k, band, self.reads, self.bases, self.seconds, self.bases_per_second(), self.cells, self.full_cells, self.cell_fraction(), peak_bytes, identity, band_escape_rate, self.rejected)
+    }
+
+    pub fn bases_per_second(&self) -> f64 {
+        self.bases as f64 / self.seconds.max(1e-9)
+    }
+
+    pub fn cell_fraction(&self) -> f64 {
+        self.cells as f64 / self.full_cells.max(1) as f64
+    }
+
+    pub fn band_escape_rate(&self) -> Option<f64> {
+        self.band_escapes.map(|band_escapes| band_escapes as f64 / self.reads.max(1) as f64)
+    }
+
+    // k and band as printed, `missing` for the unbanded kernels
+    fn k_band(&self, missing: &str) -> (String, String) {
+        match self.variant {
+            Variant::Banded { k, band } => (k.to_string(), band.to_string()),
+            _ => (missing.to_string(), missing.to_string()),
+        }
+    }
+}
+
+/// Datasets, variants and output format of a bench run
+#[derive(Clone, Debug)]
+pub struct BenchConfig {
+    pub profiles: Vec<String>, // simulated datasets, see `ErrorProfile::from_name`
+    pub length: usize,
+    pub reads: usize,
+    pub seed: u64,
+    pub fasta: Option<String>, // reads from a file instead of the simulated datasets
+    pub truth: Option<String>, // first record is the truth of the fasta reads
+    pub kmer_sizes: Vec<usize>,
+    pub band_sizes: Vec<usize>,
+    pub json: bool,
+    pub escapes: bool, // align every read with the scalar kernel too, to count the band escapes
+    pub out: Option<String>, // file for the rows instead of stdout
+}
+
+impl Default for BenchConfig {
+    fn default() -> Self {
+        BenchConfig {
+            profiles: vec!["ont".to_string(), "clr".to_string(), "hifi".to_string()],
+            length: 1000,
+            reads: 10,
+            seed: 0,
+            fasta: None,
+            truth: None,
+            kmer_sizes: vec![4, 8, 12],
+            band_sizes: vec![10, 20, 50],
+            json: false,
+            escapes: false,
+            out: None,
+        }
+    }
+}
+
+impl BenchConfig {
+    /// Parse `--profiles ont,clr --length 1000 --reads 10 --seed 0 --fasta reads.fa
+    /// --truth truth.fa --k 4,8 --band 10,20 --json --escapes --out bench.tsv`, anything not
+    /// given keeps its default
+    pub fn from_args(args: &[String]) -> std::result::Result<BenchConfig, String> {
+        let mut config = BenchConfig::default();
+        let mut args = args.iter();
+        while let Some(flag) = args.next() {
+            if flag == "--json" {
+                config.json = true;
+                continue;
+            }
+            if flag == "--escapes" {
+                config.escapes = true;
+                continue;
+            }
+            let value = args.next().ok_or(format!("{} needs a value", flag))?;
+            let number = |value: &str| value.parse::<usize>().map_err(|_| format!("{} takes numbers, got {}", flag, value));
+            match flag.as_str() {
+                "--profiles" => config.profiles = value.split(',').map(|name| name.to_string()).collect(),
+                "--length" => config.length = number(value)?,
+                "--reads" => config.reads = number(value)?,
+                "--seed" => config.seed = number(value)? as u64,
+                "--fasta" => config.fasta = Some(value.clone()),
+                "--truth" => config.truth = Some(value.clone()),
+                "--out" => config.out = Some(value.clone()),
+                "--k" => config.kmer_sizes = value.split(',').map(number).collect::<std::result::Result<_, _>>()?,
+                "--band" => config.band_sizes = value.split(',').map(number).collect::<std::result::Result<_, _>>()?,
+                _ => return Err(format!("unknown option {}", flag)),
+            }
+        }
+        for name in &config.profiles {
+            if ErrorProfile::from_name(name).is_none() {
+                return Err(format!("unknown profile {}, use ont, clr or hifi", name));
+            }
+        }
+        Ok(config)
+    }
+
+    pub fn datasets(&self) -> Result<Vec<Dataset>> {
+        if let Some(path) = &self.fasta {
+            let truth = match &self.truth {
+                Some(truth_path) => read_fasta(truth_path)?.into_iter().next(),
+                None => None,
+            };
+            return Ok(vec![Dataset { name: path.clone(), reads: read_fasta(path)?, truth }]);
+        }
+        Ok(self.profiles.iter().map(|name| {
+            let config = SimulationConfig { length: self.length, reads: self.reads, profile: ErrorProfile::from_name(name).unwrap(), reverse_fraction: 0.0, chimera_fraction: 0.0, seed: self.seed };
+            let simulation = simulate(&config);
+            Dataset { name: name.clone(), reads: simulation.sequences(), truth: Some(simulation.truth) }
+        }).collect())
+    }
+
+    pub fn variants(&self) -> Vec<Variant> {
+        let mut variants = vec![Variant::Scalar, Variant::Simd];
+        for k in &self.kmer_sizes {
+            for band in &self.band_sizes {
+                variants.push(Variant::Banded { k: *k, band: *band });
+            }
+        }
+        variants
+    }
+}
+
+/// Build the dataset's graph with one variant and measure it. Reads the graph cannot align
+/// are counted as rejected and skipped.
+pub fn bench_variant(dataset: &Dataset, variant: Variant, check_escapes: bool) -> Result<BenchRow> {
+    // the reads before the first valid one are rejected like the later bad reads
+    let first = dataset.reads.iter().position(|read| check_sequence(read).is_ok()).ok_or(PoaError::EmptySequence)?;
+    let mut aligner = Aligner::new(1, -1, -2, &dataset.reads[first])?;
+    let mut workspace = AlignerWorkspace::new();
+    let mut row = BenchRow { dataset: dataset.name.clone(), variant, reads: 0, bases: 0, seconds: 0.0, cells: 0, full_cells: 0, peak_bytes: None, identity: None, band_escapes: None, rejected: first };
+    let baseline = live_bytes();
+    for read in &dataset.reads[first + 1..] {
+        let poa = aligner.poa_mut();
+        if poa.check_alignable(read).is_err() {
+            row.rejected += 1;
+            continue;
+        }
+        let nodes = poa.compact_graph().node_count();
+        reset_peak();
+        let now = Instant::now();
+        let (alignment, cells) = match variant {
+            Variant::Scalar => (poa.custom(read).alignment(), nodes * read.len()),
+            Variant::Simd => (poa.custom_simd(read), nodes * read.len().div_ceil(8) * 8),
+            Variant::Banded { k, band } => {
+                let lcsk_path = poa.anchor_query(read, k)?;
+                let alignment = poa.custom_simd_indirect_address_with_workspace(read, &lcsk_path, band, &mut workspace);
+                (alignment, workspace.banded_cells())
+            }
+        };
+        row.seconds += now.elapsed().as_secs_f64();
+        if counting() {
+            row.peak_bytes = Some(row.peak_bytes.unwrap_or(0).max(peak_bytes().saturating_sub(baseline)));
+        }
+        row.reads += 1;
+        row.bases += read.len();
+        row.cells += cells;
+        row.full_cells += nodes * read.len();
+        // the scalar kernel is the reference for the band, outside the measured time
+        let checked = check_escapes && matches!(variant, Variant::Banded { .. });
+        let escaped = checked && alignment.score < poa.custom(read).alignment().score;
+        let rejected = poa.add_alignment(&alignment, read).is_err();
+        row.rejected += rejected as usize;
+        if checked {
+            row.band_escapes = Some(row.band_escapes.unwrap_or(0) + (escaped || rejected) as usize);
+        }
+    }
+    row.identity = dataset.truth.as_ref().map(|truth| identity(&aligner.consensus(), truth));
+    Ok(row)
+}
+
+/// Run every variant on every dataset and write the rows as TSV or as a JSON array, to the
+/// output file or else to stdout
+pub fn run_bench(config: &BenchConfig) -> Result<()> {
+    let mut rows = vec![];
+    for dataset in config.datasets()? {
+        for variant in config.variants() {
+            rows.push(bench_variant(&dataset, variant, config.escapes)?);
+        }
+    }
+    let lines: Vec<String> = if config.json {
+        let last = rows.len().saturating_sub(1);
+        let objects = rows.iter().enumerate().map(|(index, row)| format!("  {}{}", row.to_json(), if index < last { "," } else { "" }));
+        std::iter::once("[".to_string()).chain(objects).chain(std::iter::once("]".to_string())).collect()
+    } else {
+        std::iter::once(BenchRow::TSV_HEADER.to_string()).chain(rows.iter().map(|row| row.to_tsv())).collect()
+    };
+    match &config.out {
+        Some(path) => std::fs::write(path, lines.join("\n") + "\n").map_err(|error| PoaError::Io { path: path.clone(), message: error.to_string() }),
+        None => {
+            for line in lines {
+                println!("{}", line);
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Sequences of a FASTA file, upper cased
+pub fn read_fasta(path: &str) -> Result<Vec<Vec<u8>>> {
+    let text = std::fs::read_to_string(path).map_err(|error| PoaError::Io { path: path.to_string(), message: error.to_string() })?;
+    let mut sequences: Vec<Vec<u8>> = vec![];
+    for line in text.lines() {
+        let line = line.trim();
+        if line.starts_with('>') {
+            sequences.push(vec![]);
+        } else if let Some(sequence) = sequences.last_mut() {
+            sequence.extend(line.bytes().map(|base| base.to_ascii_uppercase()));
+        }
+    }
+    Ok(sequences)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bad_reads_are_skipped() {
+        let simulation = simulate(&SimulationConfig { length: 200, reads: 6, profile: ErrorProfile::pacbio_hifi(), reverse_fraction: 0.0, chimera_fraction: 0.0, seed: 0 });
+        let mut reads = simulation.sequences();
+        let mut with_n = reads[2].clone();
+        with_n[100] = b'N';
+        reads.insert(2, with_n);
+        reads.insert(4, vec![]);
+        // a bad first read, the graph starts with the next one
+        reads.insert(0, b"NNNN".to_vec());
+        let dataset = Dataset { name: "bad".to_string(), reads, truth: Some(simulation.truth) };
+        for variant in [Variant::Scalar, Variant::Simd, Variant::Banded { k: 8, band: 20 }] {
+            let row = bench_variant(&dataset, variant, true).unwrap();
+            // the bad reads are not aligned, the good ones all are
+            assert_eq!((row.reads, row.rejected), (5, 3), "{}", variant);
+            assert!(row.identity.unwrap() > 0.95);
+        }
+        let empty = Dataset { name: "empty".to_string(), reads: vec![vec![]], truth: None };
+        assert!(bench_variant(&empty, Variant::Simd, false).is_err());
+    }
+}
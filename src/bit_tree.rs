@@ -9,30 +9,21 @@
 //!
 //! Time Complexity: O(log n) where `n = tree.len()`.
 //! Memory Complexity: O(n) where `n = tree.len()`.
-//! # Example for a max bit tree
 //!
-//! ```
-//! use bio::data_structures::bit_tree::*;
-//!
-//! let mut bit = MaxBitTree::new(10);
-//! bit.set(0, (1,0));
-//! bit.set(1, (0,1));
-//! bit.set(2, (2,2));
-//! bit.set(3, (4,3));
-//!
-//! assert_eq!(bit.get(0), (1, 0));
-//! assert_eq!(bit.get(1), (1, 0));
-//! assert_eq!(bit.get(2), (2, 2));
-//! assert_eq!(bit.get(3), (4, 3));
-//! assert_eq!(bit.get(4), (4, 3));
+//! LCSk++ needs a tree per path for every read. A `FenwickTreePool` keeps the buffers of
+//! the trees of the last read, and a `CompressedFenwickTree` only has a slot for the graph
+//! positions anchors end at instead of one for every position up to the last of them.
+//! `RangeFenwickTree` also answers queries over a range of positions, at twice the memory.
 
-use std::cmp::max;
+use std::cmp::{max, min};
 use std::marker::PhantomData;
 use std::ops::Add;
 
 /// Fenwick tree prefix operator
 pub trait PrefixOp<T> {
     fn operation(t1: T, t2: T) -> T;
+    /// Value of a position nothing was set at, `operation(identity(), t) == t`
+    fn identity() -> T;
 }
 
 /// In a max bit tree or Fenwick Tree, get(i) will return the largest element e that has been added
@@ -41,6 +32,7 @@ pub trait PrefixOp<T> {
 /// a smaller element at the same index.
 /// Time Complexity: O(n) to build a new tree or O(log n) for get() and set() operations,
 /// where `n = tree.len()`.
+#[derive(Debug)]
 pub struct FenwickTree<T: Default + Ord, Op: PrefixOp<T>> {
     tree: Vec<T>,
     phantom: PhantomData<Op>,
@@ -52,16 +44,36 @@ impl<T: Ord + Default + Copy, Op: PrefixOp<T>> FenwickTree<T, Op> {
         // Pad length by one. The first element is unused.
         // Done this way to make the tree structure work correctly.
         FenwickTree {
-            tree: vec![T::default(); len + 1],
+            tree: vec![Op::identity(); len + 1],
             phantom: PhantomData,
         }
     }
 
+    /// Number of positions
+    pub fn len(&self) -> usize {
+        self.tree.len() - 1
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Undo every set, keeping the length
+    pub fn clear(&mut self) {
+        self.tree.fill(Op::identity());
+    }
+
+    /// Clear the tree and change its length, the allocation is kept when it is large enough
+    pub fn reset(&mut self, len: usize) {
+        self.clear();
+        self.tree.resize(len + 1, Op::identity());
+    }
+
     /// Returns the largest element e that has been added
     /// to the bit tree with set(j, e), where j <= i.
     pub fn get(&self, idx: usize) -> T {
         let mut idx = idx + 1;
-        let mut sum = T::default();
+        let mut sum = Op::identity();
         while idx > 0 {
             sum = Op::operation(sum, self.tree[idx]);
             idx -= (idx as isize & -(idx as isize)) as usize;
@@ -84,22 +96,382 @@ impl<T: Ord + Default + Copy, Op: PrefixOp<T>> FenwickTree<T, Op> {
     }
 }
 
+#[derive(Debug)]
 pub struct MaxOp;
-impl<T: Copy + Ord> PrefixOp<T> for MaxOp {
+impl<T: Copy + Ord + Default> PrefixOp<T> for MaxOp {
     fn operation(t1: T, t2: T) -> T {
         max(t1, t2)
     }
+
+    fn identity() -> T {
+        T::default()
+    }
 }
 
 /// Fenwick tree specialized for prefix-max
 pub type MaxBitTree<T> = FenwickTree<T, MaxOp>;
 
+/// Largest value of a type, the identity of `MinOp`
+pub trait MaxValue {
+    fn max_value() -> Self;
+}
+
+macro_rules! impl_max_value {
+    ($($t:ty),*) => {
+        $(impl MaxValue for $t {
+            fn max_value() -> Self {
+                <$t>::MAX
+            }
+        })*
+    };
+}
+
+impl_max_value!(u8, u16, u32, u64, usize, i8, i16, i32, i64, isize);
+
+impl<A: MaxValue, B: MaxValue> MaxValue for (A, B) {
+    fn max_value() -> Self {
+        (A::max_value(), B::max_value())
+    }
+}
+
+#[derive(Debug)]
+pub struct MinOp;
+impl<T: Copy + Ord + MaxValue> PrefixOp<T> for MinOp {
+    fn operation(t1: T, t2: T) -> T {
+        min(t1, t2)
+    }
+
+    fn identity() -> T {
+        T::max_value()
+    }
+}
+
+/// Fenwick tree specialized for prefix-min, positions nothing was set at are `T::MAX`
+pub type MinBitTree<T> = FenwickTree<T, MinOp>;
+
+#[derive(Debug)]
 pub struct SumOp;
-impl<T: Copy + Add> PrefixOp<T> for SumOp
+impl<T: Copy + Add + Default> PrefixOp<T> for SumOp
 where
     T: Add<Output = T>,
 {
     fn operation(t1: T, t2: T) -> T {
         t1 + t2
     }
-}
\ No newline at end of file
+
+    fn identity() -> T {
+        T::default()
+    }
+}
+
+/// Fenwick tree specialized for prefix-sum, set adds to a position
+pub type SumBitTree<T> = FenwickTree<T, SumOp>;
+
+/// Fenwick tree that also keeps everything set at each position, which the range queries
+/// need on top of the prefix tree.
+#[derive(Debug)]
+pub struct RangeFenwickTree<T: Default + Ord, Op: PrefixOp<T>> {
+    tree: FenwickTree<T, Op>,
+    values: Vec<T>, // everything set at each position combined, 1 based like the tree
+}
+
+impl<T: Ord + Default + Copy, Op: PrefixOp<T>> RangeFenwickTree<T, Op> {
+    pub fn new(len: usize) -> Self {
+        RangeFenwickTree { tree: FenwickTree::new(len), values: vec![Op::identity(); len + 1] }
+    }
+
+    pub fn len(&self) -> usize {
+        self.tree.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.tree.clear();
+        self.values.fill(Op::identity());
+    }
+
+    pub fn reset(&mut self, len: usize) {
+        self.tree.reset(len);
+        self.values.fill(Op::identity());
+        self.values.resize(len + 1, Op::identity());
+    }
+
+    pub fn get(&self, idx: usize) -> T {
+        self.tree.get(idx)
+    }
+
+    /// Combines the elements added with set(j, e) where from <= j <= to, the range max of a
+    /// `RangeMaxBitTree`. O(log² n), positions past the end are ignored.
+    pub fn range(&self, from: usize, to: usize) -> T {
+        let mut result = Op::identity();
+        if from > to || from >= self.len() {
+            return result;
+        }
+        // tree[idx] combines the positions idx - lowbit(idx) + 1..=idx (1 based)
+        let (from, mut idx) = (from + 1, min(to, self.len() - 1) + 1);
+        while idx >= from {
+            let lowbit = (idx as isize & -(idx as isize)) as usize;
+            if idx - lowbit + 1 >= from {
+                result = Op::operation(result, self.tree.tree[idx]);
+                idx -= lowbit;
+            } else {
+                result = Op::operation(result, self.values[idx]);
+                idx -= 1;
+            }
+        }
+        result
+    }
+
+    pub fn set(&mut self, idx: usize, val: T) {
+        if idx < self.len() {
+            self.values[idx + 1] = Op::operation(self.values[idx + 1], val);
+        }
+        self.tree.set(idx, val);
+    }
+}
+
+/// Fenwick tree specialized for prefix-max and range-max
+pub type RangeMaxBitTree<T> = RangeFenwickTree<T, MaxOp>;
+
+/// Trees handed back after use, `take` reuses their allocations
+#[derive(Debug)]
+pub struct FenwickTreePool<T: Default + Ord, Op: PrefixOp<T>> {
+    trees: Vec<FenwickTree<T, Op>>,
+}
+
+impl<T: Ord + Default + Copy, Op: PrefixOp<T>> FenwickTreePool<T, Op> {
+    pub fn new() -> Self {
+        FenwickTreePool { trees: vec![] }
+    }
+
+    /// A cleared tree with len elements, one handed back earlier if there is one
+    pub fn take(&mut self, len: usize) -> FenwickTree<T, Op> {
+        match self.trees.pop() {
+            Some(mut tree) => {
+                tree.reset(len);
+                tree
+            }
+            None => FenwickTree::new(len),
+        }
+    }
+
+    /// Hand a tree back for reuse
+    pub fn put(&mut self, tree: FenwickTree<T, Op>) {
+        self.trees.push(tree);
+    }
+
+    /// Number of trees waiting to be reused
+    pub fn len(&self) -> usize {
+        self.trees.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.trees.is_empty()
+    }
+}
+
+impl<T: Ord + Default + Copy, Op: PrefixOp<T>> Default for FenwickTreePool<T, Op> {
+    fn default() -> Self {
+        FenwickTreePool::new()
+    }
+}
+
+pub type MaxBitTreePool<T> = FenwickTreePool<T, MaxOp>;
+
+/// Fenwick tree over a sparse set of keys, such as the graph ranks the anchors of a path end
+/// at. get(key) combines the elements set at the keys <= key, which do not have to be keys of
+/// the tree themselves; set panics for a key the tree was not built with.
+/// Time Complexity: O(log n) for get() and set(), where `n` is the number of keys.
+pub struct CompressedFenwickTree<T: Default + Ord, Op: PrefixOp<T>> {
+    keys: Vec<usize>, // sorted without duplicates, the position of a key in the tree
+    tree: FenwickTree<T, Op>,
+}
+
+impl<T: Ord + Default + Copy, Op: PrefixOp<T>> CompressedFenwickTree<T, Op> {
+    pub fn new(keys: Vec<usize>) -> Self {
+        CompressedFenwickTree::from_tree(keys, FenwickTree::new(0))
+    }
+
+    /// Build over the keys reusing the allocation of `tree`, e.g. one from a `FenwickTreePool`
+    pub fn from_tree(mut keys: Vec<usize>, mut tree: FenwickTree<T, Op>) -> Self {
+        keys.sort_unstable();
+        keys.dedup();
+        tree.reset(keys.len());
+        CompressedFenwickTree { keys, tree }
+    }
+
+    /// The underlying tree, to hand back to a pool
+    pub fn into_tree(self) -> FenwickTree<T, Op> {
+        self.tree
+    }
+
+    /// Number of keys
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// Undo every set, keeping the keys
+    pub fn clear(&mut self) {
+        self.tree.clear();
+    }
+
+    pub fn get(&self, key: usize) -> T {
+        match self.keys.partition_point(|other| *other <= key) {
+            0 => Op::identity(),
+            position => self.tree.get(position - 1),
+        }
+    }
+
+    pub fn set(&mut self, key: usize, val: T) {
+        let position = self.keys.binary_search(&key).unwrap_or_else(|_| panic!("key {} is not in the compressed tree", key));
+        self.tree.set(position, val);
+    }
+}
+
+/// Compressed Fenwick tree specialized for prefix-max
+pub type CompressedMaxBitTree<T> = CompressedFenwickTree<T, MaxOp>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng, rngs::StdRng};
+
+    #[test]
+    fn max_tree_prefix() {
+        let mut bit = MaxBitTree::new(10);
+        bit.set(0, (1, 0));
+        bit.set(1, (0, 1));
+        bit.set(2, (2, 2));
+        bit.set(3, (4, 3));
+        assert_eq!(bit.get(0), (1, 0));
+        assert_eq!(bit.get(1), (1, 0));
+        assert_eq!(bit.get(2), (2, 2));
+        assert_eq!(bit.get(3), (4, 3));
+        assert_eq!(bit.get(9), (4, 3));
+    }
+
+    #[test]
+    fn clear_and_reset() {
+        let mut bit = MaxBitTree::new(10);
+        bit.set(3, 7u32);
+        bit.clear();
+        assert_eq!(bit.len(), 10);
+        assert_eq!(bit.get(9), 0);
+        bit.set(5, 2);
+        bit.reset(20);
+        assert_eq!(bit.len(), 20);
+        assert!((0..20).all(|idx| bit.get(idx) == 0));
+        bit.set(15, 4);
+        assert_eq!(bit.get(14), 0);
+        assert_eq!(bit.get(19), 4);
+        bit.reset(0);
+        assert!(bit.is_empty());
+    }
+
+    #[test]
+    fn range_matches_brute_force_max() {
+        let mut rng = StdRng::seed_from_u64(0);
+        for len in [1, 7, 64, 100] {
+            let mut bit = RangeMaxBitTree::new(len);
+            let mut values = vec![0u32; len];
+            for _ in 0..2 * len {
+                let (idx, val) = (rng.gen_range(0..len), rng.gen_range(0..1000));
+                bit.set(idx, val);
+                values[idx] = max(values[idx], val);
+            }
+            for from in 0..len {
+                for to in from..len + 2 {
+                    let expected = values[from..min(to + 1, len)].iter().copied().max().unwrap_or(0);
+                    assert_eq!(bit.range(from, to), expected, "range {}..={} of {}", from, to, len);
+                }
+            }
+            assert_eq!(bit.range(len, len + 5), 0);
+            if len > 1 {
+                assert_eq!(bit.range(1, 0), 0);
+            }
+            bit.reset(len / 2);
+            assert_eq!(bit.range(0, len), 0);
+        }
+    }
+
+    #[test]
+    fn min_tree() {
+        let mut bit = MinBitTree::new(10);
+        bit.set(4, 7u32);
+        bit.set(2, 9);
+        assert_eq!(bit.get(1), u32::MAX);
+        assert_eq!(bit.get(3), 9);
+        assert_eq!(bit.get(9), 7);
+        bit.clear();
+        assert_eq!(bit.get(9), u32::MAX);
+    }
+
+    #[test]
+    fn sum_tree() {
+        let mut bit = SumBitTree::new(10);
+        bit.set(1, 2);
+        bit.set(5, 3);
+        bit.set(5, 1);
+        assert_eq!(bit.get(0), 0);
+        assert_eq!(bit.get(4), 2);
+        assert_eq!(bit.get(9), 6);
+        let mut range_bit: RangeFenwickTree<i32, SumOp> = RangeFenwickTree::new(10);
+        range_bit.set(1, 2);
+        range_bit.set(5, 3);
+        range_bit.set(5, 1);
+        assert_eq!(range_bit.range(2, 9), 4);
+        assert_eq!(range_bit.range(0, 4), 2);
+    }
+
+    #[test]
+    fn pool_reuses_cleared_trees() {
+        let mut pool: MaxBitTreePool<(u32, u32)> = FenwickTreePool::new();
+        assert!(pool.is_empty());
+        for read in 0..3 {
+            let mut bit = pool.take(5 + read as usize);
+            assert_eq!(bit.len(), 5 + read as usize);
+            assert!((0..bit.len()).all(|idx| bit.get(idx) == (0, 0)));
+            bit.set(2, (4, read));
+            pool.put(bit);
+        }
+        assert_eq!(pool.len(), 1);
+        let (first, second) = (pool.take(3), pool.take(3));
+        assert_eq!((first.get(2), second.get(2)), ((0, 0), (0, 0)));
+        assert!(pool.is_empty());
+    }
+
+    #[test]
+    fn compressed_get_between_keys() {
+        let mut pool: MaxBitTreePool<(u32, u32)> = FenwickTreePool::new();
+        for read in 0..2 {
+            let mut bit = CompressedFenwickTree::from_tree(vec![1000, 20, 300, 20], pool.take(3));
+            assert_eq!(bit.len(), 3);
+            bit.set(20, (4, read));
+            bit.set(300, (2, read));
+            assert_eq!(bit.get(0), (0, 0));
+            assert_eq!(bit.get(19), (0, 0));
+            assert_eq!(bit.get(20), (4, read));
+            assert_eq!(bit.get(299), (4, read));
+            assert_eq!(bit.get(5000), (4, read));
+            bit.set(1000, (6, read));
+            assert_eq!(bit.get(999), (4, read));
+            assert_eq!(bit.get(1000), (6, read));
+            pool.put(bit.into_tree());
+        }
+        assert_eq!(pool.len(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "key 21 is not in the compressed tree")]
+    fn compressed_set_needs_a_key() {
+        let mut bit: CompressedMaxBitTree<u32> = CompressedFenwickTree::new(vec![20, 300]);
+        bit.set(21, 1);
+    }
+}
@@ -0,0 +1,198 @@
+//! Anchor chaining
+//!
+//! The k-mer matches of a query are chained into the (query position, node index) path that
+//! bands `custom_simd_indirect_address`. LCSk++ maximizes the number of k-mer bases in the
+//! chain, either along the read paths (`lcskpp_graph`) or over a path cover of the graph.
+//! The gap cost chainer scores like minimap2 instead: every link gains the bases it adds and
+//! pays a concave cost for the difference of its query and graph distances, so chains with
+//! implausible jumps through repeats lose against co-linear ones.
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use crate::anchor::{AnchorSet, Chain};
+use crate::bit_tree::MaxBitTreePool;
+use crate::compact_graph::CompactPoaGraph;
+use crate::error::{PoaError, Result};
+use crate::lcsk::lcskpp_graph_pooled;
+use crate::path_cover::PathCoverChainer;
+
+/// How `Poa::anchor_query` chains the k-mer anchors
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum Chaining {
+    /// LCSk++ with one Fenwick tree per read path, anchors chain along a shared path (`lcskpp_graph`)
+    #[default]
+    PerPath,
+    /// LCSk++ with one Fenwick tree per path of a minimum path cover, anchors chain when one reaches the other
+    PathCover,
+    /// minimap2 style chaining with a concave gap cost
+    GapCost(GapCost),
+}
+
+/// Chains k-mer anchors into the path `custom_simd_indirect_address` takes
+pub trait Chainer: fmt::Debug + Send + Sync {
+    fn chain_anchors(&self, anchors: &AnchorSet) -> Result<Chain>;
+}
+
+/// A chainer shared by the graph that keeps it and the anchorers made from that graph
+pub type SharedChainer = Arc<dyn Chainer>;
+
+/// LCSk++ trees kept by the graph across queries and changes of the graph
+pub type SharedTreePool = Arc<Mutex<MaxBitTreePool<(u32, u32)>>>;
+
+/// LCSk++ along the read paths, see `lcskpp_graph`. The Fenwick trees come from and go back
+/// to the pool of the graph.
+#[derive(Debug)]
+pub struct LcskChainer {
+    topo_map: Vec<usize>, // rank -> node index
+    pool: SharedTreePool,
+}
+
+impl LcskChainer {
+    pub fn new(topo_map: Vec<usize>, pool: SharedTreePool) -> Self {
+        LcskChainer { topo_map, pool }
+    }
+}
+
+impl Chainer for LcskChainer {
+    fn chain_anchors(&self, anchors: &AnchorSet) -> Result<Chain> {
+        let (kmer_pos_vec, kmer_path_vec, kmers_previous_node_in_paths, kmer_graph_path) = anchors.to_matches();
+        let (path, _, score) = lcskpp_graph_pooled(kmer_pos_vec, kmer_path_vec, kmers_previous_node_in_paths, anchors.num_of_paths, anchors.k, kmer_graph_path, &self.topo_map, &mut self.pool.lock().unwrap())?;
+        Ok(Chain { path, score })
+    }
+}
+
+impl Chainer for PathCoverChainer {
+    fn chain_anchors(&self, anchors: &AnchorSet) -> Result<Chain> {
+        let ranks: Vec<Vec<u32>> = anchors.anchors.iter().map(|anchor| anchor.ranks.clone()).collect();
+        let (path, _, score) = self.chain(&anchors.positions(), &ranks, anchors.k)?;
+        Ok(Chain { path, score })
+    }
+}
+
+/// Parameters of the gap cost chaining. The defaults are the ones of minimap2 except for
+/// `max_skip`, doubled as the same position of the graph gets anchors from several paths.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct GapCost {
+    pub max_gap: u32, // anchors further apart than this in the query or the graph are not chained
+    pub max_iterations: usize, // earlier anchors tried at most for every anchor
+    pub max_skip: usize, // stop after this many earlier anchors already chained through a tried one did not improve the score
+    pub gap_scale: f32, // scales the gap cost
+}
+
+impl Default for GapCost {
+    fn default() -> Self {
+        GapCost { max_gap: 5000, max_iterations: 5000, max_skip: 50, gap_scale: 1.0 }
+    }
+}
+
+/// minimap2 style chaining on the graph. The graph distance of two anchors is the difference
+/// of the longest path lengths from a source to their last nodes, and anchors only chain when
+/// the first reaches the second (or overlaps it shifted along the same nodes).
+#[derive(Debug)]
+pub struct GapCostChainer {
+    gap_cost: GapCost,
+    cover: Arc<PathCoverChainer>, // answers the reachability queries
+    levels: Vec<u32>, // rank -> longest path length from a source
+    node_ids: Vec<usize>,
+}
+
+impl GapCostChainer {
+    /// `cover` is the path cover chainer of the same graph, it is only used for reachability
+    pub fn new(compact: &CompactPoaGraph, gap_cost: GapCost, cover: Arc<PathCoverChainer>) -> Self {
+        let mut levels = vec![0; compact.node_count()];
+        for rank in 0..compact.node_count() {
+            levels[rank] = compact.predecessors(rank).iter().map(|predecessor| levels[*predecessor] + 1).max().unwrap_or(0);
+        }
+        GapCostChainer { gap_cost, cover, levels, node_ids: compact.node_ids().to_vec() }
+    }
+
+    // gap cost of a link whose query and graph distances differ by `difference`
+    fn gap_penalty(&self, difference: u32, k: usize) -> f32 {
+        if difference == 0 {
+            return 0.0;
+        }
+        self.gap_cost.gap_scale * (0.01 * k as f32 * difference as f32 + 0.5 * (difference as f32).log2())
+    }
+}
+
+impl Chainer for GapCostChainer {
+    fn chain_anchors(&self, anchors: &AnchorSet) -> Result<Chain> {
+        let (k, anchors) = (anchors.k, &anchors.anchors);
+        if anchors.is_empty() {
+            return Ok(Chain::default());
+        }
+        for (index, anchor) in anchors.iter().enumerate() {
+            if anchor.ranks.len() != k {
+                return Err(PoaError::InvalidAnchor { anchor: index });
+            }
+        }
+        // predecessors end at a lower rank, so anchors are visited by the rank of their last node
+        let mut order: Vec<usize> = (0..anchors.len()).collect();
+        order.sort_by_key(|anchor| (anchors[*anchor].last_rank(), anchors[*anchor].query_pos));
+        let mut scores = vec![k as f32; anchors.len()];
+        let mut previous: Vec<Option<usize>> = vec![None; anchors.len()];
+        // anchor -> last anchor for which it was the predecessor of a tried anchor, such a link is rarely better
+        let mut tried_through = vec![usize::MAX; anchors.len()];
+        for (index, current) in order.iter().enumerate() {
+            let (x, first, last) = (anchors[*current].query_pos, anchors[*current].first_rank() as usize, anchors[*current].last_rank() as usize);
+            let mut skipped = 0;
+            for candidate in order[index.saturating_sub(self.gap_cost.max_iterations)..index].iter().rev() {
+                let candidate_x = anchors[*candidate].query_pos;
+                if candidate_x >= x || x - candidate_x > self.gap_cost.max_gap {
+                    continue;
+                }
+                let query_distance = x - candidate_x;
+                let candidate_last = anchors[*candidate].last_rank() as usize;
+                // overlapping k-mers shifted along the same nodes, or a reachable anchor
+                let shifted = (query_distance as usize) < k && anchors[*candidate].ranks[query_distance as usize..] == anchors[*current].ranks[..k - query_distance as usize];
+                let graph_distance = if shifted {
+                    query_distance
+                } else if self.cover.reaches(candidate_last, first) {
+                    self.levels[last] - self.levels[candidate_last]
+                } else {
+                    continue;
+                };
+                if graph_distance > self.gap_cost.max_gap {
+                    continue;
+                }
+                let gained = query_distance.min(graph_distance).min(k as u32) as f32;
+                let score = scores[*candidate] + gained - self.gap_penalty(query_distance.abs_diff(graph_distance), k);
+                if score > scores[*current] {
+                    scores[*current] = score;
+                    previous[*current] = Some(*candidate);
+                    skipped = 0;
+                } else if tried_through[*candidate] == *current {
+                    skipped += 1;
+                    if skipped > self.gap_cost.max_skip {
+                        break;
+                    }
+                }
+                if let Some(before) = previous[*candidate] {
+                    tried_through[before] = *current;
+                }
+            }
+        }
+        let mut best = order[0];
+        for anchor in &order {
+            if scores[*anchor] > scores[best] {
+                best = *anchor;
+            }
+        }
+        let mut chain = vec![best];
+        while let Some(anchor) = previous[*chain.last().unwrap()] {
+            chain.push(anchor);
+        }
+        chain.reverse();
+        // every anchor adds the bases after the end of the one before it in the query
+        let mut query_graph_path = vec![];
+        let mut query_end: usize = 0;
+        for (index, anchor) in chain.iter().enumerate() {
+            let x = anchors[*anchor].query_pos as usize;
+            let skip = if index == 0 { 0 } else { (query_end + 1).saturating_sub(x) };
+            for (offset, rank) in anchors[*anchor].ranks.iter().enumerate().skip(skip) {
+                query_graph_path.push((x + offset, self.node_ids[*rank as usize]));
+            }
+            query_end = x + k - 1;
+        }
+        Ok(Chain { path: query_graph_path, score: scores[best].max(0.0) as u32 })
+    }
+}
@@ -0,0 +1,174 @@
+//! Compact, topologically ranked view of a POA graph
+//!
+//! Nodes are stored by topological rank and the predecessors and successors of every
+//! node are kept in CSR style arrays (one offsets vector and one flat vector of ranks
+//! and edge weights), so the alignment kernels iterate contiguous memory instead of
+//! following petgraph's linked edge lists. Parallel edges are merged, summing weights.
+//!
+//! Unary chains (a node whose only predecessor is the previous rank, and that rank has
+//! no other successor) are grouped into segments. Segments cover consecutive ranks, so
+//! the bases and node indices of a segment are plain slices of the rank arrays.
+use std::ops::Range;
+use crate::poa::POAGraph;
+use petgraph::graph::NodeIndex;
+use petgraph::visit::EdgeRef;
+use petgraph::{Direction, Graph, Incoming, Outgoing};
+
+#[derive(Default, Clone, Debug)]
+pub struct CompactPoaGraph {
+    bases: Vec<u8>, // rank -> base
+    node_ids: Vec<usize>, // rank -> node index in the petgraph graph
+    pred_offsets: Vec<usize>,
+    preds: Vec<usize>, // ranks of the predecessors
+    pred_weights: Vec<i32>,
+    succ_offsets: Vec<usize>,
+    succs: Vec<usize>, // ranks of the successors
+    succ_weights: Vec<i32>,
+    segment_starts: Vec<usize>, // first rank of every segment, followed by the node count
+    segment_of: Vec<usize>, // rank -> segment
+}
+
+impl CompactPoaGraph {
+    /// Build the compact view of `graph` given its topological order (rank -> node index)
+    pub fn from_poa_graph(graph: &POAGraph, topo_order: &[usize]) -> Self {
+        let mut compact = CompactPoaGraph::default();
+        compact.rebuild(graph, topo_order);
+        compact
+    }
+
+    /// Rebuild in place, reusing the allocated arrays
+    pub fn rebuild(&mut self, graph: &POAGraph, topo_order: &[usize]) {
+        let mut topo_rank = vec![0; graph.node_count()];
+        for (rank, node) in topo_order.iter().enumerate() {
+            topo_rank[*node] = rank;
+        }
+        self.bases.clear();
+        self.node_ids.clear();
+        self.node_ids.extend_from_slice(topo_order);
+        self.bases.extend(topo_order.iter().map(|node| graph.raw_nodes()[*node].weight));
+        Self::fill_adjacency(graph, topo_order, &topo_rank, Incoming, &mut self.pred_offsets, &mut self.preds, &mut self.pred_weights);
+        Self::fill_adjacency(graph, topo_order, &topo_rank, Outgoing, &mut self.succ_offsets, &mut self.succs, &mut self.succ_weights);
+        self.fill_segments();
+    }
+
+    // split the ranks into maximal unary chains
+    fn fill_segments(&mut self) {
+        self.segment_starts.clear();
+        self.segment_of.clear();
+        for rank in 0..self.node_count() {
+            if !self.continues_segment(rank) {
+                self.segment_starts.push(rank);
+            }
+            self.segment_of.push(self.segment_starts.len() - 1);
+        }
+        self.segment_starts.push(self.node_count());
+    }
+
+    // neighbours are kept in petgraph iteration order so ties are resolved the same way
+    fn fill_adjacency(graph: &POAGraph, topo_order: &[usize], topo_rank: &[usize], direction: Direction, offsets: &mut Vec<usize>, neighbours: &mut Vec<usize>, weights: &mut Vec<i32>) {
+        offsets.clear();
+        neighbours.clear();
+        weights.clear();
+        offsets.push(0);
+        for node in topo_order {
+            let start = neighbours.len();
+            for edge in graph.edges_directed(NodeIndex::new(*node), direction) {
+                let other = if direction == Incoming { edge.source() } else { edge.target() };
+                let other_rank = topo_rank[other.index()];
+                // merge parallel edges
+                match neighbours[start..].iter().position(|rank| *rank == other_rank) {
+                    Some(position) => weights[start + position] += *edge.weight(),
+                    None => {
+                        neighbours.push(other_rank);
+                        weights.push(*edge.weight());
+                    }
+                }
+            }
+            offsets.push(neighbours.len());
+        }
+    }
+
+    /// Convert back to a petgraph graph, node indices of the result are the ranks
+    pub fn to_poa_graph(&self) -> POAGraph {
+        let mut graph: POAGraph = Graph::with_capacity(self.node_count(), self.succs.len());
+        for base in &self.bases {
+            graph.add_node(*base);
+        }
+        for rank in 0..self.node_count() {
+            for (succ, weight) in self.successors(rank).iter().zip(self.successor_weights(rank)) {
+                graph.add_edge(NodeIndex::new(rank), NodeIndex::new(*succ), *weight);
+            }
+        }
+        graph
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.bases.len()
+    }
+
+    pub fn edge_count(&self) -> usize {
+        self.succs.len()
+    }
+
+    /// Base stored in the node with the given rank
+    pub fn base(&self, rank: usize) -> u8 {
+        self.bases[rank]
+    }
+
+    /// Node index in the petgraph graph of the node with the given rank
+    pub fn node_id(&self, rank: usize) -> usize {
+        self.node_ids[rank]
+    }
+
+    pub fn node_ids(&self) -> &[usize] {
+        &self.node_ids
+    }
+
+    /// Ranks of the predecessors of the node with the given rank
+    pub fn predecessors(&self, rank: usize) -> &[usize] {
+        &self.preds[self.pred_offsets[rank]..self.pred_offsets[rank + 1]]
+    }
+
+    pub fn predecessor_weights(&self, rank: usize) -> &[i32] {
+        &self.pred_weights[self.pred_offsets[rank]..self.pred_offsets[rank + 1]]
+    }
+
+    /// Ranks of the successors of the node with the given rank
+    pub fn successors(&self, rank: usize) -> &[usize] {
+        &self.succs[self.succ_offsets[rank]..self.succ_offsets[rank + 1]]
+    }
+
+    pub fn successor_weights(&self, rank: usize) -> &[i32] {
+        &self.succ_weights[self.succ_offsets[rank]..self.succ_offsets[rank + 1]]
+    }
+
+    /// True if the only predecessor of the node is the previous rank and that node has
+    /// no other successor, so the node can be aligned straight after the previous row
+    pub fn continues_segment(&self, rank: usize) -> bool {
+        rank > 0 && self.predecessors(rank) == [rank - 1] && self.successors(rank - 1) == [rank]
+    }
+
+    pub fn segment_count(&self) -> usize {
+        self.segment_starts.len().saturating_sub(1)
+    }
+
+    /// Ranks covered by the segment
+    pub fn segment(&self, segment: usize) -> Range<usize> {
+        self.segment_starts[segment]..self.segment_starts[segment + 1]
+    }
+
+    /// Segment the node with the given rank belongs to
+    pub fn segment_of(&self, rank: usize) -> usize {
+        self.segment_of[rank]
+    }
+
+    /// Bases of the segment in order
+    pub fn segment_bases(&self, segment: usize) -> &[u8] {
+        &self.bases[self.segment(segment)]
+    }
+
+    /// Base level node indices of the segment, to map segment positions back to the graph
+    pub fn segment_node_ids(&self, segment: usize) -> &[usize] {
+        &self.node_ids[self.segment(segment)]
+    }
+}
@@ -0,0 +1,45 @@
+//! Errors of the aligner, graph and anchoring code
+//!
+//! The public entry points return `Result` so a bad read can be reported and skipped
+//! instead of taking the whole batch down with a panic.
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PoaError {
+    /// A sequence without bases where at least one is needed
+    EmptySequence,
+    /// A base other than A, C, G or T where only those can be scored
+    InvalidBase { position: usize, base: u8 },
+    /// The graph has no nodes left
+    EmptyGraph,
+    /// An alignment that consumes a different number of bases than the sequence given with it has
+    AlignmentMismatch { sequence_len: usize, aligned_len: usize },
+    /// An anchor whose chain of nodes does not start at the rank given for it
+    InvalidAnchor { anchor: usize },
+    /// A k-mer size of 0, or above `max` where the k-mers have to be packed
+    InvalidKmerSize { k: usize, max: Option<usize> },
+    /// A file that could not be read or written
+    Io { path: String, message: String },
+    /// A subcommand given options it cannot run with
+    Usage(String),
+}
+
+impl fmt::Display for PoaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PoaError::EmptySequence => write!(f, "empty sequence"),
+            PoaError::InvalidBase { position, base } => write!(f, "invalid base {:?} at position {}", *base as char, position),
+            PoaError::EmptyGraph => write!(f, "the graph has no nodes"),
+            PoaError::AlignmentMismatch { sequence_len, aligned_len } => write!(f, "alignment covers {} bases of a sequence of {}", aligned_len, sequence_len),
+            PoaError::InvalidAnchor { anchor } => write!(f, "anchor {} does not start at its first rank", anchor),
+            PoaError::InvalidKmerSize { k, max: Some(max) } => write!(f, "k-mer size {} is not in 1..={}", k, max),
+            PoaError::InvalidKmerSize { k, max: None } => write!(f, "k-mer size {} is not positive", k),
+            PoaError::Io { path, message } => write!(f, "{}: {}", path, message),
+            PoaError::Usage(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for PoaError {}
+
+pub type Result<T> = std::result::Result<T, PoaError>;
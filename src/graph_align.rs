@@ -0,0 +1,129 @@
+//! Graph to graph (profile-profile) alignment
+//!
+//! Global alignment of two POA graphs. Both graphs are walked in topological order and
+//! every cell (node of the first graph, node of the second graph) takes the best of its
+//! predecessor pairs, the same way `custom` does for a linear query against one graph.
+//! The result is a list of node pair operations, used to fuse two graphs.
+use crate::compact_graph::CompactPoaGraph;
+use crate::poa::POAGraph;
+use petgraph::algo::toposort;
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub enum GraphAlignmentOperation {
+    Match(usize, usize), // node of the first graph, node of the second graph
+    Mismatch(usize, usize),
+    Del(usize), // node of the first graph aligned to a gap
+    Ins(usize), // node of the second graph aligned to a gap
+}
+
+#[derive(Default, Clone, Eq, PartialEq, Hash, Debug)]
+pub struct GraphAlignment {
+    pub score: i32,
+    pub operations: Vec<GraphAlignmentOperation>,
+}
+
+// how a cell was reached, the predecessor cell is stored next to it
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum Move {
+    Start,
+    Diagonal,
+    Up, // consumed a node of the first graph
+    Left, // consumed a node of the second graph
+}
+
+/// Globally align two POA graphs. Node indices in the returned operations are the
+/// petgraph node indices of `graph_a` and `graph_b`.
+pub fn align_graphs(graph_a: &POAGraph, graph_b: &POAGraph, match_score: i32, mismatch_score: i32, gap_open_score: i32) -> GraphAlignment {
+    let order_a: Vec<usize> = toposort(graph_a, None).expect("POA graph has a cycle").into_iter().map(|node| node.index()).collect();
+    let order_b: Vec<usize> = toposort(graph_b, None).expect("POA graph has a cycle").into_iter().map(|node| node.index()).collect();
+    let compact_a = CompactPoaGraph::from_poa_graph(graph_a, &order_a);
+    let compact_b = CompactPoaGraph::from_poa_graph(graph_b, &order_b);
+    align_compact_graphs(&compact_a, &compact_b, match_score, mismatch_score, gap_open_score)
+}
+
+/// Same as `align_graphs` on graphs which already have a compact view
+pub fn align_compact_graphs(compact_a: &CompactPoaGraph, compact_b: &CompactPoaGraph, match_score: i32, mismatch_score: i32, gap_open_score: i32) -> GraphAlignment {
+    let (n, m) = (compact_a.node_count(), compact_b.node_count());
+    if n == 0 || m == 0 {
+        return GraphAlignment::default();
+    }
+    // row and column 0 are the virtual start, row rank + 1 is the node with that rank
+    let width = m + 1;
+    let mut scores: Vec<i32> = vec![i32::MIN / 2; (n + 1) * width];
+    let mut moves: Vec<(Move, usize, usize)> = vec![(Move::Start, 0, 0); (n + 1) * width];
+    scores[0] = 0;
+    // a node without predecessors continues from the virtual start
+    let start = [usize::MAX];
+    let preds_a = |rank: usize| -> &[usize] { if compact_a.predecessors(rank).is_empty() { &start } else { compact_a.predecessors(rank) } };
+    let preds_b = |rank: usize| -> &[usize] { if compact_b.predecessors(rank).is_empty() { &start } else { compact_b.predecessors(rank) } };
+    // usize::MAX (the virtual start) wraps to row / column 0
+    let row = |rank: usize| rank.wrapping_add(1);
+    for i in 0..=n {
+        for j in 0..=m {
+            if i == 0 && j == 0 {
+                continue;
+            }
+            let mut best = (i32::MIN / 2, Move::Start, 0, 0);
+            if i > 0 && j > 0 {
+                let score = if compact_a.base(i - 1) == compact_b.base(j - 1) { match_score } else { mismatch_score };
+                for pa in preds_a(i - 1) {
+                    for pb in preds_b(j - 1) {
+                        let candidate = scores[row(*pa) * width + row(*pb)] + score;
+                        if candidate > best.0 {
+                            best = (candidate, Move::Diagonal, row(*pa), row(*pb));
+                        }
+                    }
+                }
+            }
+            if i > 0 {
+                for pa in preds_a(i - 1) {
+                    let candidate = scores[row(*pa) * width + j] + gap_open_score;
+                    if candidate > best.0 {
+                        best = (candidate, Move::Up, row(*pa), j);
+                    }
+                }
+            }
+            if j > 0 {
+                for pb in preds_b(j - 1) {
+                    let candidate = scores[i * width + row(*pb)] + gap_open_score;
+                    if candidate > best.0 {
+                        best = (candidate, Move::Left, i, row(*pb));
+                    }
+                }
+            }
+            scores[i * width + j] = best.0;
+            moves[i * width + j] = (best.1, best.2, best.3);
+        }
+    }
+    // the alignment ends at the best pair of sinks
+    let mut end = (i32::MIN, 0, 0);
+    for i in (1..=n).filter(|i| compact_a.successors(i - 1).is_empty()) {
+        for j in (1..=m).filter(|j| compact_b.successors(j - 1).is_empty()) {
+            if scores[i * width + j] > end.0 {
+                end = (scores[i * width + j], i, j);
+            }
+        }
+    }
+    let (score, mut i, mut j) = end;
+    let mut operations = vec![];
+    while i != 0 || j != 0 {
+        let (movement, prev_i, prev_j) = moves[i * width + j];
+        match movement {
+            Move::Diagonal => {
+                let (a, b) = (compact_a.node_id(i - 1), compact_b.node_id(j - 1));
+                if compact_a.base(i - 1) == compact_b.base(j - 1) {
+                    operations.push(GraphAlignmentOperation::Match(a, b));
+                } else {
+                    operations.push(GraphAlignmentOperation::Mismatch(a, b));
+                }
+            }
+            Move::Up => operations.push(GraphAlignmentOperation::Del(compact_a.node_id(i - 1))),
+            Move::Left => operations.push(GraphAlignmentOperation::Ins(compact_b.node_id(j - 1))),
+            Move::Start => break,
+        }
+        i = prev_i;
+        j = prev_j;
+    }
+    operations.reverse();
+    GraphAlignment { score, operations }
+}
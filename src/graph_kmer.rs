@@ -0,0 +1,233 @@
+//! K-mer index built directly on the POA graph
+//!
+//! Instead of enumerating paths and hashing their sequences, the k-mers ending at every
+//! node are found by dynamic programming over the predecessors: a node extends the
+//! partial chains (shorter than k) ending at its predecessors by itself. Branching can
+//! make the number of chains explode, so each node keeps at most `max_variants` partial
+//! and full chains. Occurrences are stored as node chains so anchors come straight from
+//! the graph, and the index is updated incrementally when reads are added.
+use crate::packed_kmer::{packed_kmer_keys, MAX_PACKED_K};
+use crate::poa::POAGraph;
+use fxhash::FxHashMap;
+use petgraph::graph::NodeIndex;
+use petgraph::{Incoming, Outgoing};
+
+#[derive(Default, Clone, Debug)]
+pub struct GraphKmerIndex {
+    k: usize,
+    max_variants: usize,
+    partial: Vec<Vec<Vec<usize>>>, // node index -> chains shorter than k ending at the node
+    ending: Vec<Vec<Vec<usize>>>, // node index -> chains of k nodes ending at the node
+    occurrences: FxHashMap<u64, Vec<Vec<usize>>>, // packed k-mer -> node chains spelling it
+    capped: Vec<bool>, // node index -> the node hit the variant limit
+}
+
+impl GraphKmerIndex {
+    /// Index all the k-mers of the graph, `topo_order` is rank -> node index. k-mers are
+    /// packed into `u64` keys so k can be at most 32.
+    pub fn new(graph: &POAGraph, topo_order: &[usize], k: usize, max_variants: usize) -> Self {
+        assert!(k > 0 && k <= MAX_PACKED_K, "graph k-mer index needs 0 < k <= {}", MAX_PACKED_K);
+        let mut index = GraphKmerIndex { k, max_variants, ..Default::default() };
+        index.rebuild(graph, topo_order);
+        index
+    }
+
+    pub fn k(&self) -> usize {
+        self.k
+    }
+
+    /// Number of distinct k-mers in the index
+    pub fn kmer_count(&self) -> usize {
+        self.occurrences.len()
+    }
+
+    /// Number of stored node chains over all k-mers
+    pub fn occurrence_count(&self) -> usize {
+        self.occurrences.values().map(|chains| chains.len()).sum()
+    }
+
+    /// Number of nodes that hit the variant limit, their k-mers are incomplete
+    pub fn capped_nodes(&self) -> usize {
+        self.capped.iter().filter(|capped| **capped).count()
+    }
+
+    /// Index the whole graph again, needed after nodes were removed as that moves node indices
+    pub fn rebuild(&mut self, graph: &POAGraph, topo_order: &[usize]) {
+        self.partial = vec![vec![]; graph.node_count()];
+        self.ending = vec![vec![]; graph.node_count()];
+        self.occurrences.clear();
+        self.capped = vec![false; graph.node_count()];
+        for node in topo_order {
+            self.index_node(graph, *node);
+        }
+    }
+
+    /// Update the index after nodes or edges were added. `changed` are the new nodes and the
+    /// targets of the new edges; they and everything up to k - 1 nodes downstream are indexed again.
+    pub fn update(&mut self, graph: &POAGraph, topo_rank: &[usize], changed: &[usize]) {
+        self.partial.resize(graph.node_count(), vec![]);
+        self.ending.resize(graph.node_count(), vec![]);
+        self.capped.resize(graph.node_count(), false);
+        // the chains of a node only depend on the k - 1 nodes before it
+        let mut affected = vec![false; graph.node_count()];
+        let mut frontier: Vec<usize> = changed.to_vec();
+        for node in &frontier {
+            affected[*node] = true;
+        }
+        for _ in 1..self.k {
+            let mut next_frontier = vec![];
+            for node in frontier {
+                for successor in graph.neighbors_directed(NodeIndex::new(node), Outgoing) {
+                    if !affected[successor.index()] {
+                        affected[successor.index()] = true;
+                        next_frontier.push(successor.index());
+                    }
+                }
+            }
+            frontier = next_frontier;
+        }
+        let mut nodes: Vec<usize> = (0..graph.node_count()).filter(|node| affected[*node]).collect();
+        nodes.sort_by_key(|node| topo_rank[*node]);
+        for node in &nodes {
+            self.remove_node_occurrences(graph, *node);
+        }
+        for node in nodes {
+            self.index_node(graph, node);
+        }
+    }
+
+    // extend the partial chains of the predecessors by the node
+    fn index_node(&mut self, graph: &POAGraph, node: usize) {
+        let mut partial = vec![vec![node]];
+        let mut ending = vec![];
+        let mut capped = false;
+        for predecessor in graph.neighbors_directed(NodeIndex::new(node), Incoming) {
+            for chain in &self.partial[predecessor.index()] {
+                let target = if chain.len() + 1 == self.k { &mut ending } else { &mut partial };
+                // parallel edges give the same chain twice
+                if target.iter().any(|other: &Vec<usize>| other.len() == chain.len() + 1 && other[..chain.len()] == chain[..]) {
+                    continue;
+                }
+                if target.len() >= self.max_variants {
+                    capped = true;
+                    continue;
+                }
+                let mut extended = chain.clone();
+                extended.push(node);
+                target.push(extended);
+            }
+        }
+        // a single node chain is already a k-mer when k is 1
+        if self.k == 1 {
+            ending = partial;
+            partial = vec![];
+        }
+        self.capped[node] = capped;
+        for chain in &ending {
+            // chains through non-ACGT nodes are not indexed
+            if let Some(key) = chain_key(graph, chain, self.k) {
+                self.occurrences.entry(key).or_default().push(chain.clone());
+            }
+        }
+        self.partial[node] = partial;
+        self.ending[node] = ending;
+    }
+
+    fn remove_node_occurrences(&mut self, graph: &POAGraph, node: usize) {
+        for chain in std::mem::take(&mut self.ending[node]) {
+            let key = match chain_key(graph, &chain, self.k) {
+                Some(key) => key,
+                None => continue,
+            };
+            if let Some(chains) = self.occurrences.get_mut(&key) {
+                chains.retain(|other| *other != chain);
+                if chains.is_empty() {
+                    self.occurrences.remove(&key);
+                }
+            }
+        }
+        self.partial[node].clear();
+    }
+
+    /// Anchors of the query in the form `lcskpp_graph` takes them, with node ranks from `topo_rank`:
+    /// (query position, rank of the first node) sorted, the paths (all path 0), the ranks of the
+    /// predecessors of the first node and the ranks of every node of the chain.
+    pub fn find_kmer_matches(&self, query: &[u8], graph: &POAGraph, topo_rank: &[usize]) -> (Vec<(u32, u32)>, Vec<Vec<usize>>, Vec<Vec<u32>>, Vec<Vec<u32>>) {
+        // (query position, first rank) -> (first node, chain ranks)
+        let mut anchors: FxHashMap<(u32, u32), (usize, Vec<u32>)> = FxHashMap::default();
+        for (query_pos, key) in packed_kmer_keys(query, self.k, false).into_iter().enumerate() {
+            if let Some(key) = key {
+                if let Some(chains) = self.occurrences.get(&key) {
+                    for chain in chains {
+                        let ranks: Vec<u32> = chain.iter().map(|node| topo_rank[*node] as u32).collect();
+                        // the first chain wins when two share the start, like better_find_kmer_matches
+                        anchors.entry((query_pos as u32, ranks[0])).or_insert((chain[0], ranks));
+                    }
+                }
+            }
+        }
+        let mut keys: Vec<(u32, u32)> = anchors.keys().copied().collect();
+        keys.sort_unstable();
+        let mut kmer_paths = vec![];
+        let mut previous_nodes = vec![];
+        let mut kmer_graph_path = vec![];
+        for key in &keys {
+            let (first, chain) = anchors.remove(key).unwrap();
+            let mut predecessors: Vec<u32> = graph.neighbors_directed(NodeIndex::new(first), Incoming).map(|node| topo_rank[node.index()] as u32).collect();
+            predecessors.sort_unstable();
+            predecessors.dedup();
+            if predecessors.is_empty() {
+                predecessors.push(u32::MAX);
+            }
+            kmer_paths.push(vec![0; predecessors.len()]);
+            previous_nodes.push(predecessors);
+            kmer_graph_path.push(chain);
+        }
+        (keys, kmer_paths, previous_nodes, kmer_graph_path)
+    }
+}
+
+// packed key of the bases along the chain
+fn chain_key(graph: &POAGraph, chain: &[usize], k: usize) -> Option<u64> {
+    let bases: Vec<u8> = chain.iter().map(|node| graph.raw_nodes()[*node].weight).collect();
+    packed_kmer_keys(&bases, k, false)[0]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::GraphKmerIndex;
+    use crate::poa::Poa;
+    use crate::simulate::get_random_sequences_from_generator;
+    use std::collections::BTreeMap;
+
+    // k-mer -> occurrence chains, sorted so the order they were found in does not matter
+    fn occurrence_sets(index: &GraphKmerIndex) -> BTreeMap<u64, Vec<Vec<usize>>> {
+        index.occurrences.iter().map(|(key, chains)| {
+            let mut chains = chains.clone();
+            chains.sort();
+            (*key, chains)
+        }).collect()
+    }
+
+    #[test]
+    fn update_matches_a_rebuild() {
+        let k = 6;
+        let reads: Vec<Vec<u8>> = get_random_sequences_from_generator(200, 10, 3).into_iter().map(|seq| seq.into_bytes()).collect();
+        // a limit that is never hit and one that caps the branching nodes
+        for max_variants in [1000, 2] {
+            let mut poa = Poa::from_string(1, -1, -2, &reads[0]).unwrap();
+            poa.enable_kmer_index(k, max_variants).unwrap();
+            for read in &reads[1..] {
+                let lcsk_path = poa.anchor_query(read, k).unwrap();
+                let alignment = poa.custom_simd_indirect_address(read, &lcsk_path, 20);
+                poa.add_alignment(&alignment, read).unwrap();
+                let incremental = poa.kmer_index().unwrap();
+                let rebuilt = GraphKmerIndex::new(&poa.graph, poa.topological_order(), k, max_variants);
+                assert_eq!(occurrence_sets(incremental), occurrence_sets(&rebuilt));
+                assert_eq!(incremental.capped, rebuilt.capped);
+            }
+            let capped = poa.kmer_index().unwrap().capped_nodes();
+            assert_eq!(capped > 0, max_variants == 2, "{} capped nodes with max_variants {}", capped, max_variants);
+        }
+    }
+}
@@ -0,0 +1,175 @@
+//! Cross-check of the POA kernels
+//!
+//! `Poa::custom` (scalar), `Poa::custom_simd` and the banded `custom_simd_indirect_address`
+//! implement the same recurrence. `check_kernels` runs all three on a query against the same
+//! graph and compares them: the score matrices cell by cell in topological order, the final
+//! scores, and the graphs the three alignments give when they are added. The banded kernel is
+//! only compared inside the band it computed, and there it may score a cell lower than the
+//! scalar one when the best path to the cell leaves the band, so only a higher score counts
+//! as a divergence.
+use crate::error::Result;
+use crate::poa::{AlignerWorkspace, Poa};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Kernel {
+    Scalar,
+    Simd,
+    Banded,
+}
+
+/// First cell, in the order the kernels fill them, where a kernel differs from the scalar one
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct CellDivergence {
+    pub rank: usize,
+    pub node: usize,
+    pub query_pos: usize,
+    pub base: u8, // base of the node
+    pub scalar: i32,
+    pub found: i32,
+}
+
+/// Result of `check_kernels` for one query
+#[derive(Clone, Debug)]
+pub struct KernelCheck {
+    pub scalar_score: i32,
+    pub simd_score: i32,
+    pub banded_score: i32,
+    pub simd_divergence: Option<CellDivergence>,
+    pub banded_divergence: Option<CellDivergence>,
+    pub banded_cells: usize, // cells inside the band, the ones compared
+    pub banded_below: usize, // cells inside the band scored lower than the scalar kernel
+    pub simd_graph_equal: bool, // the graph after adding the alignment equals the scalar one
+    pub banded_graph_equal: bool,
+    pub add_errors: Vec<(Kernel, String)>, // alignments the graph rejected, see `Poa::add_alignment`
+}
+
+impl KernelCheck {
+    /// The SIMD kernel agrees with the scalar one cell by cell, in score and in graph
+    pub fn simd_agrees(&self) -> bool {
+        self.simd_divergence.is_none() && self.simd_score == self.scalar_score && self.simd_graph_equal
+    }
+
+    /// The banded kernel agrees inside its band, in score and in graph
+    pub fn banded_agrees(&self) -> bool {
+        self.banded_divergence.is_none() && self.banded_score == self.scalar_score && self.banded_graph_equal
+    }
+}
+
+/// Align the query to the graph with the three kernels and compare them, the graph itself is
+/// left unchanged. `lcsk_path` and `band_size` are passed on to the banded kernel.
+pub fn check_kernels(poa: &Poa, query: &Vec<u8>, lcsk_path: &Vec<(usize, usize)>, band_size: usize) -> Result<KernelCheck> {
+    poa.check_alignable(query)?;
+    let mut poa = poa.clone();
+    let traceback = poa.custom(query);
+    let scalar_alignment = traceback.alignment();
+    let (simd_alignment, simd_scores) = poa.custom_simd_with_scores(query);
+    let mut workspace = AlignerWorkspace::new();
+    let banded_alignment = poa.custom_simd_indirect_address_with_workspace(query, lcsk_path, band_size, &mut workspace);
+    let compact = poa.compact_graph();
+    let (mut simd_divergence, mut banded_divergence, mut banded_cells, mut banded_below) = (None, None, 0, 0);
+    for rank in 0..compact.node_count() {
+        let node = compact.node_id(rank);
+        for query_pos in 0..query.len() {
+            let scalar = traceback.score(node, query_pos);
+            let cell = |found| CellDivergence { rank, node, query_pos, base: compact.base(rank), scalar, found };
+            let simd = simd_scores[node][query_pos / 8][query_pos % 8];
+            if simd != scalar && simd_divergence.is_none() {
+                simd_divergence = Some(cell(simd));
+            }
+            if let Some(banded) = workspace.banded_score(node, query_pos) {
+                banded_cells += 1;
+                if banded < scalar {
+                    banded_below += 1;
+                }
+                if banded > scalar && banded_divergence.is_none() {
+                    banded_divergence = Some(cell(banded));
+                }
+            }
+        }
+    }
+    // the graphs the alignments give, compared by base and weighted edges in topological order
+    let mut add_errors = vec![];
+    let mut signatures = vec![];
+    for (kernel, alignment) in [(Kernel::Scalar, &scalar_alignment), (Kernel::Simd, &simd_alignment), (Kernel::Banded, &banded_alignment)] {
+        let mut graph = poa.clone();
+        match graph.add_alignment(alignment, query) {
+            Ok(_) => signatures.push(Some(graph_signature(&graph))),
+            Err(error) => {
+                add_errors.push((kernel, error.to_string()));
+                signatures.push(None);
+            }
+        }
+    }
+    Ok(KernelCheck {
+        scalar_score: scalar_alignment.score,
+        simd_score: simd_alignment.score,
+        banded_score: banded_alignment.score,
+        simd_divergence,
+        banded_divergence,
+        banded_cells,
+        banded_below,
+        simd_graph_equal: signatures[0].is_some() && signatures[1] == signatures[0],
+        banded_graph_equal: signatures[0].is_some() && signatures[2] == signatures[0],
+        add_errors,
+    })
+}
+
+// bases in topological order and the (rank, rank, weight) edges, sorted
+fn graph_signature(poa: &Poa) -> (Vec<u8>, Vec<(usize, usize, i32)>) {
+    let compact = poa.compact_graph();
+    let bases = (0..compact.node_count()).map(|rank| compact.base(rank)).collect();
+    let mut edges = vec![];
+    for rank in 0..compact.node_count() {
+        for (predecessor, weight) in compact.predecessors(rank).iter().zip(compact.predecessor_weights(rank)) {
+            edges.push((*predecessor, rank, *weight));
+        }
+    }
+    edges.sort_unstable();
+    (bases, edges)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulate::get_random_sequences_from_generator;
+    use rand::{Rng, SeedableRng, rngs::StdRng};
+
+    // checks every read of seeded random graphs against the graph built from the reads before
+    // it, `multi_source` tells whether the graph had a start node other than the first base
+    fn check_random_graphs(mut check_read: impl FnMut(&KernelCheck, bool)) {
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..10 {
+            let (length, count, seed) = (rng.gen_range(20..120), rng.gen_range(3..8), rng.gen_range(0..10_000));
+            let seqs: Vec<Vec<u8>> = get_random_sequences_from_generator(length, count, seed).into_iter().map(|seq| seq.into_bytes()).collect();
+            let mut poa = Poa::from_string(1, -1, -2, &seqs[0]).unwrap();
+            for read in seqs.iter().skip(1) {
+                let compact = poa.compact_graph();
+                let multi_source = (1..compact.node_count()).any(|rank| compact.predecessors(rank).is_empty());
+                let lcsk_path = poa.anchor_query(read, 4).unwrap();
+                let check = check_kernels(&poa, read, &lcsk_path, 10).unwrap();
+                check_read(&check, multi_source);
+                let alignment = poa.custom_simd(read);
+                poa.add_alignment(&alignment, read).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn kernels_agree() {
+        let mut multi_source_reads = 0;
+        // the graphs are not compared: at the start of the query the SIMD tracebacks may pick
+        // another alignment of the same score than the scalar one
+        check_random_graphs(|check, multi_source| {
+            assert!(check.add_errors.is_empty(), "{:?}", check.add_errors);
+            assert_eq!(check.simd_divergence, None);
+            assert_eq!(check.simd_score, check.scalar_score);
+            // the banded kernel only scores lower than the scalar one, where the best path leaves the band
+            assert_eq!(check.banded_divergence, None);
+            assert!(check.banded_score <= check.scalar_score);
+            assert!(check.banded_cells > 0);
+            multi_source_reads += multi_source as usize;
+        });
+        // graphs with a start node other than the first base are covered
+        assert!(multi_source_reads > 0);
+    }
+}
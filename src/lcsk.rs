@@ -1,4 +1,4 @@
-use crate::bit_tree::MaxBitTree;
+use crate::bit_tree::{CompressedMaxBitTree, MaxBitTreePool};
 use fxhash::FxHasher;
 use petgraph::Direction::Incoming;
 use std::cmp::{min, max};
@@ -6,13 +6,28 @@ use std::collections::HashMap;
 use std::hash::BuildHasherDefault;
 use petgraph::Outgoing;
 use petgraph::graph::NodeIndex;
+use petgraph::visit::EdgeRef;
 use petgraph::{Directed, Graph};
 use itertools::Itertools;
 use fxhash::FxHashMap;
 use std::simd::u8x32;
+use crate::seeding::{seed_positions, Seeding};
+use crate::packed_kmer::{check_match_kmer_size, PackedKmerIndex, MAX_PACKED_K};
+use crate::error::{PoaError, Result};
 
 pub type POAGraph = Graph<u8, i32, Directed, usize>;
 pub type HashMapFx<K, V> = HashMap<K, V, BuildHasherDefault<FxHasher>>;
+/// k-mer matches as `better_find_kmer_matches` returns them: (query position, first rank) sorted,
+/// the paths of every match, the rank before the match on each of those paths and the ranks of the match
+pub type KmerMatches = (Vec<(u32, u32)>, Vec<Vec<usize>>, Vec<Vec<u32>>, Vec<Vec<u32>>);
+
+/// k-mer matches as `find_kmer_matches` returns them: (query position, first node), the node
+/// k - 1 after the first, and the paths of every match with the node before it on each
+pub type PathKmerMatches = (Vec<(u32, u32)>, Vec<u32>, Vec<Vec<usize>>, Vec<Vec<u32>>);
+
+/// Sections as `divide_poa_graph_get_paths` returns them: the paths and sequences of every
+/// section, the most paths in a section and whether the path limits cut any section short
+pub type SectionPaths = (Vec<Vec<Vec<usize>>>, Vec<Vec<Vec<u8>>>, usize, bool);
 
 pub fn anchoring_lcsk_path_for_threading (ascending_path: &Vec<(usize, usize)>, original_path: &Vec<(usize, usize)>, number_of_sequences: usize, graph: &POAGraph, cut_limit: usize, query_length: usize, topo_indices: Vec<usize>, query: &Vec<u8>) -> (Vec<usize>, Vec<Graph<u8, i32, Directed, usize>>, Vec<usize>, Vec<Vec<u8>>, Vec<Vec<(usize, usize)>>) {    let mut current_cut_limit = cut_limit;
     let mut section_graphs: Vec<Graph<u8, i32, Directed, usize>> = vec![];
@@ -211,28 +226,37 @@ pub fn try_to_make_the_cut(output_graph: &POAGraph, topo_index: usize, total_num
     }
 }
 
-pub fn lcskpp_graph(kmer_pos_vec: Vec<(u32, u32)>, kmer_path_vec: Vec<Vec<usize>>, kmers_previous_node_in_paths: Vec<Vec<u32>>, num_of_paths: usize, k: usize, kmer_graph_index: Vec<Vec<u32>>, topo_map: &Vec<usize>) -> (Vec<(usize, usize)>, Vec<(usize, usize)>, u32) {
+/// LCSk++ with fresh trees, `lcskpp_graph_pooled` reuses them when chaining several queries
+pub fn lcskpp_graph(kmer_pos_vec: Vec<(u32, u32)>, kmer_path_vec: Vec<Vec<usize>>, kmers_previous_node_in_paths: Vec<Vec<u32>>, num_of_paths: usize, k: usize, kmer_graph_index: Vec<Vec<u32>>, topo_map: &Vec<usize>) -> Result<(Vec<(usize, usize)>, Vec<(usize, usize)>, u32)> {
+    lcskpp_graph_pooled(kmer_pos_vec, kmer_path_vec, kmers_previous_node_in_paths, num_of_paths, k, kmer_graph_index, topo_map, &mut MaxBitTreePool::new())
+}
+
+/// Same as `lcskpp_graph` taking the Fenwick trees of the paths from `pool` and handing them
+/// back at the end, so chaining read after read does not allocate new trees
+pub fn lcskpp_graph_pooled(kmer_pos_vec: Vec<(u32, u32)>, kmer_path_vec: Vec<Vec<usize>>, kmers_previous_node_in_paths: Vec<Vec<u32>>, num_of_paths: usize, k: usize, kmer_graph_index: Vec<Vec<u32>>, topo_map: &Vec<usize>, pool: &mut MaxBitTreePool<(u32, u32)>) -> Result<(Vec<(usize, usize)>, Vec<(usize, usize)>, u32)> {
     // return nothing if empty
     if kmer_pos_vec.is_empty() {
-        return (vec![], vec![],  0);
+        return Ok((vec![], vec![],  0));
     }
 
     let k = k as u32;
 
     let mut events: Vec<(u32, u32, u32, Vec<usize>, Vec<u32>, Vec<u32>)> = Vec::new(); // x, idx, path, prev ,kmer nodes in greph)
-    let mut max_ns = vec![0; num_of_paths];
+    // graph ranks the anchors of every path end at, the only positions its tree is set at
+    let mut end_ranks: Vec<Vec<usize>> = vec![vec![]; num_of_paths];
     // generate the required events
     for (idx, &(x, y)) in kmer_pos_vec.iter().enumerate() {
         // to change the code to this shit
-        assert!(y == kmer_graph_index[idx][0]);
+        if kmer_graph_index[idx].first() != Some(&y) {
+            return Err(PoaError::InvalidAnchor { anchor: idx });
+        }
         // add the previous one as well
         // IF END SAVE y - K - 1 NODE IF START SAVE y - 1 NODE (-1 if not available)
         // added  graph node and graph node + k
         events.push((x, kmer_graph_index[idx][0], (idx + kmer_pos_vec.len()) as u32, kmer_path_vec[idx].clone(), kmers_previous_node_in_paths[idx].clone(), kmer_graph_index[idx].clone()));
         events.push((x + k - 1, *kmer_graph_index[idx].last().unwrap(), idx as u32, kmer_path_vec[idx].clone(), kmers_previous_node_in_paths[idx].clone(), kmer_graph_index[idx].clone()));
-        for path in kmer_path_vec[idx].clone() {
-            max_ns[path] = max(max_ns[path], x + k - 1);
-            max_ns[path] = max(max_ns[path], kmer_graph_index[idx][k as usize - 1]);
+        for path in &kmer_path_vec[idx] {
+            end_ranks[*path].push(kmer_graph_index[idx][k as usize - 1] as usize);
         }
     }
     // ev.2 = ev.6[k as usize - 1]
@@ -244,11 +268,11 @@ pub fn lcskpp_graph(kmer_pos_vec: Vec<(u32, u32)>, kmer_path_vec: Vec<Vec<usize>
         //println!("{:?}", event);
     //}
     //println!("DONE");
-    let mut max_bit_tree_path = vec![];
-    // generate empty fenwick trees
-    for (_index, n) in max_ns.iter().enumerate() {
-        let max_col_dp: MaxBitTree<(u32, u32)> = MaxBitTree::new(*n as usize);
-        max_bit_tree_path.push(max_col_dp);
+    // empty fenwick trees over the end ranks of each path
+    let mut max_bit_tree_path: Vec<CompressedMaxBitTree<(u32, u32)>> = vec![];
+    for ranks in end_ranks {
+        let tree = pool.take(ranks.len());
+        max_bit_tree_path.push(CompressedMaxBitTree::from_tree(ranks, tree));
     }
     let mut dp: Vec<(u32, i32, Vec<u32>, u32)> = Vec::with_capacity(events.len()); //index is index score prev match, corrosponding graph nodes, query pos
     let mut best_dp = (k, 0, 0); // score, coloumn, path
@@ -302,6 +326,10 @@ pub fn lcskpp_graph(kmer_pos_vec: Vec<(u32, u32)>, kmer_path_vec: Vec<Vec<usize>
                     let prev_node = ev.4[path_index];
                     if prev_node != u32::MAX {
                         if let Ok(cont_idx) = kmer_pos_vec.binary_search(&(ev.0 - k, prev_node)) {
+                            // only a continuation if the other k-mer runs through the same nodes shifted by one
+                            if kmer_graph_index[cont_idx][1..] != ev.5[..k as usize - 1] {
+                                continue;
+                            }
                             //println!("!!!!!!!!!!");
                             let prev_score = dp[cont_idx].0;
                             //let candidate = (prev_score + 1, cont_idx as i32, prev_path);
@@ -309,7 +337,8 @@ pub fn lcskpp_graph(kmer_pos_vec: Vec<(u32, u32)>, kmer_path_vec: Vec<Vec<usize>
                                 //println!("CONTINUTING VALUE {}", dp[p].2.len());
                                 dp[p].0 = prev_score + 1;
                                 dp[p].1 = cont_idx as i32;
-                                dp[p].3 = dp[p].3 + 1;
+                                // only the last node is new, it sits at the end of the k-mer in the query
+                                dp[p].3 = ev.0;
                                 dp[p].2 = vec![*dp[p].2.last().unwrap()];
                             }
                             best_dp = max(best_dp, (dp[p].0, p as i32, path));
@@ -328,6 +357,9 @@ pub fn lcskpp_graph(kmer_pos_vec: Vec<(u32, u32)>, kmer_path_vec: Vec<Vec<usize>
             tree_update_required_level = ev.0;
         }
     }
+    for tree in max_bit_tree_path {
+        pool.put(tree.into_tree());
+    }
     let mut traceback = Vec::new();
     let (best_score, mut prev_match, mut _path) = best_dp;
     //println!("BEST SCORE: {} PREV_MATCH: {}", best_score, prev_match);
@@ -340,10 +372,10 @@ pub fn lcskpp_graph(kmer_pos_vec: Vec<(u32, u32)>, kmer_path_vec: Vec<Vec<usize>
         //println!("{} ", prev_match);
         traceback.push(prev_match as usize);
         dp[prev_match as usize].2.reverse();
-        let mut query_pos = dp[prev_match as usize].3 + dp[prev_match as usize].2.len() as u32  - 1;
-        //println!("ORIGINAL Q POS {}", query_pos);
-        for node in &dp[prev_match as usize].2 {
-            
+        let last_query_pos = dp[prev_match as usize].3 + dp[prev_match as usize].2.len() as u32  - 1;
+        //println!("ORIGINAL Q POS {}", last_query_pos);
+        for (offset, node) in dp[prev_match as usize].2.iter().enumerate() {
+            let query_pos = last_query_pos - offset as u32;
             let converted_node = topo_map[*node as usize];
             let current_node = *node as usize;
             if last_node == usize::MAX {
@@ -352,7 +384,6 @@ pub fn lcskpp_graph(kmer_pos_vec: Vec<(u32, u32)>, kmer_path_vec: Vec<Vec<usize>
             //println!("q pos {}", query_pos);
             query_graph_path.push((query_pos as usize, converted_node));
             unconverted_query_graph_path.push((query_pos as usize, *node as usize));
-            query_pos -= 1;
             //println!("{} != {}", last_node, current_node);
             assert!(last_node >= current_node);
             last_node = current_node;
@@ -363,12 +394,79 @@ pub fn lcskpp_graph(kmer_pos_vec: Vec<(u32, u32)>, kmer_path_vec: Vec<Vec<usize>
     query_graph_path.reverse();
     unconverted_query_graph_path.reverse();
     //println!("{:?}", query_graph_path);
-    (query_graph_path, unconverted_query_graph_path, best_score)
+    Ok((query_graph_path, unconverted_query_graph_path, best_score))
+}
+
+/// Thread the previously added sequence through the graph to record its path, then
+/// return the LCSk++ path of the query against all the recorded paths. The trees come from
+/// `pool`, keep it across the queries of a graph.
+pub fn lcsk_path_for_query(output_graph: &POAGraph, topo_indices: &Vec<usize>, topo_map: &Vec<usize>, previous_sequence: &[u8], query: &[u8], all_paths: &mut Vec<Vec<usize>>, all_sequences: &mut Vec<Vec<u8>>, kmer_size: usize, pool: &mut MaxBitTreePool<(u32, u32)>) -> Result<Vec<(usize, usize)>> {
+    check_match_kmer_size(kmer_size)?;
+    let mut error_index = 0;
+    loop {
+        let (error_occured, temp_path, temp_sequence) = find_sequence_in_graph (previous_sequence.to_vec(), output_graph, topo_indices, topo_map, error_index)?;
+        if error_index > 10 {
+            break;
+        }
+        if !error_occured {
+            all_paths.push(temp_path);
+            all_sequences.push(temp_sequence);
+            break;
+        }
+        error_index += 1;   
+    }
+    let (kmer_pos_vec, kmer_path_vec, kmers_previous_node_in_paths, kmer_graph_path) = better_find_kmer_matches(query, all_sequences, all_paths, kmer_size);
+    let (lcsk_path, _lcsk_path_unconverted, _k_new_score) = lcskpp_graph_pooled(kmer_pos_vec, kmer_path_vec, kmers_previous_node_in_paths, all_paths.len(), kmer_size, kmer_graph_path, topo_indices, pool)?;
+    Ok(lcsk_path)
+}
+
+/// Strand of a read relative to the graph
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Orientation {
+    #[default]
+    Forward,
+    Reverse,
+}
+
+/// Reverse complement of a sequence, bases other than ACGT are kept as they are
+pub fn reverse_complement(seq: &[u8]) -> Vec<u8> {
+    seq.iter().rev().map(|base| match base {
+        b'A' => b'T',
+        b'C' => b'G',
+        b'G' => b'C',
+        b'T' => b'A',
+        b'a' => b't',
+        b'c' => b'g',
+        b'g' => b'c',
+        b't' => b'a',
+        other => *other,
+    }).collect()
 }
 
 pub fn better_find_kmer_matches(query: &[u8], graph_sequences: &Vec<Vec<u8>>, graph_ids: &Vec<Vec<usize>>, k: usize) -> (Vec<(u32, u32)>, Vec<Vec<usize>>, Vec<Vec<u32>>, Vec<Vec<u32>>) {
-    // hash the query
-    let set = hash_kmers_2(query, k);
+    better_find_kmer_matches_seeded(query, graph_sequences, graph_ids, k, Seeding::Exhaustive)
+}
+
+/// Same as `better_find_kmer_matches` but only the k-mers selected by `seeding` are matched
+pub fn better_find_kmer_matches_seeded(query: &[u8], graph_sequences: &Vec<Vec<u8>>, graph_ids: &Vec<Vec<usize>>, k: usize, seeding: Seeding) -> (Vec<(u32, u32)>, Vec<Vec<usize>>, Vec<Vec<u32>>, Vec<Vec<u32>>) {
+    if k == 0 || k > MAX_PACKED_K {
+        // too long to pack, the k-mers are hashed as slices
+        let set = hash_kmers_seeded(query, k, seeding);
+        return better_find_kmer_matches_by(graph_sequences, graph_ids, k, seeding, |seq, positions| find_kmer_matches_seq1_hashed_at(&set, seq, k, positions));
+    }
+    // index the query
+    let query_index = PackedKmerIndex::with_positions(query, k, false, &seed_positions(query, k, seeding));
+    better_find_kmer_matches_with_index(&query_index, graph_sequences, graph_ids, seeding)
+}
+
+/// Same as `better_find_kmer_matches_seeded` with an already built (and possibly cached) query index
+pub fn better_find_kmer_matches_with_index(query_index: &PackedKmerIndex, graph_sequences: &Vec<Vec<u8>>, graph_ids: &Vec<Vec<usize>>, seeding: Seeding) -> (Vec<(u32, u32)>, Vec<Vec<usize>>, Vec<Vec<u32>>, Vec<Vec<u32>>) {
+    better_find_kmer_matches_by(graph_sequences, graph_ids, query_index.k(), seeding, |seq, positions| query_index.find_matches(seq, positions))
+}
+
+// `better_find_kmer_matches` with the matches of the query k-mers in a path sequence given by
+// `find_matches(sequence, positions)`
+fn better_find_kmer_matches_by(graph_sequences: &[Vec<u8>], graph_ids: &[Vec<usize>], k: usize, seeding: Seeding, find_matches: impl Fn(&[u8], &[u32]) -> Vec<(u32, u32)>) -> KmerMatches {
     // go through the paths and get the indices of path and make a list with query index, graph index, paths
     // aggregated result
     let mut all_result_per_path: Vec<Vec<(u32, u32, u32, u32, Vec<u32>)>> = vec![vec![]; graph_sequences.len()]; // seq, graph, graph + k, prev node in path, path index
@@ -377,7 +475,7 @@ pub fn better_find_kmer_matches(query: &[u8], graph_sequences: &Vec<Vec<u8>>, gr
     let mut kmers_previous_node_in_paths: Vec<Vec<u32>> = vec![];
     let mut kmer_graph_path: Vec<Vec<u32>>= vec![];
     for (index, seq) in graph_sequences.iter().enumerate() {
-        let matches = find_kmer_matches_seq1_hashed_2(&set, seq, k);
+        let matches = find_matches(seq, &seed_positions(seq, k, seeding));
         // go through the matches and see if they are in the already made list
         for a_match in matches {
             // get the path index and save it 
@@ -388,7 +486,7 @@ pub fn better_find_kmer_matches(query: &[u8], graph_sequences: &Vec<Vec<u8>>, gr
             }
             let graph_index = graph_ids[index][a_match.1 as usize] as u32;
             let graph_index_minus_1;
-            let graph_index_plus_k = graph_ids[index][a_match.1 as usize - 1 + k] as u32;
+            let graph_index_plus_k = graph_ids[index][a_match.1 as usize + k - 1] as u32;
             if a_match.1 > 0 {
                 graph_index_minus_1 = graph_ids[index][a_match.1 as usize - 1] as u32;
             }
@@ -427,7 +525,13 @@ pub fn better_find_kmer_matches(query: &[u8], graph_sequences: &Vec<Vec<u8>>, gr
     (kmers_result_vec, kmers_paths, kmers_previous_node_in_paths, kmer_graph_path)
 }
 
-pub fn find_sequence_in_graph (sequence: Vec<u8>, graph: &POAGraph, topo_indices: &Vec<usize>, topo_map: &HashMap<usize, usize>, error_index: usize) -> (bool, Vec<usize>, Vec<u8>) {
+pub fn find_sequence_in_graph (sequence: Vec<u8>, graph: &POAGraph, topo_indices: &Vec<usize>, topo_map: &Vec<usize>, error_index: usize) -> Result<(bool, Vec<usize>, Vec<u8>)> {
+    if sequence.is_empty() {
+        return Err(PoaError::EmptySequence);
+    }
+    if graph.node_count() == 0 {
+        return Err(PoaError::EmptyGraph);
+    }
     let mut current_node = 0;
     // created the visit vec
     let mut visited_node: Vec<bool> = vec![false; graph.node_count() + 1];
@@ -451,7 +555,7 @@ pub fn find_sequence_in_graph (sequence: Vec<u8>, graph: &POAGraph, topo_indices
     }
     loop {
         //println!("current {}", current_node);
-        let current_node_mapped = *topo_map.get(&current_node).unwrap();
+        let current_node_mapped = topo_map[current_node];
         // push to vecs required stuff
         final_path[current_index] = current_node_mapped;
         final_sequence[current_index] = graph.raw_nodes()[current_node].weight;
@@ -486,7 +590,7 @@ pub fn find_sequence_in_graph (sequence: Vec<u8>, graph: &POAGraph, topo_indices
         error_occured = true;
         //println!("ERROR");
     }
-    (error_occured, final_path, final_sequence)
+    Ok((error_occured, final_path, final_sequence))
 }
 
 pub fn dfs_get_sequence_paths (
@@ -498,7 +602,7 @@ pub fn dfs_get_sequence_paths (
     current_sequence: Vec<u8>,
     all_paths: &mut Vec<Vec<usize>>,
     all_sequences: &mut Vec<Vec<u8>>,
-    topo_map: &HashMap<usize, usize>
+    topo_map: &Vec<usize>
 ) {
     // go through the temp sequences and pop the ones which do not have the current base.
     let seq_len = temp_sequences.len();
@@ -516,7 +620,7 @@ pub fn dfs_get_sequence_paths (
     
     let mut path = current_path.clone();
     let mut sequence = current_sequence.clone();
-    path.push(*topo_map.get(&start).unwrap());
+    path.push(topo_map[start]);
     sequence.push(graph.raw_nodes()[start].weight);
     // if no neighbours, last node reached
     if (graph.neighbors(NodeIndex::new(start)).count() == 0) && (temp_sequences.len() > 0) {
@@ -679,7 +783,7 @@ pub fn find_kmer_matches_for_divided (
                 // first get the graph index for the match
                 let graph_index = graph_ids[top_index][bottom_index][a_match.1 as usize] as u32;
                 let graph_index_minus_1;
-                let graph_index_plus_k = graph_ids[top_index][bottom_index][a_match.1 as usize - 1 + k] as u32;
+                let graph_index_plus_k = graph_ids[top_index][bottom_index][a_match.1 as usize + k - 1] as u32;
                 if a_match.1 > 0 {
                     graph_index_minus_1 = graph_ids[top_index][bottom_index][a_match.1 as usize - 1] as u32;
                 }
@@ -705,7 +809,7 @@ pub fn find_kmer_matches_for_divided (
     (kmers_result_vec, kmers_plus_k, kmers_paths, kmers_previous_node_in_paths)
 }
 
-pub fn divide_poa_graph_get_paths (output_graph: &POAGraph, topo_indices: &Vec<usize>, total_num_sequences: usize, cut_threshold: usize, topo_map: &HashMap<usize, usize>) -> (Vec<Vec<Vec<usize>>>, Vec<Vec<Vec<u8>>>, usize) {
+pub fn divide_poa_graph_get_paths (output_graph: &POAGraph, topo_indices: &Vec<usize>, total_num_sequences: usize, cut_threshold: usize, topo_map: &Vec<usize>) -> SectionPaths {
     let mut cut_start_end: Vec<(usize, usize)>= vec![];
     let mut current_topo_indices_index: usize = 0;
     let mut current_cut_limit: usize = cut_threshold;
@@ -742,12 +846,13 @@ pub fn divide_poa_graph_get_paths (output_graph: &POAGraph, topo_indices: &Vec<u
     let mut all_all_paths: Vec<Vec<Vec<usize>>> = vec![];
     let mut all_all_sequences: Vec<Vec<Vec<u8>>> = vec![];
     let mut max_number_of_paths_per_section: usize = 0;
+    let mut any_capped = false;
     for current_start_end in cut_start_end {
         let (start, end) = current_start_end;
         let mut all_paths: Vec<Vec<usize>> = vec![];
         let mut all_sequences: Vec<Vec<u8>> = vec![];
         println!("DOING DFS start {} end {}", start, end);
-        simple_dfs_with_start_end(output_graph, start, end, vec![], vec![], &mut all_paths, &mut all_sequences, topo_map);
+        any_capped |= simple_dfs_with_start_end(output_graph, start, end, &mut all_paths, &mut all_sequences, topo_map, PathLimits::default());
         println!("{:?}", all_paths);
         if max_number_of_paths_per_section < all_paths.len() {
             max_number_of_paths_per_section = all_paths.len()
@@ -755,54 +860,60 @@ pub fn divide_poa_graph_get_paths (output_graph: &POAGraph, topo_indices: &Vec<u
         all_all_paths.push(all_paths);
         all_all_sequences.push(all_sequences);
     }
-    return (all_all_paths, all_all_sequences, max_number_of_paths_per_section);
+    (all_all_paths, all_all_sequences, max_number_of_paths_per_section, any_capped)
 }
 
+/// Paths between start and end (every reachable node if `end` is None), enumerated
+/// with an explicit stack up to the given limits. Returns true if a limit was hit.
 pub fn simple_dfs_with_start_end (
-    graph: &POAGraph, 
+    graph: &POAGraph,
     start: usize,
     end: usize,
-    current_path: Vec<usize>,
-    current_sequence: Vec<u8>,
     all_paths: &mut Vec<Vec<usize>>,
     all_sequences: &mut Vec<Vec<u8>>,
-    topo_map: &HashMap<usize, usize>
-) {
-    let mut path = current_path.clone();
-    let mut sequence = current_sequence.clone();
-    path.push(*topo_map.get(&start).unwrap());
-    sequence.push(graph.raw_nodes()[start].weight);
-    // if no neighbours, last node reached
-    if (start == end) || (graph.neighbors(NodeIndex::new(start)).count() == 0) {
-        // dont check for 
-        if start == end {
-            all_paths.push(path.clone());
-            all_sequences.push(sequence.clone());
-        }
-    } else {
-        // go through neighbours recursively
-        for neighbor in graph.neighbors(NodeIndex::new(start)) {
-            simple_dfs_with_start_end(graph, neighbor.index(), end, path.clone(), sequence.clone(), all_paths, all_sequences, topo_map);
-        }
-    }
+    topo_map: &Vec<usize>,
+    limits: PathLimits,
+) -> bool {
+    bounded_dfs(graph, start, Some(end), all_paths, all_sequences, topo_map, limits)
 }
 
 pub fn find_kmer_matches(query: &[u8], graph_sequences: &Vec<Vec<u8>>, graph_ids: &Vec<Vec<usize>>, k: usize) -> (Vec<(u32, u32)>, Vec<u32>, Vec<Vec<usize>>, Vec<Vec<u32>>) {
-    // hash the query
-    let set = hash_kmers(query, k);
+    find_kmer_matches_seeded(query, graph_sequences, graph_ids, k, Seeding::Exhaustive)
+}
+
+/// Same as `find_kmer_matches` but only the k-mers selected by `seeding` are matched
+pub fn find_kmer_matches_seeded(query: &[u8], graph_sequences: &Vec<Vec<u8>>, graph_ids: &Vec<Vec<usize>>, k: usize, seeding: Seeding) -> (Vec<(u32, u32)>, Vec<u32>, Vec<Vec<usize>>, Vec<Vec<u32>>) {
+    if k == 0 || k > MAX_PACKED_K {
+        // too long to pack, the k-mers are hashed as slices
+        let set = hash_kmers_seeded(query, k, seeding);
+        return find_kmer_matches_by(graph_sequences, graph_ids, k, seeding, |seq, positions| find_kmer_matches_seq1_hashed_at(&set, seq, k, positions));
+    }
+    // index the query
+    let query_index = PackedKmerIndex::with_positions(query, k, false, &seed_positions(query, k, seeding));
+    find_kmer_matches_with_index(&query_index, graph_sequences, graph_ids, seeding)
+}
+
+/// Same as `find_kmer_matches_seeded` with an already built (and possibly cached) query index
+pub fn find_kmer_matches_with_index(query_index: &PackedKmerIndex, graph_sequences: &Vec<Vec<u8>>, graph_ids: &Vec<Vec<usize>>, seeding: Seeding) -> (Vec<(u32, u32)>, Vec<u32>, Vec<Vec<usize>>, Vec<Vec<u32>>) {
+    find_kmer_matches_by(graph_sequences, graph_ids, query_index.k(), seeding, |seq, positions| query_index.find_matches(seq, positions))
+}
+
+// `find_kmer_matches` with the matches of the query k-mers in a path sequence given by
+// `find_matches(sequence, positions)`
+fn find_kmer_matches_by(graph_sequences: &[Vec<u8>], graph_ids: &[Vec<usize>], k: usize, seeding: Seeding, find_matches: impl Fn(&[u8], &[u32]) -> Vec<(u32, u32)>) -> PathKmerMatches {
     // go through the paths and get the indices of path and make a list with query index, graph index, paths
     let mut kmers_result_vec: Vec<(u32, u32)> = vec![];
     let mut kmers_plus_k: Vec<u32> = vec![];
     let mut kmers_paths: Vec<Vec<usize>> = vec![];
     let mut kmers_previous_node_in_paths: Vec<Vec<u32>> = vec![];
     for (index, seq) in graph_sequences.iter().enumerate() {
-        let matches = find_kmer_matches_seq1_hashed(&set, seq, k);
+        let matches = find_matches(seq, &seed_positions(seq, k, seeding));
         // go through the matches and see if they are in the already made list
         for a_match in matches {
             // first get the graph index for the match
             let graph_index = graph_ids[index][a_match.1 as usize] as u32;
             let graph_index_minus_1;
-            let graph_index_plus_k = graph_ids[index][a_match.1 as usize - 1 + k] as u32;
+            let graph_index_plus_k = graph_ids[index][a_match.1 as usize + k - 1] as u32;
             if a_match.1 > 0 {
                 graph_index_minus_1 = graph_ids[index][a_match.1 as usize - 1] as u32;
             }
@@ -826,29 +937,125 @@ pub fn find_kmer_matches(query: &[u8], graph_sequences: &Vec<Vec<u8>>, graph_ids
     (kmers_result_vec, kmers_plus_k, kmers_paths, kmers_previous_node_in_paths)
 }
 
+/// All paths from start to a sink, enumerated with an explicit stack up to the given
+/// limits. Returns true if a limit was hit.
 pub fn simple_dfs_all_paths (
-    graph: &POAGraph, 
+    graph: &POAGraph,
     start: usize,
-    current_path: Vec<usize>,
-    current_sequence: Vec<u8>,
     all_paths: &mut Vec<Vec<usize>>,
     all_sequences: &mut Vec<Vec<u8>>,
-    topo_map: &HashMap<usize, usize>
-) {
-    let mut path = current_path.clone();
-    let mut sequence = current_sequence.clone();
-    path.push(*topo_map.get(&start).unwrap());
-    sequence.push(graph.raw_nodes()[start].weight);
-    // if no neighbours, last node reached
-    if graph.neighbors(NodeIndex::new(start)).count() == 0 {
-        all_paths.push(path.clone());
-        all_sequences.push(sequence.clone());
-    } else {
-        // go through neighbours recursively
-        for neighbor in graph.neighbors(NodeIndex::new(start)) {
-            simple_dfs_all_paths(graph, neighbor.index(), path.clone(), sequence.clone(), all_paths, all_sequences, topo_map);
+    topo_map: &Vec<usize>,
+    limits: PathLimits,
+) -> bool {
+    bounded_dfs(graph, start, None, all_paths, all_sequences, topo_map, limits)
+}
+
+/// Caps for path enumeration, the number of paths and the total number of path nodes stored
+#[derive(Copy, Clone, Debug)]
+pub struct PathLimits {
+    pub max_paths: usize,
+    pub max_nodes: usize,
+}
+
+impl Default for PathLimits {
+    fn default() -> Self {
+        PathLimits { max_paths: 64, max_nodes: 1 << 20 }
+    }
+}
+
+/// A bounded set of graph paths (node ranks) and their sequences
+#[derive(Default, Clone, Debug)]
+pub struct PathIndex {
+    pub paths: Vec<Vec<usize>>,
+    pub sequences: Vec<Vec<u8>>,
+    /// Set when the limits stopped the enumeration, the index is then incomplete
+    pub capped: bool,
+}
+
+// depth first enumeration with a stack of (node, depth), the current path is cut back to the
+// depth of the popped node so only finished paths are copied
+fn bounded_dfs(graph: &POAGraph, start: usize, end: Option<usize>, all_paths: &mut Vec<Vec<usize>>, all_sequences: &mut Vec<Vec<u8>>, topo_map: &Vec<usize>, limits: PathLimits) -> bool {
+    let mut stored_nodes: usize = all_paths.iter().map(|path| path.len()).sum();
+    let mut path: Vec<usize> = vec![];
+    let mut sequence: Vec<u8> = vec![];
+    let mut stack: Vec<(usize, usize)> = vec![(start, 0)];
+    while let Some((node, depth)) = stack.pop() {
+        path.truncate(depth);
+        sequence.truncate(depth);
+        path.push(topo_map[node]);
+        sequence.push(graph.raw_nodes()[node].weight);
+        let is_sink = graph.neighbors(NodeIndex::new(node)).next().is_none();
+        if end == Some(node) || (end.is_none() && is_sink) {
+            if all_paths.len() >= limits.max_paths || stored_nodes + path.len() > limits.max_nodes {
+                return true;
+            }
+            stored_nodes += path.len();
+            all_paths.push(path.clone());
+            all_sequences.push(sequence.clone());
+            continue;
+        }
+        // same visiting order as the recursive version
+        let neighbours: Vec<usize> = graph.neighbors(NodeIndex::new(node)).map(|neighbour| neighbour.index()).collect();
+        for neighbour in neighbours.into_iter().rev() {
+            stack.push((neighbour, depth + 1));
         }
     }
+    false
+}
+
+/// The `n` heaviest source to sink paths (sum of edge weights), found with a k best
+/// dynamic programming pass over the topological order instead of enumerating every path
+pub fn heaviest_paths(graph: &POAGraph, topo_indices: &Vec<usize>, topo_map: &Vec<usize>, n: usize) -> PathIndex {
+    // per node the best (score, previous node, entry of the previous node) in decreasing order
+    let mut best: Vec<Vec<(i64, usize, usize)>> = vec![vec![]; graph.node_count()];
+    let mut capped = false;
+    for node in topo_indices {
+        let mut candidates: Vec<(i64, usize, usize)> = vec![];
+        for edge in graph.edges_directed(NodeIndex::new(*node), Incoming) {
+            let previous = edge.source().index();
+            for (entry, (score, _, _)) in best[previous].iter().enumerate() {
+                candidates.push((score + *edge.weight() as i64, previous, entry));
+            }
+        }
+        if candidates.is_empty() {
+            candidates.push((0, usize::MAX, 0));
+        }
+        candidates.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)).then(a.2.cmp(&b.2)));
+        if candidates.len() > n {
+            capped = true;
+            candidates.truncate(n);
+        }
+        best[*node] = candidates;
+    }
+    let mut ends: Vec<(i64, usize, usize)> = vec![];
+    for node in topo_indices {
+        if graph.neighbors(NodeIndex::new(*node)).next().is_none() {
+            ends.extend(best[*node].iter().enumerate().map(|(entry, (score, _, _))| (*score, *node, entry)));
+        }
+    }
+    ends.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)).then(a.2.cmp(&b.2)));
+    if ends.len() > n {
+        capped = true;
+        ends.truncate(n);
+    }
+    let mut index = PathIndex { capped, ..Default::default() };
+    for (_, end, entry) in ends {
+        let (mut node, mut entry) = (end, entry);
+        let mut path = vec![];
+        let mut sequence = vec![];
+        while node != usize::MAX {
+            path.push(topo_map[node]);
+            sequence.push(graph.raw_nodes()[node].weight);
+            let (_, previous, previous_entry) = best[node][entry];
+            node = previous;
+            entry = previous_entry;
+        }
+        path.reverse();
+        sequence.reverse();
+        index.paths.push(path);
+        index.sequences.push(sequence);
+    }
+    index
 }
 
 pub fn hash_kmers(seq: &[u8], k: usize) -> HashMapFx<&[u8], Vec<u32>> {
@@ -874,6 +1081,39 @@ pub fn hash_kmers_2(seq: &[u8], k: usize) -> FxHashMap<&[u8], Vec<u32>> {
     set
 }
 
+/// Like `hash_kmers_2` but only with the k-mers selected by `seeding`, for k-mers too long
+/// to pack
+pub fn hash_kmers_seeded(seq: &[u8], k: usize, seeding: Seeding) -> FxHashMap<&[u8], Vec<u32>> {
+    let mut set: FxHashMap<&[u8], Vec<u32>> = FxHashMap::default();
+    for i in seed_positions(seq, k, seeding) {
+        set.entry(&seq[i as usize..i as usize + k]).or_default().push(i);
+    }
+    set
+}
+
+/// Like `find_kmer_matches_seq1_hashed_2` but only the k-mers of seq2 starting at `positions` are looked up
+pub fn find_kmer_matches_seq1_hashed_at(
+    seq1_set: &FxHashMap<&[u8], Vec<u32>>,
+    seq2: &[u8],
+    k: usize,
+    positions: &[u32],
+) -> Vec<(u32, u32)> {
+    let mut matches = Vec::new();
+    for i in positions {
+        let slc = &seq2[*i as usize..*i as usize + k];
+        if let Some(matches1) = seq1_set.get(slc) {
+            // skip non unique kmers
+            if matches1.len() > 2 {
+                continue;
+            }
+            for pos1 in matches1 {
+                matches.push((*pos1, *i));
+            }
+        }
+    }
+    matches
+}
+
 pub fn find_kmer_matches_seq1_hashed(
     seq1_set: &HashMapFx<&[u8], Vec<u32>>,
     seq2: &[u8],
@@ -921,3 +1161,20 @@ pub fn find_kmer_matches_seq1_hashed_2(
     //matches.sort_unstable();
     matches
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn continued_kmers_keep_their_query_positions() {
+        // a single path spelling the query, too short for two k-mers that do not overlap so
+        // the chain has to continue the first k-mer base by base
+        let query = b"ACGTTG".to_vec();
+        let ranks: Vec<usize> = (0..query.len()).collect();
+        let (kmer_pos_vec, kmer_path_vec, kmers_previous_node_in_paths, kmer_graph_path) = better_find_kmer_matches(&query, &vec![query.clone()], &vec![ranks.clone()], 4);
+        let (path, _, score) = lcskpp_graph(kmer_pos_vec, kmer_path_vec, kmers_previous_node_in_paths, 1, 4, kmer_graph_path, &ranks).unwrap();
+        assert_eq!(score, 6);
+        assert_eq!(path, (0..query.len()).map(|position| (position, position)).collect::<Vec<_>>());
+    }
+}
@@ -7,11 +7,451 @@ use std::time::Instant;
 use rand::{Rng, SeedableRng, rngs::StdRng};
 mod lcsk;
 mod bit_tree;
-use std::collections::HashMap;
-use petgraph::visit::Topo;
-use crate::lcsk::{find_sequence_in_graph, better_find_kmer_matches, lcskpp_graph, anchoring_lcsk_path_for_threading};
+mod alloc_counter;
+mod compact_graph;
+mod read_order;
+mod graph_align;
+mod graph_kmer;
+mod seeding;
+mod packed_kmer;
+mod path_cover;
+mod chaining;
+mod anchor;
+mod error;
+mod kernel_check;
+mod simulate;
+mod bench;
+use alloc_counter::allocation_snapshot;
+use crate::bit_tree::MaxBitTreePool;
+use crate::lcsk::lcsk_path_for_query;
+use crate::read_order::ReadOrder;
+use crate::simulate::{get_random_sequences_from_generator, get_repetitive_sequences_from_generator};
+
+#[cfg(feature = "alloc-counter")]
+#[global_allocator]
+static GLOBAL: alloc_counter::CountingAllocator = alloc_counter::CountingAllocator;
 
 fn main() {
+    let result = match std::env::args().nth(1).as_deref() {
+        Some("alloc-bench") => allocation_benchmark(),
+        Some("order") => run_with_read_order(std::env::args().nth(2).as_deref().unwrap_or("input")),
+        Some("refine") => run_with_refinement(),
+        Some("correct") => run_read_correction(),
+        Some("merge") => run_merge(),
+        Some("graph-align") => run_graph_alignment(),
+        Some("prune") => run_with_pruning(),
+        Some("paths") => run_path_enumeration(),
+        Some("graph-kmers") => run_graph_kmer_index(),
+        Some("seeding") => seeding_benchmark(),
+        Some("strands") => run_mixed_strands(),
+        Some("gap-chain") => gap_chaining_benchmark(),
+        Some("bad-reads") => run_with_bad_reads(),
+        Some("kernel-check") => kernel_cross_check(),
+        Some("simulate") => run_simulated_reads(),
+        Some("bench") => run_benchmark(),
+        _ => run_default(),
+    };
+    // a failed run reports its error instead of panicking
+    if let Err(error) = result {
+        eprintln!("error: {}", error);
+        std::process::exit(1);
+    }
+}
+
+// builds the graph in one call with the given read order strategy
+fn run_with_read_order(order_name: &str) -> error::Result<()> {
+    let read_order = match ReadOrder::from_name(order_name) {
+        Some(read_order) => read_order,
+        None => return Err(error::PoaError::Usage(format!("unknown read order {}, use one of input, longest-first, guide-tree, best-backbone", order_name))),
+    };
+    let seqs: Vec<Vec<u8>> = get_random_sequences_from_generator(100, 10, 0).into_iter().map(|seq| seq.into_bytes()).collect();
+    let now = Instant::now();
+    let aligner = Aligner::from_reads(1, -1, -2, &seqs, read_order, 4, 10)?;
+    let time = now.elapsed().as_micros() as usize;
+    let (used_order, indices) = aligner.read_order();
+    println!("read order {} {:?}", used_order, indices);
+    println!("consensus {}", String::from_utf8_lossy(&aligner.consensus()));
+    println!("graph nodes {} edges {} built in {}μs", aligner.graph().node_count(), aligner.graph().edge_count(), time);
+    Ok(())
+}
+
+// builds the graph and then runs refinement passes until the consensus is stable
+fn run_with_refinement() -> error::Result<()> {
+    let seqs: Vec<Vec<u8>> = get_random_sequences_from_generator(100, 10, 0).into_iter().map(|seq| seq.into_bytes()).collect();
+    let mut aligner = Aligner::from_reads(1, -1, -2, &seqs, ReadOrder::Input, 4, 10)?;
+    println!("consensus before {}", String::from_utf8_lossy(&aligner.consensus()));
+    println!("graph nodes {} edges {}", aligner.graph().node_count(), aligner.graph().edge_count());
+    let now = Instant::now();
+    let iterations = aligner.refine(4, 10, 5)?;
+    let time = now.elapsed().as_micros() as usize;
+    println!("consensus after  {}", String::from_utf8_lossy(&aligner.consensus()));
+    println!("graph nodes {} edges {} after {} passes in {}μs", aligner.graph().node_count(), aligner.graph().edge_count(), iterations, time);
+    Ok(())
+}
+
+// error corrects every read with the consensus of the other reads
+fn run_read_correction() -> error::Result<()> {
+    let seqs: Vec<Vec<u8>> = get_random_sequences_from_generator(100, 10, 0).into_iter().map(|seq| seq.into_bytes()).collect();
+    let aligner = Aligner::from_reads(1, -1, -2, &seqs, ReadOrder::Input, 4, 10)?;
+    let (_, order) = aligner.read_order();
+    // sequence ids follow the order the reads were added in
+    for (seq_id, corrected) in aligner.corrected_reads().iter().enumerate() {
+        if let Some(corrected) = corrected {
+            println!("read {}", order[seq_id]);
+            println!("original  {}", String::from_utf8_lossy(&seqs[order[seq_id]]));
+            println!("corrected {}", String::from_utf8_lossy(corrected));
+        }
+    }
+    Ok(())
+}
+
+// builds two graphs from the two halves of the reads and merges them, compared to one graph of all reads
+fn run_merge() -> error::Result<()> {
+    let seqs: Vec<Vec<u8>> = get_random_sequences_from_generator(100, 10, 0).into_iter().map(|seq| seq.into_bytes()).collect();
+    let (first_half, second_half) = seqs.split_at(seqs.len() / 2);
+    let single = Aligner::from_reads(1, -1, -2, &seqs, ReadOrder::Input, 4, 10)?;
+    let mut merged = Aligner::from_reads(1, -1, -2, &first_half.to_vec(), ReadOrder::Input, 4, 10)?;
+    let other = Aligner::from_reads(1, -1, -2, &second_half.to_vec(), ReadOrder::Input, 4, 10)?;
+    let now = Instant::now();
+    let new_ids = merged.merge(&other, 4, 10)?;
+    let time = now.elapsed().as_micros() as usize;
+    println!("merged sequence ids {:?} in {}μs", new_ids, time);
+    println!("single consensus {}", String::from_utf8_lossy(&single.consensus()));
+    println!("merged consensus {}", String::from_utf8_lossy(&merged.consensus()));
+    println!("single graph nodes {} edges {}", single.graph().node_count(), single.graph().edge_count());
+    println!("merged graph nodes {} edges {}", merged.graph().node_count(), merged.graph().edge_count());
+    Ok(())
+}
+
+// aligns the graphs built from the two halves of the reads to each other
+fn run_graph_alignment() -> error::Result<()> {
+    let seqs: Vec<Vec<u8>> = get_random_sequences_from_generator(100, 10, 0).into_iter().map(|seq| seq.into_bytes()).collect();
+    let (first_half, second_half) = seqs.split_at(seqs.len() / 2);
+    let first = Aligner::from_reads(1, -1, -2, &first_half.to_vec(), ReadOrder::Input, 4, 10)?;
+    let second = Aligner::from_reads(1, -1, -2, &second_half.to_vec(), ReadOrder::Input, 4, 10)?;
+    let now = Instant::now();
+    let alignment = first.align_graph(&second);
+    let time = now.elapsed().as_micros() as usize;
+    let mut counts = [0; 4];
+    for op in &alignment.operations {
+        match op {
+            graph_align::GraphAlignmentOperation::Match(_, _) => counts[0] += 1,
+            graph_align::GraphAlignmentOperation::Mismatch(_, _) => counts[1] += 1,
+            graph_align::GraphAlignmentOperation::Del(_) => counts[2] += 1,
+            graph_align::GraphAlignmentOperation::Ins(_) => counts[3] += 1,
+        }
+    }
+    println!("graph nodes {} and {}", first.graph().node_count(), second.graph().node_count());
+    println!("score {} matches {} mismatches {} deletions {} insertions {} in {}μs", alignment.score, counts[0], counts[1], counts[2], counts[3], time);
+    Ok(())
+}
+
+// adds the reads with and without pruning every 3 reads
+fn run_with_pruning() -> error::Result<()> {
+    let seqs: Vec<Vec<u8>> = get_random_sequences_from_generator(100, 10, 0).into_iter().map(|seq| seq.into_bytes()).collect();
+    for pruning in [None, Some(Pruning { every_reads: 3, min_edge_weight: 2, min_tip_len: 5 })] {
+        let mut aligner = Aligner::new(1, -1, -2, &seqs[0])?;
+        aligner.set_pruning(pruning);
+        let now = Instant::now();
+        for read in &seqs[1..] {
+            aligner.align_with_anchors(read)?;
+        }
+        let time = now.elapsed().as_micros() as usize;
+        println!("pruning {:?}", pruning);
+        println!("consensus {}", String::from_utf8_lossy(&aligner.consensus()));
+        println!("graph nodes {} edges {} built in {}μs", aligner.graph().node_count(), aligner.graph().edge_count(), time);
+    }
+    Ok(())
+}
+
+// enumerates the paths of a graph with the bounded depth first search and the heaviest path search
+fn run_path_enumeration() -> error::Result<()> {
+    let seqs: Vec<Vec<u8>> = get_random_sequences_from_generator(100, 10, 0).into_iter().map(|seq| seq.into_bytes()).collect();
+    let aligner = Aligner::from_reads(1, -1, -2, &seqs, ReadOrder::Input, 4, 10)?;
+    let (topo_order, topo_ranks) = (aligner.topological_order(), aligner.topological_ranks());
+    for max_paths in [16, 256, 4096] {
+        let limits = lcsk::PathLimits { max_paths, ..Default::default() };
+        let (mut all_paths, mut all_sequences) = (vec![], vec![]);
+        let now = Instant::now();
+        let capped = lcsk::simple_dfs_all_paths(aligner.graph(), topo_order[0], &mut all_paths, &mut all_sequences, topo_ranks, limits);
+        let time = now.elapsed().as_micros() as usize;
+        println!("depth first max paths {} found {} capped {} in {}μs", max_paths, all_paths.len(), capped, time);
+        let now = Instant::now();
+        let index = lcsk::heaviest_paths(aligner.graph(), topo_order, topo_ranks, max_paths);
+        let time = now.elapsed().as_micros() as usize;
+        println!("heaviest max paths {} found {} capped {} in {}μs", max_paths, index.paths.len(), index.capped, time);
+    }
+    let index = lcsk::heaviest_paths(aligner.graph(), topo_order, topo_ranks, 1);
+    println!("heaviest path {}", String::from_utf8_lossy(&index.sequences[0]));
+    println!("consensus     {}", String::from_utf8_lossy(&aligner.consensus()));
+    Ok(())
+}
+
+// adds the reads anchored with the path index and with the incrementally updated graph k-mer index
+fn run_graph_kmer_index() -> error::Result<()> {
+    let seqs: Vec<Vec<u8>> = get_random_sequences_from_generator(100, 10, 0).into_iter().map(|seq| seq.into_bytes()).collect();
+    for use_kmer_index in [false, true] {
+        let mut aligner = Aligner::new(1, -1, -2, &seqs[0])?;
+        if use_kmer_index {
+            // a low variant limit so some nodes are capped and their counts are compared too
+            aligner.enable_kmer_index(4, 8)?;
+        }
+        let mut anchor_time = 0;
+        let mut anchors = 0;
+        for read in &seqs[1..] {
+            let now = Instant::now();
+            let lcsk_path = aligner.anchor_query(read, 4)?;
+            anchor_time += now.elapsed().as_micros() as usize;
+            anchors += lcsk_path.len();
+            aligner.global_simd_banded(read, &lcsk_path, 10)?;
+        }
+        println!("graph k-mer index {} anchored bases {} anchoring time {}μs", use_kmer_index, anchors, anchor_time);
+        println!("consensus {}", String::from_utf8_lossy(&aligner.consensus()));
+        if let Some(kmer_index) = aligner.kmer_index() {
+            // the incrementally updated index has to match one built from scratch
+            let rebuilt = graph_kmer::GraphKmerIndex::new(aligner.graph(), aligner.topological_order(), 4, 8);
+            println!("k-mers {} occurrences {} capped nodes {}", kmer_index.kmer_count(), kmer_index.occurrence_count(), kmer_index.capped_nodes());
+            println!("rebuilt k-mers {} occurrences {} capped nodes {}", rebuilt.kmer_count(), rebuilt.occurrence_count(), rebuilt.capped_nodes());
+        }
+    }
+    Ok(())
+}
+
+// anchor recall and band quality of minimizer and syncmer seeding against exhaustive k-mers,
+// the reads are aligned to a graph of the first reads; band quality is the fraction of reads
+// where the banded score equals the full DP score and the mean score lost to the band
+fn seeding_benchmark() -> error::Result<()> {
+    let kmer_size = 8;
+    let band_size = 40;
+    let seqs: Vec<Vec<u8>> = get_random_sequences_from_generator(1000, 20, 0).into_iter().map(|seq| seq.into_bytes()).collect();
+    let mut poa = Poa::from_string(1, -1, -2, &seqs[0])?;
+    for read in &seqs[1..10] {
+        let lcsk_path = poa.anchor_query(read, kmer_size)?;
+        let alignment = poa.custom_simd_indirect_address(read, &lcsk_path, band_size);
+        poa.add_alignment(&alignment, read)?;
+    }
+    let schemes = [seeding::Seeding::Exhaustive, seeding::Seeding::Minimizer { w: 5 }, seeding::Seeding::Minimizer { w: 10 }, seeding::Seeding::Syncmer { s: 4, offset: 0 }, seeding::Seeding::Syncmer { s: 5, offset: 2 }];
+    println!("seeding\tanchors\trecall\tanchored_bases\texact_band\tscore_loss\ttime_us");
+    let mut exhaustive_anchors: Vec<Vec<(u32, u32)>> = vec![];
+    for seeding in schemes {
+        poa.set_seeding(seeding);
+        let anchorer = poa.anchorer(kmer_size);
+        let (mut anchors, mut recalled, mut exhaustive, mut anchored_bases, mut exact_band, mut score_loss, mut time) = (0, 0, 0, 0, 0, 0, 0);
+        for (read_index, read) in seqs[10..].iter().enumerate() {
+            let now = Instant::now();
+            let anchor_set = anchorer.find_anchors(read)?;
+            let chain = anchorer.chain(&anchor_set)?;
+            time += now.elapsed().as_micros() as usize;
+            let positions = anchor_set.positions();
+            if seeding == seeding::Seeding::Exhaustive {
+                exhaustive_anchors.push(positions.clone());
+            }
+            anchors += anchor_set.len();
+            exhaustive += exhaustive_anchors[read_index].len();
+            recalled += positions.iter().filter(|anchor| exhaustive_anchors[read_index].binary_search(anchor).is_ok()).count();
+            anchored_bases += chain.path.len();
+            let banded = poa.custom_simd_indirect_address(read, &chain.path, band_size).score;
+            let full = poa.custom_simd(read).score;
+            score_loss += full - banded;
+            if banded == full {
+                exact_band += 1;
+            }
+        }
+        let reads = (seqs.len() - 10) as f64;
+        println!("{}\t{}\t{:.3}\t{}\t{:.3}\t{:.1}\t{}", seeding, anchors, recalled as f64 / exhaustive as f64, anchored_bases, exact_band as f64 / reads, score_loss as f64 / reads, time);
+    }
+    Ok(())
+}
+
+// every other read is reverse complemented, the orientation is detected when the read is added
+fn run_mixed_strands() -> error::Result<()> {
+    let seqs: Vec<Vec<u8>> = get_random_sequences_from_generator(100, 10, 0).into_iter().map(|seq| seq.into_bytes()).collect();
+    let mut aligner = Aligner::new(1, -1, -2, &seqs[0])?;
+    let mut correct = 0;
+    for (index, read) in seqs.iter().enumerate().skip(1) {
+        let (truth, read) = if index % 2 == 1 { (lcsk::Orientation::Reverse, lcsk::reverse_complement(read)) } else { (lcsk::Orientation::Forward, read.clone()) };
+        let orientation = aligner.add_read(&read, 4, 10)?;
+        if orientation == truth {
+            correct += 1;
+        }
+        println!("read {} true orientation {:?} detected {:?}", index, truth, orientation);
+    }
+    println!("orientation correct for {} of {} reads", correct, seqs.len() - 1);
+    println!("consensus {}", String::from_utf8_lossy(&aligner.consensus()));
+    Ok(())
+}
+
+// LCSk++ and gap cost chaining of reads from a template with a 200 base repeat in three copies.
+// A long jump is a link of the chain whose query and rank distances differ by more than 50.
+fn gap_chaining_benchmark() -> error::Result<()> {
+    let kmer_size = 8;
+    let band_size = 40;
+    let seqs: Vec<Vec<u8>> = get_repetitive_sequences_from_generator(1000, 20, 0, 200, 3).into_iter().map(|seq| seq.into_bytes()).collect();
+    let mut poa = Poa::from_string(1, -1, -2, &seqs[0])?;
+    for (index, read) in seqs.iter().enumerate().take(10).skip(1) {
+        let lcsk_path = poa.anchor_query(read, kmer_size)?;
+        let alignment = poa.custom_simd_indirect_address(read, &lcsk_path, band_size);
+        // the band can lose the read in the repeats, the traceback then stops early
+        if let Err(error) = poa.add_alignment(&alignment, read) {
+            println!("read {} left out of the graph: {}", index, error);
+        }
+    }
+    println!("chaining\tanchored_bases\tlong_jumps\texact_band\tscore_loss\ttime_us");
+    for (name, chaining) in [("lcsk", chaining::Chaining::PerPath), ("lcsk_cover", chaining::Chaining::PathCover), ("gap_cost", chaining::Chaining::GapCost(chaining::GapCost::default()))] {
+        poa.set_chaining(chaining);
+        let (mut anchored_bases, mut long_jumps, mut exact_band, mut score_loss, mut time) = (0, 0, 0, 0, 0);
+        for read in &seqs[10..] {
+            let now = Instant::now();
+            let lcsk_path = poa.anchor_query(read, kmer_size)?;
+            time += now.elapsed().as_micros() as usize;
+            anchored_bases += lcsk_path.len();
+            let ranks = poa.topological_ranks();
+            long_jumps += lcsk_path.windows(2).filter(|pair| {
+                let query_distance = pair[1].0 as i64 - pair[0].0 as i64;
+                let rank_distance = ranks[pair[1].1] as i64 - ranks[pair[0].1] as i64;
+                (query_distance - rank_distance).abs() > 50
+            }).count();
+            let banded = poa.custom_simd_indirect_address(read, &lcsk_path, band_size).score;
+            let full = poa.custom_simd(read).score;
+            score_loss += full - banded;
+            if banded == full {
+                exact_band += 1;
+            }
+        }
+        let reads = (seqs.len() - 10) as f64;
+        println!("{}\t{}\t{}\t{:.3}\t{:.1}\t{}", name, anchored_bases, long_jumps, exact_band as f64 / reads, score_loss as f64 / reads, time);
+    }
+    Ok(())
+}
+
+// a batch with an empty read and a read with an N in it, the bad reads are reported and
+// skipped and the rest still end up in the graph; then an alignment given with the wrong
+// sequence, a k-mer size the graph index can not pack and the path anchoring that still takes it
+fn run_with_bad_reads() -> error::Result<()> {
+    let seqs: Vec<Vec<u8>> = get_random_sequences_from_generator(100, 10, 0).into_iter().map(|seq| seq.into_bytes()).collect();
+    let mut reads = seqs[1..].to_vec();
+    reads.insert(2, vec![]);
+    let mut with_n = seqs[3].clone();
+    with_n[50] = b'N';
+    reads.insert(5, with_n);
+    let mut aligner = Aligner::new(1, -1, -2, &seqs[0])?;
+    let mut skipped = 0;
+    for (index, read) in reads.iter().enumerate() {
+        if let Err(error) = aligner.align_with_anchors(read) {
+            println!("read {} skipped: {}", index, error);
+            skipped += 1;
+        }
+    }
+    println!("added {} of {} reads", reads.len() - skipped, reads.len());
+    println!("consensus {}", String::from_utf8_lossy(&aligner.consensus()));
+    let mut poa = Poa::from_string(1, -1, -2, &seqs[0])?;
+    let alignment = poa.custom_simd(&seqs[1]);
+    let node_count = poa.graph.node_count();
+    if let Err(error) = poa.add_alignment(&alignment, &seqs[1][..90].to_vec()) {
+        println!("alignment with a truncated sequence: {}, graph nodes {} before and {} after", error, node_count, poa.graph.node_count());
+    }
+    if let Err(error) = poa.enable_kmer_index(40, 4) {
+        println!("graph k-mer index with k 40: {}", error);
+    }
+    println!("anchoring the graph sequence with k 40: {} anchors", poa.anchor_query(&seqs[0], 40)?.len());
+    if let Err(error) = poa.anchor_query(&seqs[1], 0) {
+        println!("anchoring with k 0: {}", error);
+    }
+    Ok(())
+}
+
+// runs the scalar, SIMD and banded kernels side by side on random graphs: reads of random
+// length and number from random seeds are added one by one (with the full SIMD kernel), each
+// checked against the graph of the reads before it; the first divergence of each kind is printed
+fn kernel_cross_check() -> error::Result<()> {
+    let mut rng = StdRng::seed_from_u64(0);
+    let (mut reads, mut simd_agrees, mut banded_agrees, mut banded_score_equal, mut banded_cells, mut banded_below, mut rejected) = (0, 0, 0, 0, 0, 0, 0);
+    let mut reported = (false, false);
+    for graph_index in 0..20 {
+        let (length, count, seed) = (rng.gen_range(20..200), rng.gen_range(3..8), rng.gen_range(0..10_000));
+        let seqs: Vec<Vec<u8>> = get_random_sequences_from_generator(length, count, seed).into_iter().map(|seq| seq.into_bytes()).collect();
+        let mut poa = Poa::from_string(1, -1, -2, &seqs[0])?;
+        for (read_index, read) in seqs.iter().enumerate().skip(1) {
+            let lcsk_path = poa.anchor_query(read, 4)?;
+            let check = kernel_check::check_kernels(&poa, read, &lcsk_path, 10)?;
+            reads += 1;
+            simd_agrees += check.simd_agrees() as usize;
+            banded_agrees += check.banded_agrees() as usize;
+            banded_score_equal += (check.banded_score == check.scalar_score) as usize;
+            banded_cells += check.banded_cells;
+            banded_below += check.banded_below;
+            rejected += check.add_errors.len();
+            if let (Some(cell), false) = (check.simd_divergence, reported.0) {
+                println!("graph {} (length {} seed {}) read {}: simd differs first at {:?}", graph_index, length, seed, read_index, cell);
+                reported.0 = true;
+            }
+            if let (Some(cell), false) = (check.banded_divergence, reported.1) {
+                println!("graph {} (length {} seed {}) read {}: banded differs first at {:?}", graph_index, length, seed, read_index, cell);
+                reported.1 = true;
+            }
+            for (kernel, error) in &check.add_errors {
+                println!("graph {} read {}: {:?} alignment rejected: {}", graph_index, read_index, kernel, error);
+            }
+            let alignment = poa.custom_simd(read);
+            poa.add_alignment(&alignment, read)?;
+        }
+    }
+    println!("{} reads, simd agrees with scalar for {}, banded for {} (same score {}), {} banded cells compared ({} below scalar), {} alignments rejected", reads, simd_agrees, banded_agrees, banded_score_equal, banded_cells, banded_below, rejected);
+    Ok(())
+}
+
+// reads simulated with each error profile, the measured error rates and the consensus
+// identity to the truth, then nanopore reads from both strands with chimeras
+fn run_simulated_reads() -> error::Result<()> {
+    let profiles = [("uniform", simulate::ErrorProfile::uniform(0.03, 0.03, 0.03)), ("ont", simulate::ErrorProfile::ont()), ("clr", simulate::ErrorProfile::pacbio_clr()), ("hifi", simulate::ErrorProfile::pacbio_hifi())];
+    for (name, profile) in profiles {
+        let config = simulate::SimulationConfig { length: 1000, reads: 10, profile, reverse_fraction: 0.0, chimera_fraction: 0.0, seed: 0 };
+        let simulation = simulate::simulate(&config);
+        let (mut substituted, mut inserted, mut deleted) = (0, 0, 0);
+        for read in &simulation.reads {
+            let counts = read.error_counts();
+            substituted += counts.0;
+            inserted += counts.1;
+            deleted += counts.2;
+        }
+        let truth_bases = (config.length * config.reads) as f64;
+        let (aligner, rejected) = add_simulated_reads(&simulation)?;
+        let consensus = aligner.consensus();
+        println!("{} substitutions {:.4} insertions {:.4} deletions {:.4} per truth base, {} reads rejected, consensus length {} identity {:.4}", name, substituted as f64 / truth_bases, inserted as f64 / truth_bases, deleted as f64 / truth_bases, rejected, consensus.len(), simulation.identity(&consensus));
+    }
+    let config = simulate::SimulationConfig { length: 1000, reads: 10, profile: simulate::ErrorProfile::ont(), reverse_fraction: 0.5, chimera_fraction: 0.2, seed: 2 };
+    let simulation = simulate::simulate(&config);
+    for (index, read) in simulation.reads.iter().enumerate() {
+        let pieces: Vec<String> = read.segments.iter().map(|segment| format!("{}..{} {:?}", segment.truth_start, segment.truth_end, segment.orientation)).collect();
+        println!("read {} from {}{}", index, pieces.join(" + "), if read.is_chimeric() { " chimeric" } else { "" });
+    }
+    let (aligner, rejected) = add_simulated_reads(&simulation)?;
+    println!("both strands with chimeras {} reads rejected, consensus identity {:.4}", rejected, simulation.identity(&aligner.consensus()));
+    Ok(())
+}
+
+// adds the reads from either strand, reads the graph rejects (the band lost the path) are counted and skipped
+fn add_simulated_reads(simulation: &simulate::Simulation) -> error::Result<(Aligner, usize)> {
+    let mut aligner = Aligner::new(1, -1, -2, &simulation.reads[0].seq)?;
+    let mut rejected = 0;
+    for read in &simulation.reads[1..] {
+        if aligner.add_read(&read.seq, 8, 50).is_err() {
+            rejected += 1;
+        }
+    }
+    Ok((aligner, rejected))
+}
+
+// every aligner variant on simulated or FASTA datasets, options as in `BenchConfig::from_args`
+fn run_benchmark() -> error::Result<()> {
+    let args: Vec<String> = std::env::args().skip(2).collect();
+    match bench::BenchConfig::from_args(&args) {
+        Ok(config) => bench::run_bench(&config),
+        Err(message) => Err(error::PoaError::Usage(message)),
+    }
+}
+
+fn run_default() -> error::Result<()> {
     let match_score = 1;
     let mismatch_score = -1;
     let gap_open_score = 2;
@@ -21,108 +461,58 @@ fn main() {
     let kmer_size = 4;
     let mut all_paths = vec![];
     let mut all_sequences = vec![];
+    let mut pool = MaxBitTreePool::new();
     let seqs = get_random_sequences_from_generator(100, 10, seed);
-    let mut aligner = Aligner::new(match_score, mismatch_score, -gap_open_score, &seqs[0].as_bytes().to_vec());
+    let mut aligner = Aligner::new(match_score, mismatch_score, -gap_open_score, &seqs[0].as_bytes().to_vec())?;
     for index in 1..seqs.len() {
-        let output_graph = aligner.graph();
-        let mut topo = Topo::new(&output_graph);
-        let mut topo_indices = vec![];
-        let mut topo_map = HashMap::new();
-        let mut incrementing_index: usize = 0;
-        while let Some(node) = topo.next(&output_graph) {
-            topo_indices.push(node.index());
-            topo_map.insert(node.index(), incrementing_index);
-            incrementing_index += 1;
-        }
-        let mut error_index = 0;
-        loop {
-            let (error_occured, temp_path, temp_sequence) = find_sequence_in_graph (seqs[index - 1].as_bytes().to_vec().clone(), &output_graph, &topo_indices, &topo_map, error_index);
-            if error_index > 10 {
-                break;
-            }
-            if !error_occured {
-                all_paths.push(temp_path);
-                all_sequences.push(temp_sequence);
-                break;
-            }
-            error_index += 1;   
-        }
         let query = &seqs[index].as_bytes().to_vec();
-        let (kmer_pos_vec, kmer_path_vec, kmers_previous_node_in_paths, kmer_graph_path) = better_find_kmer_matches(&query, &all_sequences, &all_paths, kmer_size);
-        let (lcsk_path, _lcsk_path_unconverted, _k_new_score) = lcskpp_graph(kmer_pos_vec, kmer_path_vec, kmers_previous_node_in_paths, all_paths.len(), kmer_size, kmer_graph_path, &topo_indices);
+        let lcsk_path = lcsk_path_for_query(aligner.graph(), aligner.topological_order(), aligner.topological_ranks(), seqs[index - 1].as_bytes(), query, &mut all_paths, &mut all_sequences, kmer_size, &mut pool)?;
         //println!("{:?}", lcsk_path);
         let now = Instant::now();
         //aligner.global_simd(query);
-        aligner.global_simd_banded(query, &lcsk_path, band_size);
+        aligner.global_simd_banded(query, &lcsk_path, band_size)?;
         let time = now.elapsed().as_micros() as usize;
         println!("Completed kmer time elapsed time {}μs", time);
     }
-    
-}
-
-fn get_random_sequences_from_generator(sequence_length: usize, num_of_sequences: usize, seed: usize) -> Vec<String> {
-    let mut rng = StdRng::seed_from_u64(seed as u64);
-    //vector to save all the sequences 
-    let mut randomvec: Vec<String> = vec![];
-    //generate the first sequence of random bases of length sequence_length
-    let mut firstseq: Vec<char> = vec![];
-    for _ in 0..sequence_length {
-        firstseq.push(match rng.gen_range(0..4) {
-            0 => 'A',
-            1 => 'C',
-            2 => 'G',
-            3 => 'T',
-            _ => 'X'
-        });
-    }
-    //randomvec.push(firstseq.iter().collect::<String>());
-    //loop for 10 
-    for _ in 0..num_of_sequences {
-        //clone the sequence
-        let mut mutseq = firstseq.clone();
-        //mutate the all the bases with 0.05 chance
-        for i in 0..mutseq.len() {
-            match rng.gen_range(0..20) {
-                0 => {
-                    mutseq[i] = match rng.gen_range(0..4) {
-                        0 => 'A',
-                        1 => 'C',
-                        2 => 'G',
-                        3 => 'T',
-                        _ => 'X'
-                    }
-                },
-                _ => {}
-            }
-        }
-        //put indels at location with chance 0.1 
-        for i in 0..mutseq.len() {
-            let mean_value: f64 = 1.5; //2.0 before
-            //get length of the indel geometric distributed mean value 1.5
-            let indel_length: usize  = ((1.0 - rng.gen::<f64>()).ln() / (1.00 - (1.00 / mean_value) as f64).ln()).ceil() as usize;
-            match rng.gen_range(0..20) {
-                //insertion of elements
-                0 => {
-                    if i + indel_length < mutseq.len() {
-                        for _ in 0..indel_length{
-                            mutseq.insert(i + 1, mutseq[i]);
-                        }
-                    }
-                },
-                //deletion of elements
-                1 => {
-                    if i + indel_length < mutseq.len() {
-                        for _ in 0..indel_length{
-                            mutseq.remove(i);
-                        }
-                    }
-                }
-                _ => {}
-            }
-        }
-        //println!("{:?}", mutseq.iter().collect::<String>());
-        //insert to vector
-        randomvec.push(mutseq.iter().collect::<String>());
+    Ok(())
+}
+
+// compares the allocations of the banded kernel with a fresh and a reused workspace
+fn allocation_benchmark() -> error::Result<()> {
+    if !alloc_counter::counting() {
+        return Err(error::PoaError::Usage("alloc-bench needs the allocation counter, run it with --features alloc-counter".to_string()));
+    }
+    let match_score = 1;
+    let mismatch_score = -1;
+    let gap_open_score = 2;
+    let band_size = 10;
+    let seed = 0;
+    let kmer_size = 4;
+    let mut all_paths = vec![];
+    let mut all_sequences = vec![];
+    let mut pool = MaxBitTreePool::new();
+    let seqs = get_random_sequences_from_generator(100, 10, seed);
+    let mut poa = Poa::from_string(match_score, mismatch_score, -gap_open_score, &seqs[0].as_bytes().to_vec())?;
+    let mut workspace = AlignerWorkspace::new();
+    let (mut total_fresh, mut total_reused) = (0, 0);
+    for index in 1..seqs.len() {
+        let query = &seqs[index].as_bytes().to_vec();
+        let lcsk_path = lcsk_path_for_query(&poa.graph, poa.topological_order(), poa.topological_ranks(), seqs[index - 1].as_bytes(), query, &mut all_paths, &mut all_sequences, kmer_size, &mut pool)?;
+        let (allocs_start, bytes_start) = allocation_snapshot();
+        let now = Instant::now();
+        let fresh_alignment = poa.custom_simd_indirect_address(query, &lcsk_path, band_size);
+        let fresh_time = now.elapsed().as_micros() as usize;
+        let (allocs_mid, bytes_mid) = allocation_snapshot();
+        let now = Instant::now();
+        let reused_alignment = poa.custom_simd_indirect_address_with_workspace(query, &lcsk_path, band_size, &mut workspace);
+        let reused_time = now.elapsed().as_micros() as usize;
+        let (allocs_end, bytes_end) = allocation_snapshot();
+        assert!(fresh_alignment == reused_alignment);
+        total_fresh += allocs_mid - allocs_start;
+        total_reused += allocs_end - allocs_mid;
+        println!("read {} fresh: {} allocs {} bytes {}μs, workspace: {} allocs {} bytes {}μs", index, allocs_mid - allocs_start, bytes_mid - bytes_start, fresh_time, allocs_end - allocs_mid, bytes_end - bytes_mid, reused_time);
+        poa.add_alignment(&reused_alignment, query)?;
     }
-    randomvec
-}
\ No newline at end of file
+    println!("total allocations fresh {} workspace {}", total_fresh, total_reused);
+    Ok(())
+}
@@ -0,0 +1,193 @@
+//! 2-bit packed k-mers
+//!
+//! Every base is packed into two bits (A 0, C 1, G 2, T 3) so a k-mer of up to 32 bases is
+//! a `u64` key, updated by a shift per position instead of hashing k bytes. A window with
+//! a base other than ACGT has no key, the rolling state restarts after that base. With
+//! `canonical` set the key is the smaller of the k-mer and its reverse complement. The
+//! k-mer matching functions of `lcsk` hash longer k-mers as slices instead.
+use fxhash::FxHashMap;
+use crate::error::{PoaError, Result};
+
+pub const MAX_PACKED_K: usize = 32;
+const INVALID: u8 = 4;
+
+const fn base_codes() -> [u8; 256] {
+    let mut codes = [INVALID; 256];
+    codes[b'A' as usize] = 0;
+    codes[b'a' as usize] = 0;
+    codes[b'C' as usize] = 1;
+    codes[b'c' as usize] = 1;
+    codes[b'G' as usize] = 2;
+    codes[b'g' as usize] = 2;
+    codes[b'T' as usize] = 3;
+    codes[b't' as usize] = 3;
+    codes
+}
+
+static BASE_CODES: [u8; 256] = base_codes();
+
+/// Error for a k-mer size that does not pack into a `u64` key
+pub fn check_kmer_size(k: usize) -> Result<()> {
+    if k == 0 || k > MAX_PACKED_K {
+        return Err(PoaError::InvalidKmerSize { k, max: Some(MAX_PACKED_K) });
+    }
+    Ok(())
+}
+
+/// Error for a k-mer size of 0, k-mer matching hashes k-mers too long to pack as slices
+pub fn check_match_kmer_size(k: usize) -> Result<()> {
+    if k == 0 {
+        return Err(PoaError::InvalidKmerSize { k, max: None });
+    }
+    Ok(())
+}
+
+/// Key of every k-mer start position of `seq`, `None` where the window has a non-ACGT base
+pub fn packed_kmer_keys(seq: &[u8], k: usize, canonical: bool) -> Vec<Option<u64>> {
+    assert!(k > 0 && k <= MAX_PACKED_K, "packed k-mers need 0 < k <= {}", MAX_PACKED_K);
+    if seq.len() < k {
+        return vec![];
+    }
+    let mask: u64 = if k == 32 { u64::MAX } else { (1 << (2 * k)) - 1 };
+    let reverse_shift = 2 * (k as u64 - 1);
+    let mut keys = vec![None; seq.len() - k + 1];
+    let (mut forward, mut reverse) = (0u64, 0u64);
+    // number of valid bases at the end of the current window
+    let mut valid = 0;
+    for (position, base) in seq.iter().enumerate() {
+        let code = BASE_CODES[*base as usize];
+        if code == INVALID {
+            valid = 0;
+            continue;
+        }
+        forward = ((forward << 2) | code as u64) & mask;
+        // the complement of the new base goes in at the front of the reverse strand
+        reverse = (reverse >> 2) | ((3 - code as u64) << reverse_shift);
+        valid += 1;
+        if valid >= k {
+            keys[position + 1 - k] = Some(if canonical { forward.min(reverse) } else { forward });
+        }
+    }
+    keys
+}
+
+/// Owned index of the k-mers of a sequence by packed key, can be kept between reads
+#[derive(Default, Clone, Debug)]
+pub struct PackedKmerIndex {
+    k: usize,
+    canonical: bool,
+    positions: FxHashMap<u64, Vec<u32>>,
+}
+
+impl PackedKmerIndex {
+    /// Index every k-mer of the sequence
+    pub fn new(seq: &[u8], k: usize, canonical: bool) -> Self {
+        let keys = packed_kmer_keys(seq, k, canonical);
+        let mut index = PackedKmerIndex { k, canonical, positions: FxHashMap::default() };
+        for (position, key) in keys.iter().enumerate() {
+            if let Some(key) = key {
+                index.positions.entry(*key).or_default().push(position as u32);
+            }
+        }
+        index
+    }
+
+    /// Index only the k-mers starting at the given positions
+    pub fn with_positions(seq: &[u8], k: usize, canonical: bool, positions: &[u32]) -> Self {
+        let keys = packed_kmer_keys(seq, k, canonical);
+        let mut index = PackedKmerIndex { k, canonical, positions: FxHashMap::default() };
+        for position in positions {
+            if let Some(key) = keys[*position as usize] {
+                index.positions.entry(key).or_default().push(*position);
+            }
+        }
+        index
+    }
+
+    pub fn k(&self) -> usize {
+        self.k
+    }
+
+    pub fn canonical(&self) -> bool {
+        self.canonical
+    }
+
+    /// Number of distinct k-mers
+    pub fn len(&self) -> usize {
+        self.positions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.positions.is_empty()
+    }
+
+    /// Positions of the k-mer with the given key
+    pub fn get(&self, key: u64) -> Option<&Vec<u32>> {
+        self.positions.get(&key)
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &u64> {
+        self.positions.keys()
+    }
+
+    /// Matches (position in the indexed sequence, position in `seq2`) of the k-mers of `seq2`
+    /// starting at `positions`, k-mers seen more than twice in the index are skipped
+    pub fn find_matches(&self, seq2: &[u8], positions: &[u32]) -> Vec<(u32, u32)> {
+        let keys = packed_kmer_keys(seq2, self.k, self.canonical);
+        let mut matches = Vec::new();
+        for i in positions {
+            let key = match keys[*i as usize] {
+                Some(key) => key,
+                None => continue,
+            };
+            if let Some(matches1) = self.positions.get(&key) {
+                // skip non unique kmers
+                if matches1.len() > 2 {
+                    continue;
+                }
+                for pos1 in matches1 {
+                    matches.push((*pos1, *i));
+                }
+            }
+        }
+        matches
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lcsk::reverse_complement;
+    use rand::{Rng, SeedableRng, rngs::StdRng};
+
+    #[test]
+    fn keys_match_naive_packing() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let bases = b"ACGTacgt";
+        // about one N in a hundred bases
+        let seq: Vec<u8> = (0..5_000).map(|_| if rng.gen_range(0..100) == 0 { b'N' } else { bases[rng.gen_range(0..8)] }).collect();
+        for k in [1, 4, 15, 31, 32] {
+            let keys = packed_kmer_keys(&seq, k, false);
+            assert_eq!(keys.len(), seq.len() - k + 1);
+            for (position, key) in keys.iter().enumerate() {
+                let window = &seq[position..position + k];
+                let expected = if window.contains(&b'N') {
+                    None
+                } else {
+                    Some(window.iter().fold(0u64, |key, base| (key << 2) | match base.to_ascii_uppercase() { b'A' => 0, b'C' => 1, b'G' => 2, _ => 3 }))
+                };
+                assert_eq!(*key, expected, "k {} position {}", k, position);
+            }
+            // canonical keys are the same set on both strands
+            let mut forward: Vec<u64> = packed_kmer_keys(&seq, k, true).into_iter().flatten().collect();
+            let mut reverse: Vec<u64> = packed_kmer_keys(&reverse_complement(&seq), k, true).into_iter().flatten().collect();
+            forward.sort_unstable();
+            reverse.sort_unstable();
+            assert_eq!(forward, reverse);
+        }
+        assert!(packed_kmer_keys(&seq[..3], 4, false).is_empty());
+        assert!(check_kmer_size(32).is_ok());
+        assert!(check_kmer_size(33).is_err() && check_kmer_size(0).is_err());
+        assert!(check_match_kmer_size(33).is_ok() && check_match_kmer_size(0).is_err());
+    }
+}
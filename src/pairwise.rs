@@ -1,10 +1,21 @@
-
+//! Pairwise alignment of two sequences without a graph
+//!
+//! `pairwise` (affine gaps) and `pairwise_without_extend` (linear gaps) are the scalar
+//! aligners, `pairwise_simd` and `pairwise_simd_without_extend` compute the same alignments
+//! eight query columns at a time. All of them return a `PairwiseAlignment`. Scores are
+//! added, so mismatch and gap scores are negative; a gap of length l scores
+//! `gap_open_score + l * gap_extend_score`. Rows are bases of `seq_x`, columns bases of
+//! `seq_y`, and ties go to the diagonal, then to a gap in `seq_x` ('d'), then to a gap in
+//! `seq_y` ('i'), the same in every kernel. The affine aligners also have local and
+//! semi-global modes, see `PairwiseMode`.
 use std::cmp;
 const MIN_SCORE: isize = -858_993_459; // negative infinity
-use std::simd::{i16x8, Simd};
-use std::simd::cmp::SimdOrd;
-use std::simd::cmp::SimdPartialOrd;
-use std::collections::HashMap;
+use std::simd::i32x8;
+use std::simd::cmp::{SimdOrd, SimdPartialEq};
+use std::simd::num::SimdInt;
+use std::simd::{mask32x8, Select};
+use crate::error::Result;
+use crate::poa::{check_sequence, Poa, BASE_TO_INDEX};
 
 #[derive(Clone)]
 struct PairwiseMatrixCell {
@@ -19,75 +30,89 @@ struct PairwiseMatrixCellNoExtend {
     back: char,
 }
 
-pub fn pairwise_simd_without_extend (seq_x: &Vec<u8>, seq_y: &Vec<u8>, match_score: i32, mismatch_score: i32, gap_open_score: i32, gap_extend_score: i32) {
-    println!("simd without extend");
-    // initialize hash map for profiling 
-    let mut hash_table = HashMap::new();
-    hash_table.insert(65, 0);
-    hash_table.insert(67, 1);
-    hash_table.insert(71, 2);
-    hash_table.insert(84, 3);
-    // make the MM table 
-    let MM_simd_full = profile_query(seq_y, match_score, mismatch_score);
-    // filling out score matrices and back matrix
-    // the number of vectors for query SIMD 8 for now
-    let num_seq_vec = seq_y.len() / 8;
-    // make vectors of 8 of size num_seq_vec
-    let gap_open_score = gap_open_score as i16;
-    let gap_extend_score = gap_extend_score as i16;
-    let gap_open_8 = i16x8::from_array([gap_open_score, gap_open_score, gap_open_score, gap_open_score, gap_open_score, gap_open_score, gap_open_score, gap_open_score]);
-    let gap_extend_8 = i16x8::from_array([gap_extend_score, gap_extend_score, gap_extend_score, gap_extend_score, gap_extend_score, gap_extend_score, gap_extend_score, gap_extend_score]);
-    let left_mask_1 = i16x8::from_array([0, 1, 1, 1, 1, 1, 1, 1]);
-    let right_mask_7 = i16x8::from_array([1, 0, 0, 0, 0, 0, 0, 0]);
-    let mut HH: Vec<i16x8> = vec![i16x8::from_array([0, 0, 0, 0, 0, 0, 0, 0]); num_seq_vec];
-    for i in 0..seq_x.len() {
-        let mut X = i16x8::from_array([0, 0, 0, 0, 0, 0, 0, 0]);
-        let mut F = i16x8::from_array([0, 0, 0, 0, 0, 0, 0, 0]);
-        // get the database base
-        let data_base_index = hash_table.get(&seq_x[i]).unwrap();
-        for j in 0..num_seq_vec {
-            let mut H = HH[j].clone();
-            let mut E = HH[j].clone() - gap_extend_8 - gap_open_8;
-            //println!("H {:?}", H);
-            let T1 = H.rotate_elements_left::<7>() * right_mask_7;
-            //println!("T1 {:?}", T1);
-            H = (H.rotate_elements_right::<1>() * left_mask_1) + X;
-            //println!("H after rotate {:?}", H);
-            X = T1;
-            // convert MM to simd
-            let MM_simd = MM_simd_full[*data_base_index][j];
-            //let MM_simd = i16x8::from_array(MM[0..8].try_into().expect(""));
-            //println!("MM {:?}", MM_simd);
-            H = H + MM_simd;
-            H = H.simd_max(E);
-            F = F.rotate_elements_left::<7>() * right_mask_7;
-            F = (H.rotate_elements_right::<1>() * left_mask_1) + F;
-            // make simd of gap open gap extend 
-            F = F - gap_extend_8 - gap_open_8;
-            H = H.simd_max(F);
-            F = H;
-            // Final update
-            HH[j] = H.clone();
+/// Which parts of the two sequences an alignment has to cover
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum PairwiseMode {
+    /// Both sequences end to end
+    #[default]
+    Global,
+    /// The best scoring pair of substrings (Smith-Waterman)
+    Local,
+    /// All of `seq_x` against a substring of `seq_y` (glocal), gaps before and after it in
+    /// `seq_y` are free; to find a primer or an adapter (`seq_x`) in a read (`seq_y`)
+    SemiGlobal,
+}
+
+/// Score and operations of a pairwise alignment: b'm' match, b's' substitution, b'i' a base
+/// of `seq_x` only, b'd' a base of `seq_y` only. The operations align
+/// `seq_x[x_start..x_end]` to `seq_y[y_start..y_end]`, the whole sequences in global mode.
+#[derive(Default, Clone, Debug, PartialEq, Eq)]
+pub struct PairwiseAlignment {
+    pub score: i32,
+    pub operations: Vec<u8>,
+    pub x_start: usize,
+    pub x_end: usize,
+    pub y_start: usize,
+    pub y_end: usize,
+}
+
+impl PairwiseAlignment {
+    /// Extended CIGAR of `seq_x` against `seq_y`: = match, X substitution, I a base of `seq_x`
+    /// only, D a base of `seq_y` only
+    pub fn cigar(&self) -> String {
+        let mut cigar = String::new();
+        let mut index = 0;
+        while index < self.operations.len() {
+            let op = self.operations[index];
+            let run = self.operations[index..].iter().take_while(|other| **other == op).count();
+            let letter = match op {
+                b'm' => '=',
+                b's' => 'X',
+                b'i' => 'I',
+                _ => 'D',
+            };
+            cigar.push_str(&format!("{}{}", run, letter));
+            index += run;
         }
-        println!("{:?}", HH);
+        cigar
     }
 }
 
-// try one without extend
-pub fn pairwise_without_extend (seq_x: &Vec<u8>, seq_y: &Vec<u8>, match_score: i32, mismatch_score: i32, gap_open_score: i32, gap_extend_score: i32) -> (Vec<u8>, isize) {
-    println!("Pairwise without extend");
+// traceback flags of one cell of the SIMD kernel
+const FROM_E: u8 = 1; // the best score of the cell ends a gap in seq_y (moves up)
+const FROM_F: u8 = 2; // the best score of the cell ends a gap in seq_x (moves left)
+const E_OPEN: u8 = 4; // the gap in seq_y of the cell opens here
+const F_OPEN: u8 = 8;
+const ZERO: u8 = 16; // a local alignment can start here, the score is 0
+
+/// Same as `pairwise_without_extend`, eight columns at a time; the linear gap is an affine
+/// one without an open score
+pub fn pairwise_simd_without_extend (seq_x: &Vec<u8>, seq_y: &Vec<u8>, match_score: i32, mismatch_score: i32, gap_open_score: i32, gap_extend_score: i32) -> Result<PairwiseAlignment> {
+    pairwise_simd(seq_x, seq_y, match_score, mismatch_score, 0, gap_open_score + gap_extend_score)
+}
+
+/// Global alignment with linear gaps, every gap base scores `gap_open_score + gap_extend_score`
+pub fn pairwise_without_extend (seq_x: &Vec<u8>, seq_y: &Vec<u8>, match_score: i32, mismatch_score: i32, gap_open_score: i32, gap_extend_score: i32) -> PairwiseAlignment {
+    let gap_score = gap_open_score as isize + gap_extend_score as isize;
     // variables to save results
     let mut align_vec: Vec<u8> = Vec::new();
-    // make one matrix 
+    // make one matrix
     let mut pair_wise_matrix: Vec<Vec<PairwiseMatrixCellNoExtend>> = vec![vec![PairwiseMatrixCellNoExtend {score: (0), back: ('X')}; seq_y.len() + 1]; seq_x.len() + 1];
+    // first row and column are gaps
+    for (j, cell) in pair_wise_matrix[0].iter_mut().enumerate().skip(1) {
+        *cell = PairwiseMatrixCellNoExtend { score: j as isize * gap_score, back: 'd' };
+    }
+    for (i, row) in pair_wise_matrix.iter_mut().enumerate().skip(1) {
+        row[0] = PairwiseMatrixCellNoExtend { score: i as isize * gap_score, back: 'i' };
+    }
     // filling out score matrices and back matrix
     for i in 1..seq_x.len() + 1 {
         for j in 1..seq_y.len() + 1 {
-            // fill del matrix 
+            // fill del matrix
             // get j - 1 score from match matrix with gap open penalty
-            let temp_del_score = pair_wise_matrix[i][j - 1].score + gap_open_score as isize + gap_extend_score as isize;
+            let temp_del_score = pair_wise_matrix[i][j - 1].score + gap_score;
             // get i - 1 score from the match matrix with gap open penalty
-            let temp_ins_score = pair_wise_matrix[i - 1][j].score + gap_open_score as isize + gap_extend_score as isize;
+            let temp_ins_score = pair_wise_matrix[i - 1][j].score + gap_score;
             // get the match from i-1,j-1 from match matrix with match score or mismatch score
             let temp_match_score;
             if seq_x[i - 1] == seq_y[j - 1] {
@@ -114,285 +139,234 @@ pub fn pairwise_without_extend (seq_x: &Vec<u8>, seq_y: &Vec<u8>, match_score: i
     let mut i = seq_x.len();
     let mut j = seq_y.len();
     let score = pair_wise_matrix[i][j].score;
-    let mut break_on_next = false;
-    // print the score matrix
-    println!("Score matrix");
-    for i_index in 0..seq_x.len() {
-        for j_index in 0..seq_y.len() {
-            print!(" {}", pair_wise_matrix[i_index][j_index].score);
-        }
-        println!("");
-    }
-    loop {
-        match pair_wise_matrix[i][j].back {
-            'i' => {
-                i = i - 1;
-                align_vec.push('i' as u8);
-            },
-            'm' => {
-                i = i - 1;
-                j = j - 1;
-                align_vec.push('m' as u8);
-            },
-            's' => {
-                i = i - 1;
-                j = j - 1;
-                align_vec.push('s' as u8);
+    while i > 0 || j > 0 {
+        let back = pair_wise_matrix[i][j].back;
+        match back {
+            'i' => i -= 1,
+            'd' => j -= 1,
+            _ => {
+                i -= 1;
+                j -= 1;
             }
-            'd' => {
-                j = j - 1;
-                align_vec.push('d' as u8);
-            },
-            _ => (),
-        }
-        if break_on_next {
-            break;
-        }
-        if i == 0 && j == 0 {
-            break_on_next = true;
         }
+        align_vec.push(back as u8);
     }
-    (align_vec.into_iter().rev().collect(), score)
+    PairwiseAlignment { score: score as i32, operations: align_vec.into_iter().rev().collect(), x_start: 0, x_end: seq_x.len(), y_start: 0, y_end: seq_y.len() }
 }
 
-pub fn profile_query (seq_y: &Vec<u8>, match_score: i32, mismatch_score: i32) -> Vec<Vec<i16x8>> {
-    let num_seq_vec = seq_y.len() / 8;
-    let mut MM_simd = vec![];
-    // make 4 vectors for query
-    let mut A_simd: Vec<i16x8> = vec![];
-    let mut C_simd: Vec<i16x8> = vec![];
-    let mut G_simd: Vec<i16x8> = vec![];
-    let mut T_simd: Vec<i16x8> = vec![];
-    // go through the query and populate the entries
-    for index_simd in 0..num_seq_vec {
-        let mut temp_A = vec![];
-        let mut temp_C = vec![];
-        let mut temp_G = vec![];
-        let mut temp_T = vec![];
-        let simd_seq = &seq_y[(index_simd * 8)..((index_simd + 1) * 8)];
-        for base in simd_seq {
-            if *base == 65 {
-                temp_A.push(match_score as i16);
-            }
-            else {
-                temp_A.push(mismatch_score as i16);
-            }
-            if *base == 67 {
-                temp_C.push(match_score as i16);
-            }
-            else {
-                temp_C.push(mismatch_score as i16);
-            }
-            if *base == 71 {
-                temp_G.push(match_score as i16);
-            }
-            else {
-                temp_G.push(mismatch_score as i16);
-            }
-            if *base == 84 {
-                temp_T.push(match_score as i16);
-            }
-            else {
-                temp_T.push(mismatch_score as i16);
-            }
-        }
-        A_simd.push(i16x8::from_array(temp_A[0..8].try_into().expect("")));
-        C_simd.push(i16x8::from_array(temp_C[0..8].try_into().expect("")));
-        G_simd.push(i16x8::from_array(temp_G[0..8].try_into().expect("")));
-        T_simd.push(i16x8::from_array(temp_T[0..8].try_into().expect("")));
-        //println!("{:?}", simd_seq);
-    }
-    MM_simd.push(A_simd);
-    MM_simd.push(C_simd);
-    MM_simd.push(G_simd);
-    MM_simd.push(T_simd);
-    MM_simd
+/// Query profile of `seq_y` for the SIMD kernels, padded to whole vectors, see `Poa::profile_query`
+pub fn profile_query (seq_y: &Vec<u8>, match_score: i32, mismatch_score: i32) -> Vec<Vec<i32x8>> {
+    Poa::profile_query(seq_y, match_score, mismatch_score)
 }
 
-pub fn pairwise_simd (seq_x: &Vec<u8>, seq_y: &Vec<u8>, match_score: i32, mismatch_score: i32, gap_open_score: i32, gap_extend_score: i32) {
-    println!("real");
-    // initialize hash map for profiling 
-    let mut hash_table = HashMap::new();
-    hash_table.insert(65, 0);
-    hash_table.insert(67, 1);
-    hash_table.insert(71, 2);
-    hash_table.insert(84, 3);
-    // make the MM table 
-    let MM_simd_full = profile_query(seq_y, match_score, mismatch_score);
+// v moved up one lane, `first` goes into lane 0
+fn shift_in(v: i32x8, first: i32) -> i32x8 {
+    mask32x8::from_array([true, false, false, false, false, false, false, false]).select(i32x8::splat(first), v.rotate_elements_right::<1>())
+}
 
-    // filling out score matrices and back matrix
-    // the number of vectors for query SIMD 8 for now
-    let num_seq_vec = seq_y.len() / 8;
-    // make vectors of 8 of size num_seq_vec
-    let gap_open_score = gap_open_score as i16;
-    let gap_extend_score = gap_extend_score as i16;
-    let gap_open_8 = i16x8::from_array([gap_open_score, gap_open_score, gap_open_score, gap_open_score, gap_open_score, gap_open_score, gap_open_score, gap_open_score]);
-    let gap_extend_8 = i16x8::from_array([gap_extend_score, gap_extend_score, gap_extend_score, gap_extend_score, gap_extend_score, gap_extend_score, gap_extend_score, gap_extend_score]);
-    let left_mask_1 = i16x8::from_array([0, 1, 1, 1, 1, 1, 1, 1]);
-    let right_mask_7 = i16x8::from_array([1, 0, 0, 0, 0, 0, 0, 0]);
-    let mut HH: Vec<i16x8> = vec![i16x8::from_array([0, 0, 0, 0, 0, 0, 0, 0]); num_seq_vec];
-    let mut EE: Vec<i16x8> = vec![i16x8::from_array([0, 0, 0, 0, 0, 0, 0, 0]); num_seq_vec];
-    for i in 0..seq_x.len() {
-        let mut X = i16x8::from_array([0, 0, 0, 0, 0, 0, 0, 0]);
-        let mut F = i16x8::from_array([0, 0, 0, 0, 0, 0, 0, 0]);
-        // get the database base
-        let data_base_index = hash_table.get(&seq_x[i]).unwrap();
-        for j in 0..num_seq_vec {
-            let mut H = HH[j].clone();
-            let mut E = EE[j].clone();
-            //println!("H {:?}", H);
-            let T1 = H.rotate_elements_left::<7>() * right_mask_7;
-            //println!("T1 {:?}", T1);
-            H = (H.rotate_elements_right::<1>() * left_mask_1) + X;
-            //println!("H after rotate {:?}", H);
-            X = T1;
-            // convert MM to simd
-            let MM_simd = MM_simd_full[*data_base_index][j];
-            //let MM_simd = i16x8::from_array(MM[0..8].try_into().expect(""));
-            //println!("MM {:?}", MM_simd);
-            H = H + MM_simd;
-            H = H.simd_max(E);
+/// Global alignment with affine gaps, eight columns at a time, see `pairwise_simd_with_mode`
+pub fn pairwise_simd (seq_x: &Vec<u8>, seq_y: &Vec<u8>, match_score: i32, mismatch_score: i32, gap_open_score: i32, gap_extend_score: i32) -> Result<PairwiseAlignment> {
+    pairwise_simd_with_mode(seq_x, seq_y, match_score, mismatch_score, gap_open_score, gap_extend_score, PairwiseMode::Global)
+}
 
-            F = F.rotate_elements_left::<7>() * right_mask_7;
-            F = (H.rotate_elements_right::<1>() * left_mask_1) + F;
-            // make simd of gap open gap extend 
-            F = F - gap_extend_8 - gap_open_8;
-            let mut T2 = F.clone();
-            let all_non_positive = T2.simd_le(i16x8::splat(0)).all();
-            if all_non_positive {
-                F = H;
-            }
-            else {
-                for _iter in 0..8 {
-                    // lshift 2 t2, for gap extends
-                    T2 = T2.rotate_elements_right::<1>() * left_mask_1;
-                    F = F.simd_max(T2);
+/// Alignment with affine gaps in the given mode, eight columns (bases of `seq_y`) at a time.
+///
+/// Each row keeps the best score (H) and the best score ending in a gap in `seq_y` (E) per
+/// column; the gaps in `seq_x` (F) run along the row and are found with a prefix maximum
+/// inside each vector, carried from vector to vector. One byte of traceback flags is kept
+/// per cell. Bases must be A, C, G or T.
+pub fn pairwise_simd_with_mode (seq_x: &Vec<u8>, seq_y: &Vec<u8>, match_score: i32, mismatch_score: i32, gap_open_score: i32, gap_extend_score: i32, mode: PairwiseMode) -> Result<PairwiseAlignment> {
+    for seq in [seq_x, seq_y] {
+        if !seq.is_empty() {
+            check_sequence(seq)?;
+        }
+    }
+    let MM_simd_full = profile_query(seq_y, match_score, mismatch_score);
+    let (m, n) = (seq_x.len(), seq_y.len());
+    let num_seq_vec = MM_simd_full[0].len();
+    let width = num_seq_vec * 8;
+    let min_score = MIN_SCORE as i32;
+    let open_extend = gap_open_score + gap_extend_score;
+    let gap_open_extend_8 = i32x8::splat(open_extend);
+    let gap_extend_8 = i32x8::splat(gap_extend_score);
+    // column j + 1 of vector lane j, row 0 is free in the local and semi-global modes
+    let mut HH: Vec<i32x8> = (0..num_seq_vec).map(|simd_index| i32x8::from_array(std::array::from_fn(|lane| first_row(simd_index * 8 + lane + 1, gap_open_score, gap_extend_score, mode)))).collect();
+    let mut EE: Vec<i32x8> = vec![i32x8::splat(min_score); num_seq_vec];
+    let mut flags: Vec<u8> = vec![0; m * width];
+    let local = mode == PairwiseMode::Local;
+    let mut best = (0, 0, 0); // local mode: score, row and column of the first best cell
+    for i in 0..m {
+        let data_base_index = BASE_TO_INDEX[seq_x[i] as usize] as usize;
+        // H of the previous row and this row in column 0
+        let mut X = first_column(i, gap_open_score, gap_extend_score, mode);
+        let row_start = first_column(i + 1, gap_open_score, gap_extend_score, mode);
+        // the gap in seq_x into the first column of the next vector: opened from the last H or extended
+        let (mut open_carry, mut extend_carry) = (row_start + open_extend, min_score);
+        for simd_index in 0..num_seq_vec {
+            let H_prev = HH[simd_index];
+            let diagonal = shift_in(H_prev, X) + MM_simd_full[data_base_index][simd_index];
+            X = H_prev[7];
+            let E_open = H_prev + gap_open_extend_8;
+            let E = (EE[simd_index] + gap_extend_8).simd_max(E_open);
+            EE[simd_index] = E;
+            let mut H_no_f = diagonal.simd_max(E);
+            if local {
+                H_no_f = H_no_f.simd_max(i32x8::splat(0));
+            }
+            // F[l] is the best of opening from H[l - 1] and extending F[l - 1]
+            let F_open = shift_in(H_no_f + gap_open_extend_8, open_carry);
+            let mut F = F_open.simd_max(shift_in(i32x8::splat(min_score), extend_carry + gap_extend_score));
+            F = F.simd_max(shift_fill::<1>(F, min_score) + gap_extend_8);
+            F = F.simd_max(shift_fill::<2>(F, min_score) + gap_extend_8 * i32x8::splat(2));
+            F = F.simd_max(shift_fill::<4>(F, min_score) + gap_extend_8 * i32x8::splat(4));
+            let H = H_no_f.simd_max(F);
+            open_carry = H_no_f[7] + open_extend;
+            extend_carry = F[7];
+            HH[simd_index] = H;
+            // traceback flags, diagonal first, then the gap in seq_x, then the gap in seq_y
+            let zero = i32x8::splat(0);
+            let source = H.simd_eq(diagonal).select(zero, H.simd_eq(F).select(i32x8::splat(FROM_F as i32), i32x8::splat(FROM_E as i32)));
+            let mut cell_flags = source + E.simd_eq(E_open).select(i32x8::splat(E_OPEN as i32), zero) + F.simd_eq(F_open).select(i32x8::splat(F_OPEN as i32), zero);
+            if local {
+                cell_flags += H.simd_eq(zero).select(i32x8::splat(ZERO as i32), zero);
+                // first best cell in row order, padding lanes are not columns
+                if H.reduce_max() > best.0 {
+                    for (lane, score) in H.to_array().iter().enumerate().take(n - simd_index * 8) {
+                        if *score > best.0 {
+                            best = (*score, i + 1, simd_index * 8 + lane + 1);
+                        }
+                    }
                 }
-                // update H based on max F
-                H = H.simd_max(F);
-                F = F + gap_open_8;
-                F = F.simd_max(H);
             }
-            
-            // Final update
-            HH[j] = H.clone();
-            H = H - gap_open_8;
-            E = E.simd_max(H);
-            E = E - gap_extend_8;
-            //println!("E at end {:?}", E);
-            EE[j] = E;
-            //println!("");
+            for (lane, value) in cell_flags.to_array().iter().enumerate() {
+                flags[i * width + simd_index * 8 + lane] = *value as u8;
+            }
         }
-        println!("{:?}", HH);
     }
+    // H of the last row by column
+    let last_row = |j: usize| if j == 0 { first_column(m, gap_open_score, gap_extend_score, mode) } else { HH[(j - 1) / 8][(j - 1) % 8] };
+    let (score, x_end, y_end) = match mode {
+        PairwiseMode::Global => (last_row(n), m, n),
+        PairwiseMode::Local => best,
+        PairwiseMode::SemiGlobal => {
+            let y_end = (0..n + 1).fold(0, |best_j, j| if last_row(j) > last_row(best_j) { j } else { best_j });
+            (last_row(y_end), m, y_end)
+        }
+    };
+    let (operations, x_start, y_start) = simd_traceback(seq_x, seq_y, &flags, width, (x_end, y_end), mode);
+    Ok(PairwiseAlignment { score, operations, x_start, x_end, y_start, y_end })
 }
 
-pub fn fake_pairwise_simd (seq_x: &Vec<u8>, seq_y: &Vec<u8>, match_score: i32, mismatch_score: i32, gap_open_score: i32, gap_extend_score: i32) {
-    println!("fake");
-    // filling out score matrices and back matrix
-    // the number of vectors for query SIMD 8 for now
-    let num_seq_vec = seq_y.len() / 8;
-    // make vectors of 8 of size num_seq_vec
-    let mut HH = vec![vec![0; 8]; num_seq_vec];
-    let mut EE = vec![vec![0; 8]; num_seq_vec];
-    for i in 0..seq_x.len() {
-        let mut X = 0;
-        let mut F = vec![0; 8];
-        for j in 0..num_seq_vec {
-            let mut H = HH[j].clone();
-            let mut E = EE[j].clone();
-            //println!("H {:?}", H);
-            let T1 = H[7];
-            //println!("T1 {:?}", T1);
-            H = vec![X, H[0], H[1], H[2], H[3], H[4], H[5], H[6]];
-            //println!("H after rotate {:?}", H);
-            X = T1;
-            let mut MM = vec![0; 8];
-            // make a match vector of 8, just for testing, for the real thing profiling is required
-            let base_to_match = seq_x[i];
-            for iter in 0..8 {
-                if base_to_match == seq_y[(j * 8) + iter] {
-                    MM[iter] = match_score;
-                }
-                else {
-                    MM[iter] = mismatch_score;
-                }
-                // add match mismatch score to H
-                H[iter] = H[iter] + MM[iter];
-                // make H max of E or H
-                if E[iter] > H[iter] {
-                    H[iter] = E[iter];
-                }
-                // make F
-            }
-            //println!("MM {:?}", MM);
-            F = vec![F[7], H[0], H[1], H[2], H[3], H[4], H[5], H[6]];
-            for iter in 0..8 {
-                F[iter] = F[iter] - gap_open_score - gap_extend_score;
+// H of the first row at column j > 0 (a gap in seq_x)
+fn first_row(j: usize, gap_open_score: i32, gap_extend_score: i32, mode: PairwiseMode) -> i32 {
+    match mode {
+        PairwiseMode::Global => gap_open_score + j as i32 * gap_extend_score,
+        _ => 0,
+    }
+}
+
+// H of the first column at row i (a gap in seq_y)
+fn first_column(i: usize, gap_open_score: i32, gap_extend_score: i32, mode: PairwiseMode) -> i32 {
+    match mode {
+        PairwiseMode::Local => 0,
+        _ if i == 0 => 0,
+        _ => gap_open_score + i as i32 * gap_extend_score,
+    }
+}
+
+// v moved up S lanes, the lanes below S get `fill`
+fn shift_fill<const S: usize>(v: i32x8, fill: i32) -> i32x8 {
+    let below = mask32x8::from_array(std::array::from_fn(|lane| lane < S));
+    below.select(i32x8::splat(fill), v.rotate_elements_right::<S>())
+}
+
+// follow the flags of pairwise_simd from the end cell, in the state the cell was reached in;
+// returns the operations and the start row and column
+fn simd_traceback(seq_x: &Vec<u8>, seq_y: &Vec<u8>, flags: &Vec<u8>, width: usize, end: (usize, usize), mode: PairwiseMode) -> (Vec<u8>, usize, usize) {
+    let (mut i, mut j) = end;
+    let mut state = 0; // 0 best score, FROM_E or FROM_F inside a gap
+    let mut align_vec = vec![];
+    while i > 0 && j > 0 {
+        let cell = flags[(i - 1) * width + j - 1];
+        if state == 0 {
+            if mode == PairwiseMode::Local && cell & ZERO != 0 {
+                break;
             }
-            let mut T2 = F.clone();
-            for _iter in 0..8 {
-                // lshift 2 t2, for gap extends
-                T2 = vec![0, T2[0], T2[1], T2[2], T2[3], T2[4], T2[5], T2[6]];
-                for iter2 in 0..8 {
-                    // update if better
-                    if T2[iter2] > F[iter2] {
-                        F[iter2] = T2[iter2];
-                    }
-                }
+            state = cell & (FROM_E | FROM_F);
+            if state == 0 {
+                align_vec.push(if seq_x[i - 1] == seq_y[j - 1] { b'm' } else { b's' });
+                i -= 1;
+                j -= 1;
             }
-            // update H based on max F
-            for iter in 0..8 {
-                if F[iter] > H[iter] {
-                    H[iter] = F[iter];
-                }
-                F[iter] = F[iter] + gap_open_score;
-                if H[iter] > F[iter] {
-                    F[iter] = H[iter];
-                }
+        }
+        else if state == FROM_E {
+            align_vec.push(b'i');
+            i -= 1;
+            if cell & E_OPEN != 0 {
+                state = 0;
             }
-            // Final update
-            HH[j] = H.clone();
-            for iter in 0..8 {
-                if (H[iter] - gap_open_score) > E[iter] {
-                    E[iter] = H[iter] - gap_open_score;
-                }   
-                E[iter] = E[iter] - gap_extend_score;
+        }
+        else {
+            align_vec.push(b'd');
+            j -= 1;
+            if cell & F_OPEN != 0 {
+                state = 0;
             }
-            //println!("E at end {:?}", E);
-            EE[j] = E;
-            //println!("");
         }
-        println!("{:?}", HH);
     }
+    let (x_start, y_start) = border_gaps(&mut align_vec, i, j, mode);
+    (align_vec.into_iter().rev().collect(), x_start, y_start)
+}
+
+// finish a traceback that stopped at row i and column j, in reverse order: the first row
+// and column are gaps where they are not free. Returns the start row and column.
+fn border_gaps(align_vec: &mut Vec<u8>, i: usize, j: usize, mode: PairwiseMode) -> (usize, usize) {
+    match mode {
+        PairwiseMode::Global => {
+            align_vec.extend(std::iter::repeat_n(b'i', i));
+            align_vec.extend(std::iter::repeat_n(b'd', j));
+            (0, 0)
+        }
+        PairwiseMode::SemiGlobal if j == 0 => {
+            align_vec.extend(std::iter::repeat_n(b'i', i));
+            (0, 0)
+        }
+        _ => (i, j),
+    }
+}
 
+/// Global alignment with affine gaps, see `pairwise_with_mode`
+pub fn pairwise (seq_x: &Vec<u8>, seq_y: &Vec<u8>, match_score: i32, mismatch_score: i32, gap_open_score: i32, gap_extend_score: i32, band_size: usize) -> PairwiseAlignment {
+    pairwise_with_mode(seq_x, seq_y, match_score, mismatch_score, gap_open_score, gap_extend_score, band_size, PairwiseMode::Global)
 }
 
-pub fn pairwise (seq_x: &Vec<u8>, seq_y: &Vec<u8>, match_score: i32, mismatch_score: i32, gap_open_score: i32, gap_extend_score: i32, band_size: usize) -> (Vec<u8>, isize) {
+/// Alignment with affine gaps in the given mode. With `band_size` > 0 only the columns
+/// within `band_size` of the best column of the previous row are filled (all of them in
+/// the first and last 20 rows).
+pub fn pairwise_with_mode (seq_x: &Vec<u8>, seq_y: &Vec<u8>, match_score: i32, mismatch_score: i32, gap_open_score: i32, gap_extend_score: i32, band_size: usize, mode: PairwiseMode) -> PairwiseAlignment {
     // variables to save results
     let mut align_vec: Vec<u8> = Vec::new();
 
-    // make one matrix 
-    let mut pair_wise_matrix: Vec<Vec<PairwiseMatrixCell>> = vec![vec![PairwiseMatrixCell { match_score: (0), del_score: (0), ins_score: (0), back: ('X') }; seq_y.len() + 1]; seq_x.len() + 1];
+    // make one matrix, cells outside the band stay at negative infinity
+    let mut pair_wise_matrix: Vec<Vec<PairwiseMatrixCell>> = vec![vec![PairwiseMatrixCell { match_score: (MIN_SCORE), del_score: (MIN_SCORE), ins_score: (MIN_SCORE), back: ('X') }; seq_y.len() + 1]; seq_x.len() + 1];
 
     // fill out the first row and colomn
-    let temp_value = gap_open_score as isize + gap_extend_score as isize;
-    //pair_wise_matrix[0][1] = PairwiseMatrixCell { match_score: (temp_value), del_score: (temp_value), ins_score: (temp_value), back: ('d') };
-    //pair_wise_matrix[1][0] = PairwiseMatrixCell { match_score: (temp_value), del_score: (temp_value), ins_score: (temp_value), back: ('i') };
-    for i in 2..seq_y.len() + 1 {
-        //let temp_value = pair_wise_matrix[0][i - 1].del_score + gap_extend_score as isize;
-        //pair_wise_matrix[0][i] = PairwiseMatrixCell { match_score: (temp_value), del_score: (temp_value), ins_score: (temp_value), back: ('d') };
+    pair_wise_matrix[0][0].match_score = 0;
+    for (j, cell) in pair_wise_matrix[0].iter_mut().enumerate().skip(1) {
+        let temp_value = first_row(j, gap_open_score, gap_extend_score, mode) as isize;
+        *cell = PairwiseMatrixCell { match_score: (temp_value), del_score: (temp_value), ins_score: (MIN_SCORE), back: ('d') };
     }
-    for i in 2..seq_x.len() + 1 {
-        //let temp_value = pair_wise_matrix[i - 1][0].ins_score + gap_extend_score as isize;
-        //pair_wise_matrix[i][0] = PairwiseMatrixCell { match_score: (temp_value), del_score: (temp_value), ins_score: (temp_value), back: ('i') };
+    for (i, row) in pair_wise_matrix.iter_mut().enumerate().skip(1) {
+        let temp_value = first_column(i, gap_open_score, gap_extend_score, mode) as isize;
+        row[0] = PairwiseMatrixCell { match_score: (temp_value), del_score: (MIN_SCORE), ins_score: (temp_value), back: ('i') };
     }
     // calculations
     // filling out score matrices and back matrix
     let mut max_scored_position = 0;
     let mut max_score;
     let mut start = 0;
-    let mut end= seq_x.len();
+    let mut end = seq_y.len();
+    let mut best = (0, 0, 0); // local mode: score, row and column of the first best cell
     for i in 1..seq_x.len() + 1 {
         max_score = MIN_SCORE;
         if band_size > 0 {
@@ -408,11 +382,11 @@ pub fn pairwise (seq_x: &Vec<u8>, seq_y: &Vec<u8>, match_score: i32, mismatch_sc
                 start = 0;
             }
             // end at end at end :D
-            if i > seq_x.len() - 20 {
-                end = seq_x.len();
+            if i + 20 > seq_x.len() {
+                end = seq_y.len();
             }
         }
-        
+
         for j in 1..seq_y.len() + 1 {
             if j < start && (band_size > 0) && i > 1 {
                 continue;
@@ -420,7 +394,7 @@ pub fn pairwise (seq_x: &Vec<u8>, seq_y: &Vec<u8>, match_score: i32, mismatch_sc
             if j > end && (band_size > 0) && i > 1{
                 break;
             }
-            // fill del matrix 
+            // fill del matrix
             // get j - 1 score from same matrix with gap extend
             let temp_del_score = pair_wise_matrix[i][j - 1].del_score + gap_extend_score as isize;
             // get j - 1 score from match matrix with gap open penalty
@@ -453,6 +427,12 @@ pub fn pairwise (seq_x: &Vec<u8>, seq_y: &Vec<u8>, match_score: i32, mismatch_sc
             }
             // insert the max
             pair_wise_matrix[i][j].match_score = cmp::max(temp_match_score, cmp::max(temp_ins_score, temp_del_score));
+            if mode == PairwiseMode::Local {
+                pair_wise_matrix[i][j].match_score = cmp::max(pair_wise_matrix[i][j].match_score, 0);
+                if pair_wise_matrix[i][j].match_score > best.0 {
+                    best = (pair_wise_matrix[i][j].match_score, i, j);
+                }
+            }
             if max_score < pair_wise_matrix[i][j].match_score {
                 max_score = pair_wise_matrix[i][j].match_score;
                 max_scored_position = j;
@@ -468,63 +448,139 @@ pub fn pairwise (seq_x: &Vec<u8>, seq_y: &Vec<u8>, match_score: i32, mismatch_sc
             }
         }
     }
-    // back tracing using back matrix and filling out align_vec
-    let mut i = seq_x.len();
-    let mut j = seq_y.len();
-    let score = pair_wise_matrix[i][j].match_score;
-    let mut break_on_next = false;
-    // print the score matrix
-    println!("Score matrix");
-    for i_index in 0..seq_x.len() {
-        for j_index in 0..seq_y.len() {
-            print!(" {}", pair_wise_matrix[i_index][j_index].match_score);
+    // back tracing using back matrix and filling out align_vec, a gap is followed in its own
+    // matrix until the cell it was opened from
+    let (score, x_end, y_end) = match mode {
+        PairwiseMode::Global => (pair_wise_matrix[seq_x.len()][seq_y.len()].match_score, seq_x.len(), seq_y.len()),
+        PairwiseMode::Local => best,
+        PairwiseMode::SemiGlobal => {
+            let last_row = &pair_wise_matrix[seq_x.len()];
+            let y_end = (0..seq_y.len() + 1).fold(0, |best_j, j| if last_row[j].match_score > last_row[best_j].match_score { j } else { best_j });
+            (last_row[y_end].match_score, seq_x.len(), y_end)
         }
-        println!("");
-    }
-    // print the del matrix
-    println!("Del matrix");
-    for i_index in 0..seq_x.len() {
-        for j_index in 0..seq_y.len() {
-            print!(" {}", pair_wise_matrix[i_index][j_index].del_score);
-        }
-        println!("");
-    }
-    // print the ins matrix
-    println!("Ins matrix");
-    for i_index in 0..seq_x.len() {
-        for j_index in 0..seq_y.len() {
-            print!(" {}", pair_wise_matrix[i_index][j_index].ins_score);
+    };
+    let (mut i, mut j) = (x_end, y_end);
+    let mut state = 'm';
+    while i > 0 && j > 0 {
+        let cell = &pair_wise_matrix[i][j];
+        if state == 'm' && mode == PairwiseMode::Local && cell.match_score == 0 {
+            break;
         }
-        println!("");
-    }
-    loop {
-        match pair_wise_matrix[i][j].back {
+        match state {
             'i' => {
-                i = i - 1;
-                align_vec.push('i' as u8);
-            },
-            'm' => {
-                i = i - 1;
-                j = j - 1;
-                align_vec.push('m' as u8);
+                let opened = cell.ins_score == pair_wise_matrix[i - 1][j].match_score + gap_open_score as isize + gap_extend_score as isize;
+                i -= 1;
+                align_vec.push(b'i');
+                if opened {
+                    state = 'm';
+                }
             },
-            's' => {
-                i = i - 1;
-                j = j - 1;
-                align_vec.push('s' as u8);
-            }
             'd' => {
-                j = j - 1;
-                align_vec.push('d' as u8);
+                let opened = cell.del_score == pair_wise_matrix[i][j - 1].match_score + gap_open_score as isize + gap_extend_score as isize;
+                j -= 1;
+                align_vec.push(b'd');
+                if opened {
+                    state = 'm';
+                }
+            },
+            _ => match cell.back {
+                'i' | 'd' => state = cell.back,
+                back => {
+                    i -= 1;
+                    j -= 1;
+                    align_vec.push(back as u8);
+                }
             },
-            _ => (),
         }
-        if break_on_next {
-            break;
+    }
+    let (x_start, y_start) = border_gaps(&mut align_vec, i, j, mode);
+    PairwiseAlignment { score: score as i32, operations: align_vec.into_iter().rev().collect(), x_start, x_end, y_start, y_end }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulate::{simulate, ErrorProfile, SimulationConfig};
+    use rand::{Rng, SeedableRng, rngs::StdRng};
+
+    // read pairs of random length simulated from the same truth
+    fn read_pairs(count: usize) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mut rng = StdRng::seed_from_u64(0);
+        (0..count).map(|_| {
+            let config = SimulationConfig { length: rng.gen_range(1..300), reads: 2, profile: ErrorProfile::ont(), reverse_fraction: 0.0, chimera_fraction: 0.0, seed: rng.gen_range(0..10_000) };
+            let mut reads = simulate(&config).reads;
+            let seq_y = reads.pop().unwrap().seq;
+            (reads.pop().unwrap().seq, seq_y)
+        }).collect()
+    }
+
+    // score of the operations from the bases they align, a gap of n bases costs open + n * extend
+    fn rescore(alignment: &PairwiseAlignment, seq_x: &[u8], seq_y: &[u8], match_score: i32, mismatch_score: i32, gap_open_score: i32, gap_extend_score: i32) -> i32 {
+        let (mut x, mut y, mut score) = (alignment.x_start, alignment.y_start, 0);
+        for (index, op) in alignment.operations.iter().enumerate() {
+            let opens = index == 0 || alignment.operations[index - 1] != *op;
+            match op {
+                b'm' | b's' => {
+                    assert_eq!(seq_x[x] == seq_y[y], *op == b'm');
+                    score += if *op == b'm' { match_score } else { mismatch_score };
+                    x += 1;
+                    y += 1;
+                }
+                _ => {
+                    score += gap_extend_score + if opens { gap_open_score } else { 0 };
+                    if *op == b'i' { x += 1 } else { y += 1 }
+                }
+            }
+        }
+        assert_eq!((x, y), (alignment.x_end, alignment.y_end));
+        score
+    }
+
+    #[test]
+    fn simd_global_matches_scalar() {
+        for (seq_x, seq_y) in read_pairs(60) {
+            let scalar = pairwise(&seq_x, &seq_y, 1, -1, -3, -1, 0);
+            let simd = pairwise_simd(&seq_x, &seq_y, 1, -1, -3, -1).unwrap();
+            assert_eq!((simd.score, simd.cigar()), (scalar.score, scalar.cigar()));
+            assert_eq!(simd, scalar);
+            assert_eq!((simd.x_start, simd.x_end, simd.y_start, simd.y_end), (0, seq_x.len(), 0, seq_y.len()));
+            assert_eq!(rescore(&simd, &seq_x, &seq_y, 1, -1, -3, -1), simd.score);
+            let linear = pairwise_without_extend(&seq_x, &seq_y, 1, -1, -1, -1);
+            let linear_simd = pairwise_simd_without_extend(&seq_x, &seq_y, 1, -1, -1, -1).unwrap();
+            assert_eq!((linear_simd.score, linear_simd.cigar()), (linear.score, linear.cigar()));
+        }
+    }
+
+    #[test]
+    fn simd_local_and_semi_global_match_scalar() {
+        for (seq_x, seq_y) in read_pairs(60) {
+            for mode in [PairwiseMode::Local, PairwiseMode::SemiGlobal] {
+                let scalar = pairwise_with_mode(&seq_x, &seq_y, 1, -1, -3, -1, 0, mode);
+                let simd = pairwise_simd_with_mode(&seq_x, &seq_y, 1, -1, -3, -1, mode).unwrap();
+                assert_eq!((simd.score, simd.cigar()), (scalar.score, scalar.cigar()), "{:?}", mode);
+                assert_eq!(simd, scalar);
+                assert_eq!(rescore(&simd, &seq_x, &seq_y, 1, -1, -3, -1), simd.score);
+                if mode == PairwiseMode::SemiGlobal {
+                    assert_eq!((simd.x_start, simd.x_end), (0, seq_x.len()));
+                }
+            }
         }
-        if i == 0 && j == 0 {
-            break_on_next = true;
+    }
+
+    #[test]
+    fn finds_an_embedded_primer() {
+        let config = SimulationConfig { length: 500, reads: 1, profile: ErrorProfile::ont(), reverse_fraction: 0.0, chimera_fraction: 0.0, seed: 1 };
+        let mut read = simulate(&config).reads[0].seq.clone();
+        let primer = simulate(&SimulationConfig { length: 25, seed: 2, ..config }).truth;
+        // one substitution in the middle of the inserted copy
+        let mut inserted = primer.clone();
+        inserted[12] = if primer[12] == b'A' { b'C' } else { b'A' };
+        let at = read.len() / 3;
+        read.splice(at..at, inserted);
+        for mode in [PairwiseMode::SemiGlobal, PairwiseMode::Local] {
+            let found = pairwise_simd_with_mode(&primer, &read, 1, -1, -3, -1, mode).unwrap();
+            assert_eq!((found.x_start, found.x_end, found.y_start, found.y_end), (0, primer.len(), at, at + primer.len()), "{:?}", mode);
+            assert_eq!((found.score, found.cigar()), (23, "12=1X12=".to_string()));
         }
     }
-    (align_vec.into_iter().rev().collect(), score)
-}
\ No newline at end of file
+}
@@ -0,0 +1,402 @@
+//! Co-linear chaining over a minimum path cover of the graph
+//!
+//! `lcskpp_graph` keeps a Fenwick tree for every enumerated read path, so its cost grows with
+//! the number of reads and it needs the paths. Here the DAG is covered by as few paths as
+//! possible (minimum flow where every node carries at least one unit), which is bounded by the
+//! width of the graph instead. Following Mäkinen et al. (2019, "Sparse dynamic programming
+//! on DAGs with small width"), every node knows the last node of each cover path that reaches
+//! it. Nodes are visited in topological order; an anchor ending at a node is added to the trees
+//! of the cover paths through it, and its score is forwarded right away to the anchors starting
+//! at the nodes for which this node is the last one on that cover path to reach them.
+//!
+//! The objective is not the one of per path LCSk++: anchors chain whenever the end of one
+//! reaches the start of the other in the graph, so a chain can switch between read paths at
+//! any shared node. Its score is at least the per path score and usually higher; on a graph
+//! of a single read both give the same chain.
+use crate::bit_tree::MaxBitTree;
+use crate::compact_graph::CompactPoaGraph;
+use crate::error::{PoaError, Result};
+use std::cmp::max;
+use std::collections::VecDeque;
+
+// arcs of the flow network, node v is split into 2v -> 2v + 1
+struct FlowNetwork {
+    from: Vec<usize>,
+    to: Vec<usize>,
+    lower: Vec<u32>,
+    flow: Vec<u32>,
+    outgoing: Vec<Vec<usize>>,
+    incoming: Vec<Vec<usize>>,
+}
+
+impl FlowNetwork {
+    fn new(vertex_count: usize) -> Self {
+        FlowNetwork { from: vec![], to: vec![], lower: vec![], flow: vec![], outgoing: vec![vec![]; vertex_count], incoming: vec![vec![]; vertex_count] }
+    }
+
+    fn add_arc(&mut self, from: usize, to: usize, lower: u32) -> usize {
+        let arc = self.from.len();
+        self.from.push(from);
+        self.to.push(to);
+        self.lower.push(lower);
+        self.flow.push(0);
+        self.outgoing[from].push(arc);
+        self.incoming[to].push(arc);
+        arc
+    }
+
+    // push one unit back from sink to source through the residual network, false if there is no such path
+    fn reduce_once(&mut self, source: usize, sink: usize) -> bool {
+        // arc used to reach every vertex and whether it was taken backwards (decreasing its flow)
+        let mut reached_by: Vec<Option<(usize, bool)>> = vec![None; self.outgoing.len()];
+        let mut visited = vec![false; self.outgoing.len()];
+        let mut queue = VecDeque::from([sink]);
+        visited[sink] = true;
+        while let Some(vertex) = queue.pop_front() {
+            if vertex == source {
+                break;
+            }
+            for arc in &self.incoming[vertex] {
+                let next = self.from[*arc];
+                if !visited[next] && self.flow[*arc] > self.lower[*arc] {
+                    visited[next] = true;
+                    reached_by[next] = Some((*arc, true));
+                    queue.push_back(next);
+                }
+            }
+            for arc in &self.outgoing[vertex] {
+                let next = self.to[*arc];
+                if !visited[next] {
+                    visited[next] = true;
+                    reached_by[next] = Some((*arc, false));
+                    queue.push_back(next);
+                }
+            }
+        }
+        if !visited[source] {
+            return false;
+        }
+        let mut vertex = source;
+        while vertex != sink {
+            let (arc, backwards) = reached_by[vertex].unwrap();
+            if backwards {
+                self.flow[arc] -= 1;
+                vertex = self.to[arc];
+            } else {
+                self.flow[arc] += 1;
+                vertex = self.from[arc];
+            }
+        }
+        true
+    }
+}
+
+/// Minimum set of paths (as ranks) covering every node of the graph, paths may share nodes.
+/// A greedy cover is built first and then reduced by minimum flow.
+pub fn minimum_path_cover(compact: &CompactPoaGraph) -> Vec<Vec<usize>> {
+    let n = compact.node_count();
+    if n == 0 {
+        return vec![];
+    }
+    let (source, sink) = (2 * n, 2 * n + 1);
+    let mut network = FlowNetwork::new(2 * n + 2);
+    let mut start_arcs = vec![];
+    let mut node_arcs = vec![];
+    let mut end_arcs = vec![];
+    for rank in 0..n {
+        start_arcs.push(network.add_arc(source, 2 * rank, 0));
+        node_arcs.push(network.add_arc(2 * rank, 2 * rank + 1, 1));
+        end_arcs.push(network.add_arc(2 * rank + 1, sink, 0));
+    }
+    // edge arcs by (from rank, index of the successor)
+    let mut edge_arcs: Vec<Vec<usize>> = vec![];
+    for rank in 0..n {
+        edge_arcs.push(compact.successors(rank).iter().map(|successor| network.add_arc(2 * rank + 1, 2 * successor, 0)).collect());
+    }
+    // greedy feasible flow: start a path at every uncovered node and extend it over uncovered successors
+    let mut covered = vec![false; n];
+    for first in 0..n {
+        if covered[first] {
+            continue;
+        }
+        network.flow[start_arcs[first]] += 1;
+        let mut rank = first;
+        loop {
+            covered[rank] = true;
+            network.flow[node_arcs[rank]] += 1;
+            match compact.successors(rank).iter().position(|successor| !covered[*successor]) {
+                Some(index) => {
+                    network.flow[edge_arcs[rank][index]] += 1;
+                    rank = compact.successors(rank)[index];
+                }
+                None => break,
+            }
+        }
+        network.flow[end_arcs[rank]] += 1;
+    }
+    while network.reduce_once(source, sink) {}
+    // decompose the flow into paths
+    let mut paths = vec![];
+    while let Some(arc) = start_arcs.iter().find(|arc| network.flow[**arc] > 0) {
+        let first = network.to[*arc] / 2;
+        network.flow[start_arcs[first]] -= 1;
+        let mut path = vec![];
+        let mut rank = first;
+        loop {
+            path.push(rank);
+            network.flow[node_arcs[rank]] -= 1;
+            match edge_arcs[rank].iter().find(|arc| network.flow[**arc] > 0) {
+                Some(arc) => {
+                    network.flow[*arc] -= 1;
+                    rank = network.to[*arc] / 2;
+                }
+                None => break,
+            }
+        }
+        network.flow[end_arcs[rank]] -= 1;
+        paths.push(path);
+    }
+    paths
+}
+
+/// Chainer using one Fenwick tree per path of a minimum path cover
+#[derive(Default, Clone, Debug)]
+pub struct PathCoverChainer {
+    cover: Vec<Vec<usize>>,
+    on_paths: Vec<Vec<(usize, usize)>>, // rank -> (cover path, position on it)
+    forward: Vec<Vec<Vec<usize>>>, // cover path -> position -> ranks whose last strict predecessor on the path is there
+    reaching: Vec<Vec<i32>>, // cover path -> rank -> last position on the path reaching the rank (itself included), -1 if none
+    node_ids: Vec<usize>,
+    predecessors: Vec<Vec<usize>>,
+}
+
+impl PathCoverChainer {
+    pub fn new(compact: &CompactPoaGraph) -> Self {
+        let n = compact.node_count();
+        let cover = minimum_path_cover(compact);
+        let mut on_paths = vec![vec![]; n];
+        for (path, ranks) in cover.iter().enumerate() {
+            for (position, rank) in ranks.iter().enumerate() {
+                on_paths[*rank].push((path, position));
+            }
+        }
+        // last position on every cover path of a node that reaches the rank (itself included), -1 if none
+        let mut reaching: Vec<Vec<i32>> = vec![vec![-1; n]; cover.len()];
+        let mut forward: Vec<Vec<Vec<usize>>> = cover.iter().map(|ranks| vec![vec![]; ranks.len()]).collect();
+        for rank in 0..n {
+            for path in 0..cover.len() {
+                // strictly before the rank, through its predecessors
+                let last = compact.predecessors(rank).iter().map(|predecessor| reaching[path][*predecessor]).max().unwrap_or(-1);
+                if last >= 0 {
+                    forward[path][last as usize].push(rank);
+                }
+                reaching[path][rank] = last;
+            }
+            for (path, position) in &on_paths[rank] {
+                reaching[*path][rank] = *position as i32;
+            }
+        }
+        PathCoverChainer {
+            cover,
+            on_paths,
+            forward,
+            reaching,
+            node_ids: compact.node_ids().to_vec(),
+            predecessors: (0..n).map(|rank| compact.predecessors(rank).to_vec()).collect(),
+        }
+    }
+
+    /// The cover paths as ranks
+    pub fn cover(&self) -> &Vec<Vec<usize>> {
+        &self.cover
+    }
+
+    /// True if there is a path of at least one edge from `from` to `to` (both ranks)
+    pub fn reaches(&self, from: usize, to: usize) -> bool {
+        from != to && self.on_paths[from].iter().any(|(path, position)| self.reaching[*path][to] >= *position as i32)
+    }
+
+    /// LCSk++ over the anchors in the format `lcskpp_graph` takes them ((query position, first
+    /// rank) sorted and the ranks of every anchor), without paths. Two anchors chain when they do
+    /// not overlap in the query and the end of the first reaches the start of the second, or when
+    /// the second continues the first shifted by one base. Returns the same as `lcskpp_graph`.
+    pub fn chain(&self, kmer_pos_vec: &[(u32, u32)], kmer_graph_index: &[Vec<u32>], k: usize) -> Result<(Vec<(usize, usize)>, Vec<(usize, usize)>, u32)> {
+        if kmer_pos_vec.is_empty() {
+            return Ok((vec![], vec![], 0));
+        }
+        let k = k as u32;
+        let n = self.node_ids.len();
+        let mut starting_at = vec![vec![]; n];
+        let mut ending_at = vec![vec![]; n];
+        let mut query_len = 0;
+        for (idx, &(x, y)) in kmer_pos_vec.iter().enumerate() {
+            if kmer_graph_index[idx].first() != Some(&y) {
+                return Err(PoaError::InvalidAnchor { anchor: idx });
+            }
+            starting_at[y as usize].push(idx);
+            ending_at[*kmer_graph_index[idx].last().unwrap() as usize].push(idx);
+            query_len = max(query_len, x + k);
+        }
+        // one tree per cover path over the query end position of the anchors, value (score, anchor)
+        let mut trees: Vec<MaxBitTree<(u32, u32)>> = self.cover.iter().map(|_| MaxBitTree::new(query_len as usize)).collect();
+        // best predecessor forwarded to every anchor before its start node is visited
+        let mut forwarded: Vec<(u32, u32)> = vec![(0, 0); kmer_pos_vec.len()];
+        let mut dp: Vec<(u32, i32, Vec<u32>, u32)> = vec![(0, 0, vec![], 0); kmer_pos_vec.len()]; // score, prev match, graph nodes, query pos
+        let mut best_dp = (k, 0); // score, anchor
+        for rank in 0..n {
+            for &p in &starting_at[rank] {
+                let x = kmer_pos_vec[p].0;
+                dp[p] = (k, -1, kmer_graph_index[p].clone(), x);
+                let (value, position) = forwarded[p];
+                if value > 0 {
+                    dp[p].0 = k + value;
+                    dp[p].1 = position as i32;
+                }
+                // continuation of an anchor one base back in the query and the graph, already final as it starts earlier
+                if x >= 1 {
+                    for predecessor in &self.predecessors[rank] {
+                        if let Ok(cont_idx) = kmer_pos_vec.binary_search(&(x - 1, *predecessor as u32)) {
+                            if kmer_graph_index[cont_idx][1..] != kmer_graph_index[p][..k as usize - 1] {
+                                continue;
+                            }
+                            if dp[cont_idx].0 + 1 > dp[p].0 {
+                                dp[p].0 = dp[cont_idx].0 + 1;
+                                dp[p].1 = cont_idx as i32;
+                                dp[p].2 = vec![*kmer_graph_index[p].last().unwrap()];
+                                dp[p].3 = x + k - 1;
+                            }
+                        }
+                    }
+                }
+                if dp[p].1 >= 0 {
+                    best_dp = max(best_dp, (dp[p].0, p));
+                }
+            }
+            for &p in &ending_at[rank] {
+                let end = kmer_pos_vec[p].0 + k - 1;
+                for (path, _) in &self.on_paths[rank] {
+                    trees[*path].set(end as usize, (dp[p].0, p as u32));
+                }
+            }
+            // forward propagation to the anchors for which this is the last reaching node of the cover path
+            for (path, position) in &self.on_paths[rank] {
+                for target in &self.forward[*path][*position] {
+                    for &p in &starting_at[*target] {
+                        let x = kmer_pos_vec[p].0;
+                        if x > 0 {
+                            forwarded[p] = max(forwarded[p], trees[*path].get(x as usize - 1));
+                        }
+                    }
+                }
+            }
+        }
+        let (best_score, mut prev_match) = (best_dp.0, best_dp.1 as i32);
+        let mut query_graph_path = vec![];
+        let mut unconverted_query_graph_path = vec![];
+        while prev_match >= 0 {
+            let (_, prev, nodes, query_pos) = &dp[prev_match as usize];
+            // query_pos is the query position of the first kept node
+            for (offset, node) in nodes.iter().enumerate().rev() {
+                query_graph_path.push(((query_pos + offset as u32) as usize, self.node_ids[*node as usize]));
+                unconverted_query_graph_path.push(((query_pos + offset as u32) as usize, *node as usize));
+            }
+            prev_match = *prev;
+        }
+        query_graph_path.reverse();
+        unconverted_query_graph_path.reverse();
+        Ok((query_graph_path, unconverted_query_graph_path, best_score))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::chaining::Chaining;
+    use crate::poa::Poa;
+    use crate::simulate::get_random_sequences_from_generator;
+    use petgraph::graph::NodeIndex;
+
+    // quadratic LCSk++ over all anchor pairs where the end of one reaches the start of the other
+    fn brute_force_score(poa: &Poa, query: &[u8], k: usize) -> u32 {
+        let order = poa.topological_order();
+        let mut ancestors: Vec<Vec<bool>> = vec![vec![false; order.len()]; order.len()];
+        for rank in 0..order.len() {
+            for predecessor in poa.graph.neighbors_directed(NodeIndex::new(order[rank]), petgraph::Incoming) {
+                let predecessor = poa.topological_ranks()[predecessor.index()];
+                let mut merged = ancestors[predecessor].clone();
+                merged[predecessor] = true;
+                for (ancestor, reaches) in merged.into_iter().enumerate() {
+                    ancestors[rank][ancestor] |= reaches;
+                }
+            }
+        }
+        let mut anchors = poa.anchorer(k).find_anchors(query).unwrap().anchors;
+        anchors.sort_by_key(|anchor| anchor.first_rank());
+        let mut scores = vec![0; anchors.len()];
+        let mut best = 0;
+        for current in 0..anchors.len() {
+            let (x, first) = (anchors[current].query_pos, anchors[current].first_rank());
+            scores[current] = k as u32;
+            for previous in 0..current {
+                if anchors[previous].first_rank() >= first {
+                    break;
+                }
+                let previous_x = anchors[previous].query_pos;
+                if previous_x + (k as u32) - 1 < x && ancestors[first as usize][anchors[previous].last_rank() as usize] {
+                    scores[current] = scores[current].max(scores[previous] + k as u32);
+                }
+                if previous_x + 1 == x && anchors[previous].ranks[1..] == anchors[current].ranks[..k - 1] {
+                    scores[current] = scores[current].max(scores[previous] + 1);
+                }
+            }
+            best = best.max(scores[current]);
+        }
+        best
+    }
+
+    #[test]
+    fn cover_chains_over_reachability() {
+        let k = 8;
+        let seqs: Vec<Vec<u8>> = get_random_sequences_from_generator(400, 12, 0).into_iter().map(|seq| seq.into_bytes()).collect();
+        let mut poa = Poa::from_string(1, -1, -2, &seqs[0]).unwrap();
+        // a single read path, both chainings give the same chain
+        for read in &seqs[8..] {
+            poa.set_chaining(Chaining::PerPath);
+            let per_path = poa.anchor_query_scored(read, k).unwrap();
+            poa.set_chaining(Chaining::PathCover);
+            assert_eq!(poa.anchor_query_scored(read, k).unwrap(), per_path);
+        }
+        for read in &seqs[1..8] {
+            let lcsk_path = poa.anchor_query(read, k).unwrap();
+            let alignment = poa.custom_simd_indirect_address(read, &lcsk_path, 40);
+            poa.add_alignment(&alignment, read).unwrap();
+        }
+        let mut higher = 0;
+        for read in &seqs[8..] {
+            poa.set_chaining(Chaining::PerPath);
+            let (_, path_score) = poa.anchor_query_scored(read, k).unwrap();
+            poa.set_chaining(Chaining::PathCover);
+            let (anchors, cover_score) = poa.anchor_query_scored(read, k).unwrap();
+            assert_eq!(cover_score, brute_force_score(&poa, read, k));
+            assert!(cover_score >= path_score);
+            higher += (cover_score > path_score) as usize;
+            // the chain goes forward in the query and the graph
+            assert_eq!(anchors.len(), cover_score as usize);
+            for pair in anchors.windows(2) {
+                assert!(pair[0].0 < pair[1].0);
+                assert!(poa.topological_ranks()[pair[0].1] < poa.topological_ranks()[pair[1].1]);
+            }
+        }
+        assert!(higher > 0);
+        // the graph k-mer index anchors have no paths and are always chained over the cover
+        poa.set_chaining(Chaining::PerPath);
+        poa.enable_kmer_index(k, 64).unwrap();
+        for read in &seqs[8..] {
+            let (anchors, score) = poa.anchor_query_scored(read, k).unwrap();
+            assert!(score > 0);
+            for pair in anchors.windows(2) {
+                assert!(pair[0].0 < pair[1].0);
+                assert!(poa.topological_ranks()[pair[0].1] < poa.topological_ranks()[pair[1].1]);
+            }
+        }
+    }
+}
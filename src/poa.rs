@@ -1,13 +1,49 @@
 use std::cmp::{max, Ordering};
 use petgraph::graph::NodeIndex;
-use petgraph::visit::Topo;
-use petgraph::{Directed, Graph, Incoming};
+use petgraph::{Directed, Direction, Graph, Incoming, Outgoing};
+use petgraph::visit::EdgeRef;
 pub const MIN_SCORE: i32 = -858_993_459; // negative infinity; see alignment/pairwise/mod.rs
 pub type POAGraph = Graph<u8, i32, Directed, usize>;
 use std::simd::i32x8;
 use std::simd::cmp::SimdOrd;
-use std::collections::HashMap;
-use std::time::Instant;
+use std::sync::{Arc, OnceLock};
+use crate::compact_graph::CompactPoaGraph;
+use crate::graph_align::{align_compact_graphs, GraphAlignment};
+use crate::graph_kmer::GraphKmerIndex;
+use crate::lcsk::{heaviest_paths, lcsk_path_for_query, reverse_complement, Orientation, PathIndex, PathLimits};
+use crate::read_order::{order_reads, ReadOrder};
+use crate::seeding::Seeding;
+use crate::path_cover::PathCoverChainer;
+use crate::chaining::{Chaining, GapCostChainer, LcskChainer, SharedChainer, SharedTreePool};
+use crate::anchor::{AnchorSet, Anchorer, Chain};
+use crate::error::{PoaError, Result};
+use crate::packed_kmer::{check_kmer_size, check_match_kmer_size};
+
+/// Marker used in `BASE_TO_INDEX` for bases that have no query profile row.
+pub const INVALID_BASE_INDEX: u8 = u8::MAX;
+/// Precomputed base to query profile row lookup (A, C, G, T), replaces the per call hash table.
+pub const BASE_TO_INDEX: [u8; 256] = base_to_index_table();
+
+/// Error for an empty sequence or one with a base that has no query profile row, such a
+/// base would end up in a graph node the aligners can not score
+pub fn check_sequence(seq: &[u8]) -> Result<()> {
+    if seq.is_empty() {
+        return Err(PoaError::EmptySequence);
+    }
+    match seq.iter().position(|base| BASE_TO_INDEX[*base as usize] == INVALID_BASE_INDEX) {
+        Some(position) => Err(PoaError::InvalidBase { position, base: seq[position] }),
+        None => Ok(()),
+    }
+}
+
+const fn base_to_index_table() -> [u8; 256] {
+    let mut table = [INVALID_BASE_INDEX; 256];
+    table[b'A' as usize] = 0;
+    table[b'C' as usize] = 1;
+    table[b'G' as usize] = 2;
+    table[b'T' as usize] = 3;
+    table
+}
 
 // Unlike with a total order we may have arbitrary successors in the
 // traceback matrix. I have not yet figured out what the best level of
@@ -56,10 +92,12 @@ impl PartialEq for TracebackCell {
 //impl Default for TracebackCell { }
 
 
+#[derive(Default, Clone, Debug)]
 pub struct  SimdTracker {
     gap_open: i32, // required for sending fake data of unbanded sections
     simd_matrix: Vec<Vec<i32x8>>, // each row has the start end info and simd vecs (start end info is for simd vec indices)
     start_end_tracker: Vec<(usize, usize)>,
+    computed: Vec<(usize, usize)>, // first and last simd vec index the kernel computed in each row
 }
 
 impl SimdTracker {
@@ -76,9 +114,31 @@ impl SimdTracker {
         SimdTracker {
             gap_open: gap_open,
             simd_matrix: simd_matrix,
+            computed: start_end_tracker.clone(),
             start_end_tracker: start_end_tracker,
         }
     }
+    // reuse the allocated rows for a new alignment, rows only grow when the graph gets bigger
+    fn reset(&mut self, m: usize, n: usize, gap_open: i32) {
+        self.gap_open = gap_open;
+        if self.simd_matrix.len() < m {
+            self.simd_matrix.resize_with(m, Vec::new);
+        }
+        for row in self.simd_matrix.iter_mut().take(m) {
+            row.clear();
+        }
+        self.start_end_tracker.clear();
+        self.start_end_tracker.resize(m, (0, n));
+        self.computed.clear();
+        self.computed.resize(m, (0, n));
+        // make the index 0 one
+        let gap_open_8 = i32x8::splat(-gap_open);
+        let gap_multiplier = i32x8::from_array([1, 2, 3, 4, 5, 6, 7, 8]);
+        self.simd_matrix[0].extend((0..n).map(|j| {
+            let base_offset = (j * 8) as i32;
+            (gap_multiplier + i32x8::splat(base_offset)) * -gap_open_8
+        }));
+    }
     // make a skel with num of nodes of the graph
     fn with_capacity(m: usize, n: usize, gap_open: i32) -> Self {
         let start_end_tracker = vec![(0, n); m];
@@ -99,6 +159,7 @@ impl SimdTracker {
         SimdTracker {
             gap_open: gap_open,
             simd_matrix: simd_matrix,
+            computed: start_end_tracker.clone(),
             start_end_tracker: start_end_tracker,
         }
     }
@@ -123,7 +184,6 @@ impl SimdTracker {
             let gap_open = i32x8::splat(self.gap_open as i32);
             let j_multi = i32x8::splat(j as i32 * 8);
             let j_increment = i32x8::from_array([1, 2, 3, 4, 5, 6, 7, 8]);
-            println!("Go left");
             return ((j_multi + j_increment) * gap_open) + neg_10;
         }
         // make it go up to meet the band, modify the values do it does not go left should be ok with same numbers
@@ -142,6 +202,48 @@ impl SimdTracker {
         }
     }
     // set function, if not in band do nothing 
+    // score the kernel computed for a cell, None outside the band (the first row keeps its
+    // initial values there)
+    fn score(&self, i: usize, query_pos: usize) -> Option<i32> {
+        let j = query_pos / 8;
+        if self.simd_matrix[i].is_empty() || self.start_end_tracker[i].0 > j || self.start_end_tracker[i].1 <= j || self.computed[i].0 > j || self.computed[i].1 < j {
+            return None;
+        }
+        Some(self.simd_matrix[i][j - self.start_end_tracker[i].0][query_pos % 8])
+    }
+    // simd vecs the kernel computed over all rows, the first row holds one per vec of the query
+    fn computed_vecs(&self) -> usize {
+        let n = self.simd_matrix.first().map_or(0, |row| row.len());
+        self.computed.iter().map(|(start, end)| (*end).min(n.saturating_sub(1)) + 1 - (*start).min(n)).sum()
+    }
+}
+
+/// Buffers reused across reads by the SIMD kernels
+///
+/// Holds the query profile and the banded score matrix so aligning a read only
+/// allocates when the graph or the query is larger than any seen before.
+#[derive(Default, Clone, Debug)]
+pub struct AlignerWorkspace {
+    profile: Vec<Vec<i32x8>>,
+    simd_tracker: SimdTracker,
+}
+
+impl AlignerWorkspace {
+    /// Create an empty workspace, buffers are allocated on first use.
+    pub fn new() -> Self {
+        AlignerWorkspace::default()
+    }
+
+    /// Score the last banded alignment computed for a node (index) and a query position,
+    /// `None` outside the band
+    pub fn banded_score(&self, node: usize, query_pos: usize) -> Option<i32> {
+        self.simd_tracker.score(node, query_pos)
+    }
+
+    /// DP cells the last banded alignment computed, 8 per SIMD vector
+    pub fn banded_cells(&self) -> usize {
+        self.simd_tracker.computed_vecs() * 8
+    }
 }
 
 impl Eq for TracebackCell {}
@@ -231,6 +333,11 @@ impl Traceback {
         }
     }
 
+    /// Score of the cell of a node (index) and a query position
+    pub fn score(&self, node: usize, query_pos: usize) -> i32 {
+        self.get(node + 1, query_pos + 1).score
+    }
+
     fn set(&mut self, i: usize, j: usize, cell: TracebackCell) {
         // set the matrix cell if in band range
         if !(self.matrix[i].1 > j || self.matrix[i].2 < j) {
@@ -275,7 +382,6 @@ impl Traceback {
         // Now backtrack through the matrix to construct an optimal path
         let mut i = self.last.index() + 1;
         let mut j = self.cols;
-        println!("non simd score: {}", self.get(i, j).score);
         while i > 0 || j > 0 {
             // push operation and edge corresponding to (one of the) optimal
             // routes
@@ -335,22 +441,93 @@ pub struct Aligner {
     traceback: Traceback,
     query: Vec<u8>,
     poa: Poa,
+    workspace: AlignerWorkspace,
+    read_order: (ReadOrder, Vec<usize>),
+    rejected: Vec<usize>, // reads from_reads could not add
+    pruning: Option<Pruning>,
+    kmer_size: usize, // k and band of align_with_anchors
+    band_size: usize,
 }
 
 impl Aligner {
     /// Create new instance.
-    pub fn new(match_score: i32, mismatch_score: i32, gap_open_score: i32, reference: &Vec<u8>) -> Self {
-        Aligner {
+    pub fn new(match_score: i32, mismatch_score: i32, gap_open_score: i32, reference: &Vec<u8>) -> Result<Self> {
+        Ok(Aligner {
             traceback: Traceback::new(),
             query: reference.to_vec(),
-            poa: Poa::from_string(match_score, mismatch_score, gap_open_score, reference),
+            poa: Poa::from_string(match_score, mismatch_score, gap_open_score, reference)?,
+            workspace: AlignerWorkspace::new(),
+            read_order: (ReadOrder::Input, vec![0]),
+            rejected: vec![],
+            pruning: None,
+            kmer_size: 4,
+            band_size: 10,
+        })
+    }
+
+    /// Build the graph from a batch of reads, adding them in the order chosen by `read_order`.
+    /// Each read is anchored with LCSk++ and aligned with the banded SIMD kernel. A read that
+    /// can not be added is skipped and listed by `rejected_reads`, the batch goes on.
+    pub fn from_reads(match_score: i32, mismatch_score: i32, gap_open_score: i32, reads: &Vec<Vec<u8>>, read_order: ReadOrder, kmer_size: usize, band_size: usize) -> Result<Self> {
+        check_match_kmer_size(kmer_size)?;
+        let order = order_reads(reads, read_order, kmer_size)?;
+        let mut rejected = vec![];
+        let mut remaining = order.into_iter();
+        // the first read that starts a graph, the error of the last one if none does
+        let mut last_error = PoaError::EmptySequence;
+        let (mut aligner, first) = loop {
+            let Some(first) = remaining.next() else {
+                return Err(last_error);
+            };
+            match Aligner::new(match_score, mismatch_score, gap_open_score, &reads[first]) {
+                Ok(aligner) => break (aligner, first),
+                Err(error) => {
+                    rejected.push(first);
+                    last_error = error;
+                }
+            }
+        };
+        let mut added = vec![first];
+        let mut all_paths = vec![];
+        let mut all_sequences = vec![];
+        let pool = aligner.poa.lcsk_pool.clone();
+        for current in remaining {
+            let previous = *added.last().unwrap();
+            let threaded = all_paths.len();
+            let result = lcsk_path_for_query(aligner.graph(), aligner.topological_order(), aligner.topological_ranks(), &reads[previous], &reads[current], &mut all_paths, &mut all_sequences, kmer_size, &mut pool.lock().unwrap())
+                .and_then(|lcsk_path| aligner.global_simd_banded(&reads[current], &lcsk_path, band_size).map(|_| ()));
+            match result {
+                Ok(()) => added.push(current),
+                Err(_) => {
+                    // the graph is unchanged, the previous read is threaded again with the next one
+                    all_paths.truncate(threaded);
+                    all_sequences.truncate(threaded);
+                    rejected.push(current);
+                }
+            }
         }
+        aligner.read_order = (read_order, added);
+        aligner.rejected = rejected;
+        aligner.set_anchoring(kmer_size, band_size);
+        Ok(aligner)
+    }
+
+    /// Return the read order strategy used to build the graph and the indices of the reads
+    /// in the order they were added, sequence id i is the read at the i-th index.
+    pub fn read_order(&self) -> (ReadOrder, &Vec<usize>) {
+        (self.read_order.0, &self.read_order.1)
+    }
+
+    /// Indices of the reads `from_reads` skipped as they could not be added
+    pub fn rejected_reads(&self) -> &Vec<usize> {
+        &self.rejected
     }
     /// Add the alignment of the last query to the graph.
-    pub fn add_to_graph(&mut self) -> &mut Self {
+    pub fn add_to_graph(&mut self) -> Result<&mut Self> {
         let alignment = self.traceback.alignment();
-        self.poa.add_alignment(&alignment, &self.query);
-        self
+        self.poa.add_alignment(&alignment, &self.query)?;
+        self.prune_if_due();
+        Ok(self)
     }
 
     /// Return alignment of last added query against the graph.
@@ -359,74 +536,189 @@ impl Aligner {
     }
 
     /// Globally align a given query against the graph.
-    pub fn global(&mut self, query: &Vec<u8>) -> &mut Self {
+    pub fn global(&mut self, query: &Vec<u8>) -> Result<&mut Self> {
+        self.poa.check_alignable(query)?;
         self.query = query.to_vec();
         self.traceback = self.poa.custom(query);
-        self
+        Ok(self)
     }
-    pub fn global_simd(&mut self, query: &Vec<u8>) -> &mut Self {
+    pub fn global_simd(&mut self, query: &Vec<u8>) -> Result<&mut Self> {
+        self.poa.check_alignable(query)?;
         self.query = query.to_vec();
         //let alignment = self.poa.custom_simd(query);
         let alignment = self.poa.custom_simd(query);
-        self.poa.add_alignment(&alignment, &self.query);
-        self
+        self.poa.add_alignment(&alignment, &self.query)?;
+        self.prune_if_due();
+        Ok(self)
     }
-    pub fn global_simd_banded(&mut self, query: &Vec<u8>, lcsk_path: &Vec<(usize, usize)>, band_size: usize) -> &mut Self {
-        self.query = query.to_vec();
+    pub fn global_simd_banded(&mut self, query: &Vec<u8>, lcsk_path: &Vec<(usize, usize)>, band_size: usize) -> Result<&mut Self> {
+        self.poa.check_alignable(query)?;
+        self.query.clear();
+        self.query.extend_from_slice(query);
         //let alignment = self.poa.custom_simd(query);
-        let alignment = self.poa.custom_simd_indirect_address(query, lcsk_path, band_size);
-        self.poa.add_alignment(&alignment, &self.query);
+        let alignment = self.poa.custom_simd_indirect_address_with_workspace(query, lcsk_path, band_size, &mut self.workspace);
+        self.poa.add_alignment(&alignment, &self.query)?;
+        self.prune_if_due();
+        Ok(self)
+    }
+    /// Anchor the query (seeding, k-mer matching and chaining as set on the graph), align it
+    /// with the banded SIMD kernel around the chain and add it to the graph. k and the band
+    /// are set with `set_anchoring`.
+    pub fn align_with_anchors(&mut self, query: &Vec<u8>) -> Result<&mut Self> {
+        self.poa.check_alignable(query)?;
+        let chain = self.poa.anchor_chain(query, self.kmer_size)?;
+        self.global_simd_banded(query, &chain.path, self.band_size)
+    }
+    /// Change the k-mer size and band of `align_with_anchors` (4 and 10 by default).
+    pub fn set_anchoring(&mut self, kmer_size: usize, band_size: usize) -> &mut Self {
+        self.kmer_size = kmer_size;
+        self.band_size = band_size;
         self
     }
+    /// Add a read from either strand: it is anchored on both strands, reverse complemented
+    /// when that anchors better, aligned with the banded SIMD kernel and added to the graph.
+    /// Returns the detected orientation, which is also kept with the sequence's path.
+    pub fn add_read(&mut self, read: &Vec<u8>, kmer_size: usize, band_size: usize) -> Result<Orientation> {
+        self.poa.check_alignable(read)?;
+        let (orientation, oriented, lcsk_path) = self.poa.orient_query(read, kmer_size)?;
+        let alignment = self.poa.custom_simd_indirect_address_with_workspace(&oriented, &lcsk_path, band_size, &mut self.workspace);
+        self.poa.add_alignment_oriented(&alignment, &oriented, orientation)?;
+        self.query = oriented;
+        self.prune_if_due();
+        Ok(orientation)
+    }
+    /// Prune the graph automatically while reads are added, `None` turns it off.
+    pub fn set_pruning(&mut self, pruning: Option<Pruning>) -> &mut Self {
+        self.pruning = pruning;
+        self
+    }
+    /// Prune the graph now, see `Poa::prune`.
+    pub fn prune(&mut self, min_edge_weight: i32, min_tip_len: usize) -> (usize, usize) {
+        self.poa.prune(min_edge_weight, min_tip_len)
+    }
+    // prune when pruning is on and the last read completed another `every_reads` reads
+    fn prune_if_due(&mut self) {
+        if let Some(pruning) = self.pruning {
+            if pruning.every_reads > 0 && self.poa.sequence_count().is_multiple_of(pruning.every_reads) {
+                self.poa.prune(pruning.min_edge_weight, pruning.min_tip_len);
+            }
+        }
+    }
+    /// LCSk++ anchors of the query against the graph, see `Poa::anchor_query`.
+    pub fn anchor_query(&self, query: &[u8], kmer_size: usize) -> Result<Vec<(usize, usize)>> {
+        self.poa.anchor_query(query, kmer_size)
+    }
+    /// Change which k-mers are matched when anchoring, see `Poa::set_seeding`.
+    pub fn set_seeding(&mut self, seeding: Seeding) -> &mut Self {
+        self.poa.set_seeding(seeding);
+        self
+    }
+    /// Change how anchors are chained, see `Poa::set_chaining`.
+    pub fn set_chaining(&mut self, chaining: Chaining) -> &mut Self {
+        self.poa.set_chaining(chaining);
+        self
+    }
+    /// Anchor with a graph k-mer index kept up to date as reads are added, see `Poa::enable_kmer_index`.
+    pub fn enable_kmer_index(&mut self, k: usize, max_variants: usize) -> Result<&mut Self> {
+        self.poa.enable_kmer_index(k, max_variants)?;
+        Ok(self)
+    }
+    pub fn kmer_index(&self) -> Option<&GraphKmerIndex> {
+        self.poa.kmer_index()
+    }
+    /// Remove the sequence with the given id from the graph, see `Poa::remove_sequence`.
+    pub fn remove_sequence(&mut self, seq_id: usize) -> Option<SequencePath> {
+        self.poa.remove_sequence(seq_id)
+    }
+
+    /// Globally align the graph of another aligner to this graph.
+    pub fn align_graph(&self, other: &Aligner) -> GraphAlignment {
+        self.poa.align_graph(&other.poa)
+    }
+
+    /// Merge the graph of another aligner into this one, see `Poa::merge`.
+    pub fn merge(&mut self, other: &Aligner, kmer_size: usize, band_size: usize) -> Result<Vec<Option<usize>>> {
+        self.poa.merge(&other.poa, kmer_size, band_size)
+    }
+
+    /// Return the consensus of the graph without the given sequence, the graph itself is
+    /// left unchanged. `None` if there is no such sequence or it is the only one.
+    pub fn leave_one_out_consensus(&self, seq_id: usize) -> Option<Vec<u8>> {
+        if self.poa.sequences.iter().flatten().count() < 2 {
+            return None;
+        }
+        // only the graph is copied, not the matrices and buffers of the aligner
+        let mut without = self.poa.clone();
+        without.remove_sequence(seq_id)?;
+        Some(without.consensus())
+    }
+
+    /// Error correct every sequence in the graph against the others, the corrected read
+    /// is the consensus of the graph with the read left out. Removed sequences are `None`.
+    pub fn corrected_reads(&self) -> Vec<Option<Vec<u8>>> {
+        (0..self.poa.sequence_count()).map(|seq_id| self.leave_one_out_consensus(seq_id)).collect()
+    }
+
+    /// Refine the finished graph by taking every sequence out, re-aligning it against the
+    /// graph built from the others and putting it back. Passes are repeated until the
+    /// consensus stops changing or `max_iterations` passes were made.
+    ///
+    /// Returns the number of passes made.
+    pub fn refine(&mut self, kmer_size: usize, band_size: usize, max_iterations: usize) -> Result<usize> {
+        let mut consensus = self.consensus();
+        let mut iterations = 0;
+        while iterations < max_iterations {
+            iterations += 1;
+            for seq_id in 0..self.poa.sequence_count() {
+                // nothing to align against when only one sequence is left
+                if self.poa.sequences.iter().flatten().count() < 2 {
+                    break;
+                }
+                let path = match self.poa.remove_sequence(seq_id) {
+                    Some(path) => path,
+                    None => continue,
+                };
+                let lcsk_path = self.poa.anchor_query(&path.sequence, kmer_size)?;
+                let alignment = self.poa.custom_simd_indirect_address_with_workspace(&path.sequence, &lcsk_path, band_size, &mut self.workspace);
+                self.poa.add_alignment_with_id(&alignment, &path.sequence, seq_id, path.orientation)?;
+            }
+            let refined = self.consensus();
+            if refined == consensus {
+                break;
+            }
+            consensus = refined;
+        }
+        Ok(iterations)
+    }
+
     /// Return alignment graph.
     pub fn graph(&self) -> &POAGraph {
         &self.poa.graph
     }
+    /// Return the partial order graph itself, to align with a chosen kernel and add the
+    /// alignment directly.
+    pub fn poa_mut(&mut self) -> &mut Poa {
+        &mut self.poa
+    }
+    /// Return the node indices of the graph in topological order.
+    pub fn topological_order(&self) -> &Vec<usize> {
+        self.poa.topological_order()
+    }
+    /// Return the topological rank of each node index.
+    pub fn topological_ranks(&self) -> &Vec<usize> {
+        self.poa.topological_ranks()
+    }
     /// Return the consensus sequence generated from the POA graph.
     pub fn consensus(&self) -> Vec<u8> {
-        let mut consensus: Vec<u8> = vec![];
-        let max_index = self.poa.graph.node_count();
-        let mut weight_score_next_vec: Vec<(i32, i32, usize)> = vec![(0, 0, 0); max_index + 1];
-        let mut topo = Topo::new(&self.poa.graph);
-        // go through the nodes topologically
-        while let Some(node) = topo.next(&self.poa.graph) {
-            let mut best_weight_score_next: (i32, i32, usize) = (0, 0, usize::MAX);
-            let neighbour_nodes = self.poa.graph.neighbors_directed(node, Incoming);
-            // go through the incoming neighbour nodes
-            for neighbour_node in neighbour_nodes {
-                let neighbour_index = neighbour_node.index();
-                let neighbour_score = weight_score_next_vec[neighbour_index].1;
-                let edges = self.poa.graph.edges_connecting(neighbour_node, node);
-                let weight = edges.map(|edge| edge.weight()).sum();
-                let current_node_score = weight + neighbour_score;
-                // save the neighbour node with the highest weight and score as best
-                if (weight, current_node_score, neighbour_index) > best_weight_score_next {
-                    best_weight_score_next = (weight, current_node_score, neighbour_index);
-                }
-            }
-            weight_score_next_vec[node.index()] = best_weight_score_next;
-        }
-        // get the index of the max scored node (end of consensus)
-        let mut pos = weight_score_next_vec
-            .iter()
-            .enumerate()
-            .max_by_key(|(_, &value)| value.1)
-            .map(|(idx, _)| idx)
-            .unwrap();
-        // go through weight_score_next_vec appending to the consensus
-        while pos != usize::MAX {
-            consensus.push(self.poa.graph.raw_nodes()[pos].weight);
-            pos = weight_score_next_vec[pos].2;
-        }
-        consensus.reverse();
-        consensus
+        self.poa.consensus()
     }
 }
 
 /// A partially ordered alignment graph
 ///
 /// A directed acyclic graph datastructure that represents the topology of a
-/// traceback matrix.
+/// traceback matrix. A topological order of the nodes is cached and kept up to
+/// date by `add_alignment`, so the graph should only be modified through `Poa`.
 #[derive(Default, Clone, Debug)]
 pub struct Poa {
     match_score: i32,
@@ -434,6 +726,41 @@ pub struct Poa {
     gap_open_score: i32,
     pub graph: POAGraph,
     pub memory_usage: usize,
+    topo_order: Vec<usize>, // rank -> node index
+    topo_rank: Vec<usize>, // node index -> rank
+    compact: CompactPoaGraph, // rebuilt after every change of the graph
+    sequences: Vec<Option<SequencePath>>, // by sequence id, None once removed
+    path_limits: PathLimits,
+    kmer_index: Option<GraphKmerIndex>, // kept up to date when enabled
+    seeding: Seeding, // k-mers used for path index anchoring
+    chaining: Chaining, // how the anchors are chained
+    cover: OnceLock<Arc<PathCoverChainer>>, // built on first use, dropped when the graph changes
+    chainer: OnceLock<SharedChainer>, // for `chaining`, dropped when the graph or the chaining changes
+    lcsk_pool: SharedTreePool, // LCSk++ trees reused by every query, outlives the chainers
+}
+
+/// The path of a sequence through the graph
+#[derive(Default, Clone, Debug)]
+pub struct SequencePath {
+    /// The sequence as it was aligned, reverse complemented for `Orientation::Reverse`
+    pub sequence: Vec<u8>,
+    /// Strand of the read relative to the graph
+    pub orientation: Orientation,
+    /// Node index of every base of the sequence (clipped bases are skipped)
+    pub nodes: Vec<usize>,
+    /// Edges the sequence added weight to
+    pub edges: Vec<(usize, usize)>,
+    /// Set when pruning deleted part of the path
+    pub truncated: bool,
+}
+
+/// Settings for pruning the graph while reads are added, see `Poa::prune`
+#[derive(Copy, Clone, Debug)]
+pub struct Pruning {
+    /// Prune after every this many reads
+    pub every_reads: usize,
+    pub min_edge_weight: i32,
+    pub min_tip_len: usize,
 }
 
 impl Poa {
@@ -443,7 +770,8 @@ impl Poa {
     ///
     /// * `scoring` - the score struct
     /// * `reference` - a reference TextSlice to populate the initial reference graph
-    pub fn from_string(match_score: i32, mismatch_score: i32, gap_open_score: i32, seq: &Vec<u8>) -> Self {
+    pub fn from_string(match_score: i32, mismatch_score: i32, gap_open_score: i32, seq: &Vec<u8>) -> Result<Self> {
+        check_sequence(seq)?;
         let mut graph: Graph<u8, i32, Directed, usize> =
             Graph::with_capacity(seq.len(), seq.len() - 1);
         let mut prev: NodeIndex<usize> = graph.add_node(seq[0]);
@@ -453,12 +781,122 @@ impl Poa {
             graph.add_edge(prev, node, 1);
             prev = node;
         }
-        Poa { match_score: match_score, mismatch_score: mismatch_score, gap_open_score: gap_open_score, graph, memory_usage: 0}
+        // the reference is a chain so the insertion order is topological
+        let topo_order: Vec<usize> = (0..graph.node_count()).collect();
+        let topo_rank = topo_order.clone();
+        let compact = CompactPoaGraph::from_poa_graph(&graph, &topo_order);
+        let reference_path = SequencePath {
+            sequence: seq.to_vec(),
+            nodes: topo_order.clone(),
+            edges: topo_order.windows(2).map(|pair| (pair[0], pair[1])).collect(),
+            truncated: false,
+            orientation: Orientation::Forward,
+        };
+        Ok(Poa { match_score, mismatch_score, gap_open_score, graph, memory_usage: 0, topo_order, topo_rank, compact, sequences: vec![Some(reference_path)], path_limits: PathLimits::default(), kmer_index: None, seeding: Seeding::Exhaustive, chaining: Chaining::PerPath, cover: OnceLock::new(), chainer: OnceLock::new(), lcsk_pool: SharedTreePool::default() })
+    }
+
+    /// Error for a query the aligners can not take: an empty or invalid sequence, or an
+    /// empty graph (every sequence removed)
+    pub fn check_alignable(&self, query: &[u8]) -> Result<()> {
+        check_sequence(query)?;
+        if self.graph.node_count() == 0 {
+            return Err(PoaError::EmptyGraph);
+        }
+        Ok(())
+    }
+
+    /// Return the compact topologically ranked view of the graph used by the aligners.
+    pub fn compact_graph(&self) -> &CompactPoaGraph {
+        &self.compact
+    }
+
+    /// Return the node indices of the graph in topological order.
+    pub fn topological_order(&self) -> &Vec<usize> {
+        &self.topo_order
+    }
+
+    /// Return the topological rank of each node index.
+    pub fn topological_ranks(&self) -> &Vec<usize> {
+        &self.topo_rank
+    }
+
+    // add a node directly after `after` in the cached order, a node without edges can be placed anywhere
+    fn add_node_after(&mut self, base: u8, after: NodeIndex<usize>) -> NodeIndex<usize> {
+        let node = self.graph.add_node(base);
+        let position = self.topo_rank[after.index()] + 1;
+        self.topo_order.insert(position, node.index());
+        self.topo_rank.push(position);
+        for rank in position..self.topo_order.len() {
+            self.topo_rank[self.topo_order[rank]] = rank;
+        }
+        node
+    }
+
+    // add an edge, repairing the cached order if the edge goes against it
+    fn add_edge_ordered(&mut self, from: NodeIndex<usize>, to: NodeIndex<usize>, weight: i32) {
+        self.graph.add_edge(from, to, weight);
+        let lower_bound = self.topo_rank[to.index()];
+        let upper_bound = self.topo_rank[from.index()];
+        if lower_bound < upper_bound {
+            self.reorder_affected_region(from.index(), to.index(), lower_bound, upper_bound);
+        }
+    }
+
+    // Pearce-Kelly: only the nodes ranked between the two ends of the new edge are shuffled.
+    // Nodes reachable from `to` are moved after the nodes reaching `from`, reusing their ranks.
+    fn reorder_affected_region(&mut self, from: usize, to: usize, lower_bound: usize, upper_bound: usize) {
+        let mut visited = vec![false; self.graph.node_count()];
+        let mut stack = vec![to];
+        let mut forward = vec![];
+        visited[to] = true;
+        while let Some(node) = stack.pop() {
+            forward.push(node);
+            for next in self.graph.neighbors_directed(NodeIndex::new(node), Outgoing) {
+                let next = next.index();
+                assert!(next != from, "edge {} -> {} creates a cycle", from, to);
+                if !visited[next] && self.topo_rank[next] < upper_bound {
+                    visited[next] = true;
+                    stack.push(next);
+                }
+            }
+        }
+        let mut backward = vec![];
+        stack.push(from);
+        visited[from] = true;
+        while let Some(node) = stack.pop() {
+            backward.push(node);
+            for prev in self.graph.neighbors_directed(NodeIndex::new(node), Incoming) {
+                let prev = prev.index();
+                if !visited[prev] && self.topo_rank[prev] > lower_bound {
+                    visited[prev] = true;
+                    stack.push(prev);
+                }
+            }
+        }
+        forward.sort_unstable_by_key(|node| self.topo_rank[*node]);
+        backward.sort_unstable_by_key(|node| self.topo_rank[*node]);
+        let mut ranks: Vec<usize> = backward.iter().chain(forward.iter()).map(|node| self.topo_rank[*node]).collect();
+        ranks.sort_unstable();
+        for (node, rank) in backward.into_iter().chain(forward).zip(ranks) {
+            self.topo_rank[node] = rank;
+            self.topo_order[rank] = node;
+        }
     }
 
     pub fn profile_query (seq_y: &Vec<u8>, match_score: i32, mismatch_score: i32) -> Vec<Vec<i32x8>> {
-        let num_seq_vec = (seq_y.len() + 7) / 8;
         let mut MM_simd = vec![vec![]; 4];
+        Poa::profile_query_into(seq_y, match_score, mismatch_score, &mut MM_simd);
+        MM_simd
+    }
+
+    /// Same as `profile_query` but writes into the given buffer, keeping its allocations
+    pub fn profile_query_into (seq_y: &[u8], match_score: i32, mismatch_score: i32, MM_simd: &mut Vec<Vec<i32x8>>) {
+        let num_seq_vec = (seq_y.len() + 7) / 8;
+        MM_simd.resize_with(4, Vec::new);
+        for row in MM_simd.iter_mut() {
+            row.clear();
+            row.reserve(num_seq_vec);
+        }
         // make 4 vectors for query
         let score_table = [0, mismatch_score];
         // go through the query and populate the entries
@@ -484,17 +922,18 @@ impl Poa {
             MM_simd[2].push(i32x8::from_array(temp_G));
             MM_simd[3].push(i32x8::from_array(temp_T));
         }
-        MM_simd
     }
     pub fn custom_simd_indirect_address (&mut self, query: &Vec<u8>, lcsk_path: &Vec<(usize, usize)>, band_size: usize) -> Alignment {
+        let mut workspace = AlignerWorkspace::new();
+        self.custom_simd_indirect_address_with_workspace(query, lcsk_path, band_size, &mut workspace)
+    }
+
+    /// Banded SIMD alignment reusing the buffers of `workspace` between calls
+    pub fn custom_simd_indirect_address_with_workspace (&mut self, query: &Vec<u8>, lcsk_path: &Vec<(usize, usize)>, band_size: usize, workspace: &mut AlignerWorkspace) -> Alignment {
         //println!("simd");
         // profile the query and what not
-        let mut hash_table = HashMap::new();
-        hash_table.insert(65, 0);
-        hash_table.insert(67, 1);
-        hash_table.insert(71, 2);
-        hash_table.insert(84, 3);
-        let MM_simd_full = Poa::profile_query(query, self.match_score, self.mismatch_score);
+        Poa::profile_query_into(query, self.match_score, self.mismatch_score, &mut workspace.profile);
+        let MM_simd_full = &workspace.profile;
         // other simd stuff required
         let gap_open_score = -self.gap_open_score as i32;
         let gap_open_8 = i32x8::splat(gap_open_score);
@@ -509,10 +948,12 @@ impl Poa {
         //SIMD TRACKER INIT
         // update with lcsk path
         // use lcsk path here for making matrix
-        let mut simd_tracker = SimdTracker::new(m,num_seq_vec, self.gap_open_score);
+        let simd_tracker = &mut workspace.simd_tracker;
+        simd_tracker.reset(m, num_seq_vec, self.gap_open_score);
+        // the row before the graph, where a node without predecessors starts like in the scalar kernel
+        let gap_multiplier = i32x8::from_array([1, 2, 3, 4, 5, 6, 7, 8]);
+        let start_row = |simd_index: usize| (gap_multiplier + i32x8::splat((simd_index * 8) as i32)) * -gap_open_8;
         // construct the score matrix (O(n^2) space)
-        let mut index = 0;
-        let mut topo = Topo::new(&self.graph);
         // required stuff for backtrace
         let mut last_node= 0;
 
@@ -521,7 +962,6 @@ impl Poa {
         if lcsk_path.len() == 0 {
             no_kmers = true;
         }
-        println!("no kmers {}", no_kmers);
         let mut start_banding_query_node = (0, 0);
         let mut end_banding_query_node = &(0, 0);
         let mut banding_started = false;
@@ -532,83 +972,145 @@ impl Poa {
             end_banding_query_node = lcsk_path.last().unwrap();
         }
         // END BAND STUFF
-        while let Some(node) = topo.next(&self.graph) {
-            //let mut F = i32x8::from_array([0, 0, 0, 0, 0, 0, 0, (index + 1) * -gap_open_score]);
-            let mut F = zero_8;
-            F[7] = (index + 1) * -gap_open_score;
-            // reference base and index
-            let r = self.graph.raw_nodes()[node.index()].weight;
-            let i = node.index(); // 0 index is for initialization so we start at 1
-            // THIS STUFF FOR BAND PART 2 IN LOOP
-            let mut start = 0;
-            let mut end = n;
-            if !no_kmers {
-                if banding_started == false {
-                    //do banding till start_banding_query_node + bandwidth
-                    end = start_banding_query_node.0 + band_size;
-                }
-                else if banding_ended == true {
-                    // do banding till till end of table
-                    start = if band_size > end_banding_query_node.0 {
-                        0
-                    } else {
-                        end_banding_query_node.0 - band_size
-                    };
-                }
-                else{
-                    start = if band_size > lcsk_path[current_lcsk_path_index].0 {
-                        0
-                    } else {
-                        lcsk_path[current_lcsk_path_index].0 - band_size
-                    };
-                    if lcsk_path.len() < current_lcsk_path_index + 1 {
-                        end = lcsk_path[current_lcsk_path_index + 1].0 + band_size;
+        // unary chains of the graph are aligned as a run of rows
+        for segment in 0..self.compact.segment_count() {
+            for rank in self.compact.segment(segment) {
+                let chained = rank != self.compact.segment(segment).start;
+                // reference base and index
+                let r = self.compact.base(rank);
+                let i = self.compact.node_id(rank); // 0 index is for initialization so we start at 1
+                // the column before the query scores (node index + 1) gaps, as in the scalar kernel
+                let mut F = zero_8;
+                F[7] = (i as i32 + 1) * -gap_open_score;
+                // THIS STUFF FOR BAND PART 2 IN LOOP
+                let mut start = 0;
+                let mut end = n;
+                if !no_kmers {
+                    if banding_started == false {
+                        //do banding till start_banding_query_node + bandwidth
+                        end = start_banding_query_node.0 + band_size;
                     }
-                    else {
-                        end = lcsk_path[current_lcsk_path_index].0 + band_size;
+                    else if banding_ended == true {
+                        // do banding till till end of table
+                        start = if band_size > end_banding_query_node.0 {
+                            0
+                        } else {
+                            end_banding_query_node.0 - band_size
+                        };
+                    }
+                    else{
+                        start = if band_size > lcsk_path[current_lcsk_path_index].0 {
+                            0
+                        } else {
+                            lcsk_path[current_lcsk_path_index].0 - band_size
+                        };
+                        if lcsk_path.len() < current_lcsk_path_index + 1 {
+                            end = lcsk_path[current_lcsk_path_index + 1].0 + band_size;
+                        }
+                        else {
+                            end = lcsk_path[current_lcsk_path_index].0 + band_size;
+                        }
+                    }
+                    if banding_ended != true {
+                        //println!("lcsk node {}", lcsk_path[current_lcsk_path_index].1 );
+                        if lcsk_path[current_lcsk_path_index].1 == i {
+                            //println!("updating ");
+                            current_lcsk_path_index += 1;
+                        }
+                        if start_banding_query_node.1 == i {
+                            banding_started = true;
+                        }
+                        if end_banding_query_node.1 == i {
+                            banding_ended = true;
+                        }
                     }
                 }
-                if banding_ended != true {
-                    //println!("lcsk node {}", lcsk_path[current_lcsk_path_index].1 );
-                    if lcsk_path[current_lcsk_path_index].1 == i {
-                        //println!("updating ");
-                        current_lcsk_path_index += 1;
+                if end > n {
+                    end = n;
+                }
+                // convert start and end to simd index 
+                let start_simd = start / 8;
+                let end_simd = (end / 8) + 1;
+                // EMD BAND STUFF
+                // MAKE SIMD TRACKER FOR ROW
+                if i != 0 {
+                    simd_tracker.new_row(i, start_simd, end_simd); 
+                }
+                simd_tracker.computed[i] = (start_simd, end_simd);
+                //println!("start end {} {}", start_simd, end_simd);
+                last_node = i;
+                let data_base_index = BASE_TO_INDEX[r as usize] as usize;
+                // inside a segment the only predecessor is the previous row, no predecessor lookup needed
+                if chained {
+                    let i_p = self.compact.node_id(rank - 1);
+                    let mut X = zero_8;
+                    X[0] = (i_p as i32 + 1) * -gap_open_score;
+                    for simd_index in start_simd..num_seq_vec.min(end_simd + 1) {
+                        let H_prev = simd_tracker.get(i_p, simd_index);
+                        let E = H_prev - gap_open_8;
+                        let T1 = H_prev.rotate_elements_left::<7>() * right_mask_7;
+                        let T2 = (H_prev.rotate_elements_right::<1>() * left_mask_1) + X + MM_simd_full[data_base_index][simd_index];
+                        X = T1;
+                        let H_curr = simd_tracker.get(i, simd_index).simd_max(T2).simd_max(E);
+                        simd_tracker.set(i, simd_index, H_curr);
                     }
-                    if start_banding_query_node.1 == i {
-                        banding_started = true;
+                }
+                // iterate over the predecessors of this node
+                let self_reference = [rank];
+                let mut prevs = self.compact.predecessors(rank);
+                // add node index i (self referencing if no prev)
+                if prevs.len() == 0 {
+                    prevs = &self_reference;
+                }
+                if chained {
+                    prevs = &[];
+                }
+                // vertical and diagonal
+                for &prev_rank in prevs {
+                    let i_p: usize = self.compact.node_id(prev_rank); // index of previous node
+                    //println!("S");
+                    //let mut X = i32x8::from_array([(index) * -gap_open_score, 0, 0, 0, 0, 0, 0, 0]);
+                    let mut X = zero_8;
+                    if i_p != i {
+                        X[0] = (i_p as i32 + 1) * -gap_open_score;
                     }
-                    if end_banding_query_node.1 == i {
-                        banding_ended = true;
+                    for simd_index in 0..num_seq_vec {
+                        if start_simd > simd_index {
+                            continue;
+                        }
+                        if end_simd < simd_index {
+                            break;
+                        }
+                        let H_prev = if i_p == i { start_row(simd_index) } else { simd_tracker.get(i_p, simd_index) };
+                        //println!("H_prev {:?}", H_prev);
+                        let mut H_curr;
+                        // when no prevs, start
+                        if i_p == i {
+                            H_curr = min_score_8; // was i16::Min * 8
+                        }
+                        else {
+                            H_curr = simd_tracker.get(i, simd_index);
+                        }
+                        let E = H_prev - gap_open_8;
+                        let MM_simd = MM_simd_full[data_base_index][simd_index];
+                        //println!("MM simd {:?}", MM_simd);
+                        // need to define T2 as H cannot be modified here
+                        let T1 = H_prev.rotate_elements_left::<7>() * right_mask_7;
+                        let mut T2 = (H_prev.rotate_elements_right::<1>() * left_mask_1) + X;
+                        //println!("X {:?}", X);
+                        X = T1;
+                        // match score added
+                        //println!("T2 {:?}", T2);
+                        T2 = T2 + MM_simd;
+                    
+                        //println!("E {:?}", E);
+                        // diagonal or horizontal
+                        H_curr = H_curr.simd_max(T2);
+                        H_curr = H_curr.simd_max(E);
+                        simd_tracker.set(i, simd_index, H_curr);
                     }
                 }
-            }
-            if end > n {
-                end = n;
-            }
-            // convert start and end to simd index 
-            let start_simd = start / 8;
-            let end_simd = (end / 8) + 1;
-            // EMD BAND STUFF
-            // MAKE SIMD TRACKER FOR ROW
-            if i != 0 {
-                simd_tracker.new_row(i, start_simd, end_simd); 
-            }
-            //println!("start end {} {}", start_simd, end_simd);
-            last_node = i;
-            let data_base_index = hash_table.get(&r).unwrap();
-            // iterate over the predecessors of this node
-            let mut prevs: Vec<NodeIndex<usize>> = self.graph.neighbors_directed(node, Incoming).collect();
-            // add node index i (self referencing if no prev)
-            if prevs.len() == 0 {
-                prevs.push(node);
-            }
-            // vertical and diagonal
-            for prev_node in &prevs {
-                let i_p: usize = prev_node.index(); // index of previous node
-                //println!("S");
-                //let mut X = i32x8::from_array([(index) * -gap_open_score, 0, 0, 0, 0, 0, 0, 0]);
-                let mut X = zero_8;
-                X[0] = (index) * -gap_open_score;
+                // horizontal NEeds fixing, non simd is faster here
                 for simd_index in 0..num_seq_vec {
                     if start_simd > simd_index {
                         continue;
@@ -616,72 +1118,35 @@ impl Poa {
                     if end_simd < simd_index {
                         break;
                     }
-                    let H_prev = simd_tracker.get(i_p, simd_index);
-                    //println!("H_prev {:?}", H_prev);
-                    let mut H_curr;
-                    // when no prevs, start
-                    if i_p == i {
-                        H_curr = min_score_8; // was i16::Min * 8
-                    }
-                    else {
-                        H_curr = simd_tracker.get(i, simd_index);
-                    }
-                    let E = H_prev - gap_open_8;
-                    let MM_simd = MM_simd_full[*data_base_index][simd_index];
-                    //println!("MM simd {:?}", MM_simd);
-                    // need to define T2 as H cannot be modified here
-                    let T1 = H_prev.rotate_elements_left::<7>() * right_mask_7;
-                    let mut T2 = (H_prev.rotate_elements_right::<1>() * left_mask_1) + X;
-                    //println!("X {:?}", X);
-                    X = T1;
-                    // match score added
-                    //println!("T2 {:?}", T2);
-                    T2 = T2 + MM_simd;
-                    
-                    //println!("E {:?}", E);
-                    // diagonal or horizontal
-                    H_curr = H_curr.simd_max(T2);
-                    H_curr = H_curr.simd_max(E);
-                    simd_tracker.set(i, simd_index, H_curr);
-                }
-            }
-            // horizontal NEeds fixing, non simd is faster here
-            for simd_index in 0..num_seq_vec {
-                if start_simd > simd_index {
-                    continue;
-                }
-                if end_simd < simd_index {
-                    break;
-                }
-                let H = simd_tracker.get(i, simd_index);
-                //println!("H after ver {:?}", H);
-                //F = F - gap_open_8;
-                //F = F.rotate_elements_left::<7>() * right_mask_7;
-                //println!("F before {:?}", F);
-                //let mut T3 = F.clone();
-                let mut T3 = F[7];
-                let mut max_vec = zero_8;
-                for iter in 0..8 {
-                    let temp = H[iter];
-                    // lshift 2 t2, for gap extends
-                    T3 = T3 - gap_open_score;
-                    if temp > T3 {
-                        T3 = temp;
+                    let H = simd_tracker.get(i, simd_index);
+                    //println!("H after ver {:?}", H);
+                    //F = F - gap_open_8;
+                    //F = F.rotate_elements_left::<7>() * right_mask_7;
+                    //println!("F before {:?}", F);
+                    //let mut T3 = F.clone();
+                    let mut T3 = F[7];
+                    let mut max_vec = zero_8;
+                    for iter in 0..8 {
+                        let temp = H[iter];
+                        // lshift 2 t2, for gap extends
+                        T3 = T3 - gap_open_score;
+                        if temp > T3 {
+                            T3 = temp;
+                        }
+                        //T3 = (T3 - gap_open_8) * mask_array[iter];
+                        //T3 = T3.simd_max(H * mask_array[iter]);
+                        //println!("T3 {} {:?}", _iter, T3);
+                        //H = T3.simd_max(H);
+                        max_vec[iter] = T3;
+                        //T3 = (T3).rotate_elements_right::<1>();
                     }
-                    //T3 = (T3 - gap_open_8) * mask_array[iter];
-                    //T3 = T3.simd_max(H * mask_array[iter]);
-                    //println!("T3 {} {:?}", _iter, T3);
-                    //H = T3.simd_max(H);
-                    max_vec[iter] = T3;
-                    //T3 = (T3).rotate_elements_right::<1>();
+                    //println!("max vec {:?}", max_vec);
+                    simd_tracker.set(i, simd_index, max_vec);
+                    F = max_vec;
+                    //print!("{:?}", max_vec);
                 }
-                //println!("max vec {:?}", max_vec);
-                simd_tracker.set(i, simd_index, max_vec);
-                F = max_vec;
-                //print!("{:?}", max_vec);
+                //println!("");
             }
-            //println!("");
-            index += 1;
         }
         // Get the alignment by backtracking and recalculating stuff
         let mut ops: Vec<AlignmentOperation> = vec![];
@@ -693,9 +1158,13 @@ impl Poa {
         let simd_inner_index = (current_query) % 8;
         let simd_vec_obtained = simd_tracker.get(current_node, simd_index);
         let final_score = simd_vec_obtained[simd_inner_index];
-        println!("simd score: {} vec {:?} {} {} {:?}", final_score, simd_vec_obtained, simd_index, simd_inner_index, simd_tracker.start_end_tracker[current_node]);
         //println!("query len {}", current_query);
         loop {
+            // a one base query has nothing left of its first base
+            if current_query == 0 {
+                ops.push(AlignmentOperation::Match(None));
+                break;
+            }
             let mut current_alignment_operation = AlignmentOperation::Match(None);
             //check the score ins left of query
             let prev_simd_index = (current_query - 1) / 8;
@@ -706,10 +1175,11 @@ impl Poa {
 
             let simd_vec_obtained = simd_tracker.get(current_node, simd_index);
             let current_cell_score = simd_vec_obtained[simd_inner_index];
+            let match_or_mismatch = if self.graph.raw_nodes()[current_node].weight == query[current_query] { self.match_score } else { self.mismatch_score };
             let mut next_jump = 0;
             let mut next_node = 0;
             // check left if gap open difference with left
-            let prevs: Vec<NodeIndex<usize>> = self.graph.neighbors_directed(NodeIndex::new(current_node), Incoming).collect();
+            let prevs = self.compact.predecessors(self.topo_rank[current_node]);
             let simd_prev_vec_obtained = simd_tracker.get(current_node, prev_simd_index);
             if current_cell_score == simd_prev_vec_obtained[prev_simd_inner_index] - gap_open_score {
                 current_alignment_operation = AlignmentOperation::Ins(Some(current_node));
@@ -717,8 +1187,8 @@ impl Poa {
                 next_node = current_node;
             }
             else {
-                for prev in &prevs {
-                    let i_p = prev.index();
+                for &prev_rank in prevs {
+                    let i_p = self.compact.node_id(prev_rank);
                     // Top
                     //print!("top {} ", HH[i_p][simd_index][simd_inner_index]);
                     let simd_vec_obtained = simd_tracker.get(i_p, simd_index);
@@ -729,7 +1199,7 @@ impl Poa {
                         next_node = i_p;
                     }
                     // Diagonal
-                    else if (current_cell_score == simd_prev_vec_obtained[prev_simd_inner_index] + self.match_score as i32) || (current_cell_score == simd_prev_vec_obtained[prev_simd_inner_index] + self.mismatch_score as i32) {
+                    else if current_cell_score == simd_prev_vec_obtained[prev_simd_inner_index] + match_or_mismatch {
                         current_alignment_operation = AlignmentOperation::Match(Some((i_p, current_node)));
                         next_jump = current_query - 1;
                         next_node = i_p;
@@ -764,13 +1234,14 @@ impl Poa {
     }
 
     pub fn custom_simd(&mut self, query: &Vec<u8>) -> Alignment {
+        self.custom_simd_with_scores(query).0
+    }
+
+    /// Same as `custom_simd`, also returning the score matrix: a row per node index with the
+    /// query positions packed by 8 (the last vector is padded)
+    pub fn custom_simd_with_scores(&mut self, query: &Vec<u8>) -> (Alignment, Vec<Vec<i32x8>>) {
         //println!("simd");
         // profile the query and what not
-        let mut hash_table = HashMap::new();
-        hash_table.insert(65, 0);
-        hash_table.insert(67, 1);
-        hash_table.insert(71, 2);
-        hash_table.insert(84, 3);
         let MM_simd_full = Poa::profile_query(query, self.match_score, self.mismatch_score);
         // other simd stuff required
         let gap_open_score = -self.gap_open_score as i32;
@@ -786,12 +1257,12 @@ impl Poa {
         //println!("query.len() {}", query.len());
         let num_seq_vec = (n as f64 / 8.0).ceil() as usize;
         //initialize HH with simd vecs, HH is used as traceback
-        let now = Instant::now();
         
         let mut HH: Vec<Vec<i32x8>> = vec![];
         let gap_multiplier = i32x8::from_array([1, 2, 3, 4, 5, 6, 7, 8]);
         for i in 0..m {
-            if i == 0 {
+            // a node without predecessors starts from the row before the graph, like the scalar kernel
+            if self.compact.predecessors(self.topo_rank[i]).is_empty() {
                 // Initialize the first row with gap open multipliers
                 HH.push((0..num_seq_vec).map(|j| {
                     let base_offset = (j * 8) as i32;
@@ -802,35 +1273,34 @@ impl Poa {
                 HH.push(vec![min_score_8; num_seq_vec]);
             }
         }
-        let time = now.elapsed().as_micros() as usize;
-        println!("time for old init {}μs", time);
         // construct the score matrix (O(n^2) space)
-        let mut index = 0;
-        let mut topo = Topo::new(&self.graph);
         // required stuff for backtrace
         let mut last_node= 0;
-        while let Some(node) = topo.next(&self.graph) {
-            //let mut F = i32x8::from_array([0, 0, 0, 0, 0, 0, 0, (index + 1) * -gap_open_score]);
-            let mut F = zero_8;
-            F[7] = (index + 1) * -gap_open_score;
+        for rank in 0..self.compact.node_count() {
             // reference base and index
-            let r = self.graph.raw_nodes()[node.index()].weight;
-            let i = node.index(); // 0 index is for initialization so we start at 1
+            let r = self.compact.base(rank);
+            let i = self.compact.node_id(rank); // 0 index is for initialization so we start at 1
+            // the column before the query scores (node index + 1) gaps, as in the scalar kernel
+            let mut F = zero_8;
+            F[7] = (i as i32 + 1) * -gap_open_score;
             last_node = i;
-            let data_base_index = hash_table.get(&r).unwrap();
+            let data_base_index = BASE_TO_INDEX[r as usize] as usize;
             // iterate over the predecessors of this node
-            let mut prevs: Vec<NodeIndex<usize>> = self.graph.neighbors_directed(node, Incoming).collect();
+            let self_reference = [rank];
+            let mut prevs = self.compact.predecessors(rank);
             // add node index i (self referencing if no prev)
             if prevs.len() == 0 {
-                prevs.push(node);
+                prevs = &self_reference;
             }
             // vertical and diagonal
-            for prev_node in &prevs {
-                let i_p: usize = prev_node.index(); // index of previous node
+            for &prev_rank in prevs {
+                let i_p: usize = self.compact.node_id(prev_rank); // index of previous node
                 //println!("S");
                 //let mut X = i32x8::from_array([(index) * -gap_open_score, 0, 0, 0, 0, 0, 0, 0]);
                 let mut X = zero_8;
-                X[0] = (index) * -gap_open_score;
+                if i_p != i {
+                    X[0] = (i_p as i32 + 1) * -gap_open_score;
+                }
                 for simd_index in 0..num_seq_vec {
                     let mut H_prev = HH[i_p][simd_index];
                     //println!("H_prev {:?}", H_prev);
@@ -843,7 +1313,7 @@ impl Poa {
                         H_curr = HH[i][simd_index];
                     }
                     let E = HH[i_p][simd_index] - gap_open_8;
-                    let MM_simd = MM_simd_full[*data_base_index][simd_index];
+                    let MM_simd = MM_simd_full[data_base_index][simd_index];
                     //println!("MM simd {:?}", MM_simd);
                     // need to define T2 as H cannot be modified here
                     let T1 = H_prev.rotate_elements_left::<7>() * right_mask_7;
@@ -891,7 +1361,6 @@ impl Poa {
                 //print!("{:?}", HH[i][simd_index]);
             }
             //println!("");
-            index += 1;
         }
         // Get the alignment by backtracking and recalculating stuff
         let mut ops: Vec<AlignmentOperation> = vec![];
@@ -902,9 +1371,13 @@ impl Poa {
         let simd_index = (current_query) / 8;
         let simd_inner_index = (current_query) % 8;
         let final_score = HH[current_node][simd_index][simd_inner_index];
-        println!("simd score: {}", final_score);
         //println!("query len {}", current_query);
         loop {
+            // a one base query has nothing left of its first base
+            if current_query == 0 {
+                ops.push(AlignmentOperation::Match(None));
+                break;
+            }
             let mut current_alignment_operation = AlignmentOperation::Match(None);
             //check the score ins left of query
             let prev_simd_index = (current_query - 1) / 8;
@@ -914,18 +1387,19 @@ impl Poa {
             let simd_inner_index = (current_query) % 8;
 
             let current_cell_score = HH[current_node][simd_index][simd_inner_index];
+            let match_or_mismatch = if self.graph.raw_nodes()[current_node].weight == query[current_query] { self.match_score } else { self.mismatch_score };
             let mut next_jump = 0;
             let mut next_node = 0;
             // check left if gap open difference with left
-            let prevs: Vec<NodeIndex<usize>> = self.graph.neighbors_directed(NodeIndex::new(current_node), Incoming).collect();
+            let prevs = self.compact.predecessors(self.topo_rank[current_node]);
             if current_cell_score == HH[current_node][prev_simd_index][prev_simd_inner_index] - gap_open_score {
                 current_alignment_operation = AlignmentOperation::Ins(Some(current_node));
                 next_jump = current_query - 1;
                 next_node = current_node;
             }
             else {
-                for prev in &prevs {
-                    let i_p = prev.index();
+                for &prev_rank in prevs {
+                    let i_p = self.compact.node_id(prev_rank);
                     // Top
                     //print!("top {} ", HH[i_p][simd_index][simd_inner_index]);
                     if current_cell_score == HH[i_p][simd_index][simd_inner_index] - gap_open_score {
@@ -934,7 +1408,7 @@ impl Poa {
                         next_node = i_p;
                     }
                     // Diagonal
-                    else if (current_cell_score == HH[i_p][prev_simd_index][prev_simd_inner_index] + self.match_score as i32) || (current_cell_score == HH[i_p][prev_simd_index][prev_simd_inner_index] + self.mismatch_score as i32) {
+                    else if current_cell_score == HH[i_p][prev_simd_index][prev_simd_inner_index] + match_or_mismatch {
                         current_alignment_operation = AlignmentOperation::Match(Some((i_p, current_node)));
                         next_jump = current_query - 1;
                         next_node = i_p;
@@ -962,10 +1436,10 @@ impl Poa {
         //for op in &ops {
             //println!("{:?}", op);
         //}
-        Alignment {
+        (Alignment {
             score: final_score as i32,
             operations: ops
-        }
+        }, HH)
     }
 
     pub fn custom(&mut self, query: &Vec<u8>) -> Traceback {
@@ -977,15 +1451,14 @@ impl Poa {
         let mut traceback = Traceback::with_capacity(m, n);
         traceback.initialize_scores(self.gap_open_score);
         // construct the score matrix (O(n^2) space)
-        let mut topo = Topo::new(&self.graph);
-        while let Some(node) = topo.next(&self.graph) {
+        for rank in 0..self.compact.node_count() {
+            let node = NodeIndex::new(self.compact.node_id(rank));
             // reference base and index
-            let r = self.graph.raw_nodes()[node.index()].weight; // reference base at previous index
+            let r = self.compact.base(rank); // reference base at previous index
             let i = node.index() + 1; // 0 index is for initialization so we start at 1
             traceback.last = node;
             // iterate over the predecessors of this node
-            let prevs: Vec<NodeIndex<usize>> =
-                self.graph.neighbors_directed(node, Incoming).collect();
+            let prevs = self.compact.predecessors(rank);
             traceback.new_row(
                 i,
                 n + 1,
@@ -1015,8 +1488,8 @@ impl Poa {
                             score: MIN_SCORE,
                             op: AlignmentOperation::Match(None),
                         };
-                    for prev_node in &prevs {
-                        let i_p: usize = prev_node.index() + 1; // index of previous node
+                    for &prev_rank in prevs {
+                        let i_p: usize = self.compact.node_id(prev_rank) + 1; // index of previous node
                         let temp_score;
                         if r == *query_base {
                             temp_score = self.match_score;
@@ -1067,65 +1540,119 @@ impl Poa {
     ///
     /// * `aln` - The alignment of the new sequence to the graph
     /// * `seq` - The sequence being incorporated
-    pub fn add_alignment(&mut self, aln: &Alignment, seq: &Vec<u8>) {
-        let head = Topo::new(&self.graph).next(&self.graph).unwrap();
+    ///
+    /// Returns the id of the sequence, used to look up or remove its path.
+    pub fn add_alignment(&mut self, aln: &Alignment, seq: &Vec<u8>) -> Result<usize> {
+        self.add_alignment_oriented(aln, seq, Orientation::Forward)
+    }
+
+    /// Same as `add_alignment` for a read that was reverse complemented (or not) before
+    /// alignment, `seq` is the aligned sequence and the orientation is kept with its path
+    pub fn add_alignment_oriented(&mut self, aln: &Alignment, seq: &Vec<u8>, orientation: Orientation) -> Result<usize> {
+        let seq_id = self.sequences.len();
+        self.add_alignment_with_id(aln, seq, seq_id, orientation)?;
+        Ok(seq_id)
+    }
+
+    /// Same as `add_alignment_oriented` but stores the path under the given sequence id,
+    /// used to put back a sequence taken out with `remove_sequence`. The graph is left
+    /// unchanged when an error is returned.
+    pub fn add_alignment_with_id(&mut self, aln: &Alignment, seq: &Vec<u8>, seq_id: usize, orientation: Orientation) -> Result<()> {
+        check_sequence(seq)?;
+        if self.topo_order.is_empty() {
+            return Err(PoaError::EmptyGraph);
+        }
+        // every base has to be consumed by exactly one operation, clipped ones jump ahead
+        let mut aligned_len = 0;
+        for op in aln.operations.iter() {
+            match op {
+                AlignmentOperation::Match(_) | AlignmentOperation::Ins(_) => aligned_len += 1,
+                AlignmentOperation::Yclip(_, r) => aligned_len = *r,
+                _ => {}
+            }
+            if aligned_len > seq.len() {
+                break;
+            }
+        }
+        if aligned_len != seq.len() {
+            return Err(PoaError::AlignmentMismatch { sequence_len: seq.len(), aligned_len });
+        }
+        // nodes and edges are only appended here, new ones start at these indices
+        let (old_node_count, old_edge_count) = (self.graph.node_count(), self.graph.edge_count());
+        let head: NodeIndex<usize> = NodeIndex::new(self.topo_order[0]);
         let mut prev: NodeIndex<usize> = NodeIndex::new(head.index());
         let mut i: usize = 0;
         let mut edge_not_connected: bool = false;
+        // the nodes the sequence runs through and the edges it added weight to
+        let mut path_nodes: Vec<usize> = Vec::with_capacity(seq.len());
+        let mut path_edges: Vec<(usize, usize)> = Vec::with_capacity(seq.len());
         for op in aln.operations.iter() {
             match op {
                 AlignmentOperation::Match(None) => {
                     let node: NodeIndex<usize> = NodeIndex::new(head.index());
+                    let mut path_node = node;
                     if (seq[i] != self.graph.raw_nodes()[head.index()].weight) && (seq[i] != b'X') {
-                        let node = self.graph.add_node(seq[i]);
+                        let node = self.add_node_after(seq[i], prev);
                         if edge_not_connected {
-                            self.graph.add_edge(prev, node, 1);
+                            self.add_edge_ordered(prev, node, 1);
+                            path_edges.push((prev.index(), node.index()));
                         }
                         edge_not_connected = false;
                         prev = node;
+                        path_node = node;
                     }
                     if edge_not_connected {
-                        self.graph.add_edge(prev, node, 1);
+                        self.add_edge_ordered(prev, node, 1);
+                        path_edges.push((prev.index(), node.index()));
                         prev = node;
                         edge_not_connected = false;
                     }
+                    path_nodes.push(path_node.index());
                     i += 1;
                 }
                 AlignmentOperation::Match(Some((_, p))) => {
                     let node = NodeIndex::new(*p);
                     if (seq[i] != self.graph.raw_nodes()[*p].weight) && (seq[i] != b'X') {
-                        let node = self.graph.add_node(seq[i]);
-                        self.graph.add_edge(prev, node, 1);
+                        let node = self.add_node_after(seq[i], prev);
+                        self.add_edge_ordered(prev, node, 1);
+                        path_edges.push((prev.index(), node.index()));
                         prev = node;
                     } else {
                         // increment node weight
                         match self.graph.find_edge(prev, node) {
                             Some(edge) => {
                                 *self.graph.edge_weight_mut(edge).unwrap() += 1;
+                                path_edges.push((prev.index(), node.index()));
                             }
                             None => {
                                 if prev.index() != head.index() && prev.index() != node.index() {
-                                    self.graph.add_edge(prev, node, 1);
+                                    self.add_edge_ordered(prev, node, 1);
+                                    path_edges.push((prev.index(), node.index()));
                                 }
                             }
                         }
                         prev = NodeIndex::new(*p);
                     }
+                    path_nodes.push(prev.index());
                     i += 1;
                 }
                 AlignmentOperation::Ins(None) => {
-                    let node = self.graph.add_node(seq[i]);
+                    let node = self.add_node_after(seq[i], prev);
                     if edge_not_connected {
-                        self.graph.add_edge(prev, node, 1);
+                        self.add_edge_ordered(prev, node, 1);
+                        path_edges.push((prev.index(), node.index()));
                     }
                     prev = node;
                     edge_not_connected = true;
+                    path_nodes.push(node.index());
                     i += 1;
                 }
                 AlignmentOperation::Ins(Some(_)) => {
-                    let node = self.graph.add_node(seq[i]);
-                    self.graph.add_edge(prev, node, 1);
+                    let node = self.add_node_after(seq[i], prev);
+                    self.add_edge_ordered(prev, node, 1);
+                    path_edges.push((prev.index(), node.index()));
                     prev = node;
+                    path_nodes.push(node.index());
                     i += 1;
                 }
                 AlignmentOperation::Del(_) => {} // we should only have to skip over deleted nodes and xclip
@@ -1135,5 +1662,584 @@ impl Poa {
                 }
             }
         }
+        if self.sequences.len() <= seq_id {
+            self.sequences.resize(seq_id + 1, None);
+        }
+        self.sequences[seq_id] = Some(SequencePath { sequence: seq.to_vec(), orientation, nodes: path_nodes, edges: path_edges, truncated: false });
+        self.compact.rebuild(&self.graph, &self.topo_order);
+        self.drop_chainers();
+        if let Some(kmer_index) = &mut self.kmer_index {
+            let mut changed: Vec<usize> = (old_node_count..self.graph.node_count()).collect();
+            changed.extend(self.graph.raw_edges()[old_edge_count..].iter().map(|edge| edge.target().index()));
+            kmer_index.update(&self.graph, &self.topo_rank, &changed);
+        }
+        Ok(())
+    }
+
+    /// Keep a graph k-mer index up to date and use it for anchoring instead of the path index,
+    /// `max_variants` limits the k-mers ending at a single node
+    pub fn enable_kmer_index(&mut self, k: usize, max_variants: usize) -> Result<()> {
+        check_kmer_size(k)?;
+        self.kmer_index = Some(GraphKmerIndex::new(&self.graph, &self.topo_order, k, max_variants));
+        Ok(())
+    }
+
+    pub fn kmer_index(&self) -> Option<&GraphKmerIndex> {
+        self.kmer_index.as_ref()
+    }
+
+    /// Paths used to find k-mer anchors: the paths of the sequences when there are no more
+    /// than `path_limits.max_paths` of them, otherwise the heaviest paths of the graph with
+    /// `capped` set
+    pub fn path_index(&self) -> PathIndex {
+        let sequence_paths = self.sequences.iter().flatten().count();
+        if sequence_paths > self.path_limits.max_paths {
+            let index = heaviest_paths(&self.graph, &self.topo_order, &self.topo_rank, self.path_limits.max_paths);
+            return PathIndex { capped: true, ..index };
+        }
+        let mut index = PathIndex::default();
+        for path in self.sequences.iter().flatten() {
+            // the paths are given by rank and have to be strictly increasing
+            let mut ranks = vec![];
+            let mut bases = vec![];
+            for node in &path.nodes {
+                let rank = self.topo_rank[*node];
+                if ranks.last().is_none_or(|last| rank > *last) {
+                    ranks.push(rank);
+                    bases.push(self.graph.raw_nodes()[*node].weight);
+                }
+            }
+            index.paths.push(ranks);
+            index.sequences.push(bases);
+        }
+        index
+    }
+
+    /// Change which k-mers are matched when anchoring against the path index
+    pub fn set_seeding(&mut self, seeding: Seeding) {
+        self.seeding = seeding;
+    }
+
+    /// Change how anchors are chained, LCSk++ per read path or over a minimum path cover of the
+    /// graph, or with a gap cost
+    pub fn set_chaining(&mut self, chaining: Chaining) {
+        if chaining != self.chaining {
+            self.chainer = OnceLock::new();
+        }
+        self.chaining = chaining;
+    }
+
+    /// Minimum path cover of the graph used by `Chaining::PathCover`, paths as ranks
+    pub fn path_cover(&self) -> Vec<Vec<usize>> {
+        self.cover_chainer().cover().clone()
+    }
+
+    // the path cover chainer of the current graph, built on first use
+    fn cover_chainer(&self) -> Arc<PathCoverChainer> {
+        self.cover.get_or_init(|| Arc::new(PathCoverChainer::new(&self.compact))).clone()
+    }
+
+    // the cover and the chainer are only valid for the graph they were built from
+    fn drop_chainers(&mut self) {
+        self.cover = OnceLock::new();
+        self.chainer = OnceLock::new();
+    }
+
+    /// Change the caps on the number of paths used for anchoring
+    pub fn set_path_limits(&mut self, path_limits: PathLimits) {
+        self.path_limits = path_limits;
+    }
+
+    /// LCSk++ anchors of the query, from the graph k-mer index when it is enabled for this
+    /// k-mer size, otherwise against the paths given by `path_index`. The anchors are chained
+    /// as set by `set_chaining`, except that the index anchors are chained over the path cover
+    /// instead of per read path.
+    pub fn anchor_query(&self, query: &[u8], kmer_size: usize) -> Result<Vec<(usize, usize)>> {
+        Ok(self.anchor_query_scored(query, kmer_size)?.0)
+    }
+
+    /// Same as `anchor_query`, also returning the chain score
+    pub fn anchor_query_scored(&self, query: &[u8], kmer_size: usize) -> Result<(Vec<(usize, usize)>, u32)> {
+        let chain = self.anchor_chain(query, kmer_size)?;
+        Ok((chain.path, chain.score))
+    }
+
+    /// The chain `anchor_query` bands the alignment with
+    pub fn anchor_chain(&self, query: &[u8], kmer_size: usize) -> Result<Chain> {
+        check_match_kmer_size(kmer_size)?;
+        if let Some(kmer_index) = self.kmer_index.as_ref().filter(|kmer_index| kmer_index.k() == kmer_size) {
+            let anchors = AnchorSet::from_matches(kmer_index.find_kmer_matches(query, &self.graph, &self.topo_rank), kmer_size, 1);
+            // the index anchors are not on read paths, per path LCSk++ would chain anchors on
+            // parallel branches, so they go over the path cover instead
+            let chaining = if self.chaining == Chaining::PerPath { Chaining::PathCover } else { self.chaining };
+            return self.chainer(chaining).chain_anchors(&anchors);
+        }
+        self.anchorer(kmer_size).anchor(query)
+    }
+
+    /// Anchorer against the current graph: the path index, the seeding and the chaining
+    /// set on this graph. It does not follow later changes of the graph.
+    pub fn anchorer(&self, kmer_size: usize) -> Anchorer {
+        Anchorer::new(kmer_size, self.seeding, self.path_index(), self.topo_order.clone(), self.chainer(self.chaining))
+    }
+
+    // chainer for a chaining mode, the one of `set_chaining` is kept until the graph changes
+    fn chainer(&self, chaining: Chaining) -> SharedChainer {
+        if chaining != self.chaining {
+            return self.build_chainer(chaining);
+        }
+        self.chainer.get_or_init(|| self.build_chainer(chaining)).clone()
+    }
+
+    fn build_chainer(&self, chaining: Chaining) -> SharedChainer {
+        match chaining {
+            Chaining::PerPath => Arc::new(LcskChainer::new(self.topo_order.clone(), self.lcsk_pool.clone())),
+            Chaining::PathCover => self.cover_chainer(),
+            Chaining::GapCost(gap_cost) => Arc::new(GapCostChainer::new(&self.compact, gap_cost, self.cover_chainer())),
+        }
+    }
+
+    /// Find the strand of the query by anchoring it and its reverse complement, the one with
+    /// the higher LCSk++ score wins (forward on ties). Returns the orientation, the query in
+    /// that orientation and its anchors.
+    pub fn orient_query(&self, query: &[u8], kmer_size: usize) -> Result<(Orientation, Vec<u8>, Vec<(usize, usize)>)> {
+        let (forward_path, forward_score) = self.anchor_query_scored(query, kmer_size)?;
+        let reverse = reverse_complement(query);
+        let (reverse_path, reverse_score) = self.anchor_query_scored(&reverse, kmer_size)?;
+        if reverse_score > forward_score {
+            Ok((Orientation::Reverse, reverse, reverse_path))
+        } else {
+            Ok((Orientation::Forward, query.to_vec(), forward_path))
+        }
+    }
+
+    /// Globally align another graph to this one, see `graph_align::align_graphs`.
+    pub fn align_graph(&self, other: &Poa) -> GraphAlignment {
+        align_compact_graphs(&self.compact, &other.compact, self.match_score, self.mismatch_score, self.gap_open_score)
+    }
+
+    /// Merge another graph into this one by aligning every sequence of `other` onto this
+    /// graph with the banded SIMD kernel. Matched nodes are fused and every merged sequence
+    /// adds its weight to the edges it runs through, so the result is close to the graph
+    /// built from all the sequences at once.
+    ///
+    /// Returns the new sequence id of every sequence id of `other` (`None` if it was removed).
+    pub fn merge(&mut self, other: &Poa, kmer_size: usize, band_size: usize) -> Result<Vec<Option<usize>>> {
+        let mut workspace = AlignerWorkspace::new();
+        let mut new_ids = vec![];
+        for path in other.sequences.iter() {
+            let path = match path {
+                Some(path) => path,
+                None => {
+                    new_ids.push(None);
+                    continue;
+                }
+            };
+            let lcsk_path = self.anchor_query(&path.sequence, kmer_size)?;
+            let alignment = self.custom_simd_indirect_address_with_workspace(&path.sequence, &lcsk_path, band_size, &mut workspace);
+            new_ids.push(Some(self.add_alignment_oriented(&alignment, &path.sequence, path.orientation)?));
+        }
+        Ok(new_ids)
+    }
+
+    /// Return the path of the sequence with the given id, `None` if it was removed.
+    pub fn sequence_path(&self, seq_id: usize) -> Option<&SequencePath> {
+        self.sequences.get(seq_id).and_then(|path| path.as_ref())
+    }
+
+    /// Number of sequence ids handed out, including the ids of removed sequences.
+    pub fn sequence_count(&self) -> usize {
+        self.sequences.len()
+    }
+
+    /// Take the sequence with the given id out of the graph.
+    ///
+    /// The weight the sequence added to each edge is subtracted, edges left without
+    /// weight and nodes no other sequence runs through are deleted. Node indices are
+    /// compacted (the last node takes the place of a deleted one) and the stored paths
+    /// and the cached topological order are updated to match.
+    pub fn remove_sequence(&mut self, seq_id: usize) -> Option<SequencePath> {
+        let path = self.sequences.get_mut(seq_id)?.take()?;
+        for (from, to) in &path.edges {
+            if let Some(edge) = self.graph.find_edge(NodeIndex::new(*from), NodeIndex::new(*to)) {
+                let weight = self.graph.edge_weight_mut(edge).unwrap();
+                *weight -= 1;
+                if *weight <= 0 {
+                    self.graph.remove_edge(edge);
+                }
+            }
+        }
+        let mut support = vec![0; self.graph.node_count()];
+        for other in self.sequences.iter().flatten() {
+            for node in &other.nodes {
+                support[*node] += 1;
+            }
+        }
+        let mut orphans: Vec<usize> = path.nodes.iter().copied().filter(|node| support[*node] == 0).collect();
+        orphans.sort_unstable();
+        orphans.dedup();
+        self.remove_nodes(&orphans);
+        Some(path)
+    }
+
+    /// Prune low support parts of the graph.
+    ///
+    /// Bubbles are popped: a branch of at most `min_tip_len` nodes whose edges all weigh
+    /// less than `min_edge_weight` is deleted when it leaves a node with another successor
+    /// and rejoins a node with another predecessor (an edge skipping straight to such a
+    /// node is deleted on its own). Tips, chains of low weight edges shorter than
+    /// `min_tip_len` that start or end nowhere, are deleted as well. Edges the graph
+    /// can not do without are never deleted, so pruning does not split the graph.
+    /// Sequence paths that lose nodes or edges are marked as truncated.
+    ///
+    /// Returns the number of deleted nodes and edges.
+    pub fn prune(&mut self, min_edge_weight: i32, min_tip_len: usize) -> (usize, usize) {
+        let (node_count, edge_count) = (self.graph.node_count(), self.graph.edge_count());
+        let mut delete_nodes = vec![false; node_count];
+        // lightest edges first, every deletion is checked against the graph as it is then
+        let mut candidates: Vec<(i32, NodeIndex<usize>, NodeIndex<usize>)> = self.graph.raw_edges().iter().filter(|edge| edge.weight < min_edge_weight).map(|edge| (edge.weight, edge.source(), edge.target())).collect();
+        candidates.sort_by_key(|(weight, from, to)| (*weight, from.index(), to.index()));
+        for (_, from, to) in candidates {
+            let edge = match self.graph.find_edge(from, to) {
+                Some(edge) => edge,
+                None => continue,
+            };
+            if self.graph.neighbors_directed(from, Outgoing).count() < 2 {
+                continue;
+            }
+            if self.graph.neighbors_directed(to, Incoming).count() > 1 {
+                self.graph.remove_edge(edge);
+                continue;
+            }
+            // walk the branch until it rejoins the graph
+            let mut branch = vec![to];
+            let mut current = to;
+            while branch.len() <= min_tip_len {
+                let next = match self.low_weight_successor(current, min_edge_weight) {
+                    Some(next) => next,
+                    None => break,
+                };
+                if self.graph.neighbors_directed(next, Incoming).count() > 1 {
+                    // detach the branch, its nodes are deleted at the end
+                    self.graph.remove_edge(edge);
+                    for node in &branch {
+                        delete_nodes[node.index()] = true;
+                        while let Some(edge) = self.graph.first_edge(*node, Outgoing) {
+                            self.graph.remove_edge(edge);
+                        }
+                    }
+                    break;
+                }
+                branch.push(next);
+                current = next;
+            }
+        }
+        // a source tip runs forward to a node with other predecessors, a sink tip backward
+        for node in self.graph.node_indices() {
+            let (incoming, outgoing) = (self.graph.neighbors_directed(node, Incoming).count(), self.graph.neighbors_directed(node, Outgoing).count());
+            if incoming == 0 && outgoing == 1 {
+                self.mark_tip(node, Outgoing, min_edge_weight, min_tip_len, &mut delete_nodes);
+            }
+            if outgoing == 0 && incoming == 1 {
+                self.mark_tip(node, Incoming, min_edge_weight, min_tip_len, &mut delete_nodes);
+            }
+        }
+        for path in self.sequences.iter_mut().flatten() {
+            let edges = path.edges.len();
+            let graph = &self.graph;
+            path.edges.retain(|(from, to)| graph.find_edge(NodeIndex::new(*from), NodeIndex::new(*to)).is_some());
+            if path.edges.len() != edges {
+                path.truncated = true;
+            }
+        }
+        let nodes: Vec<usize> = (0..node_count).filter(|node| delete_nodes[*node]).collect();
+        // deleting edges keeps the order topological, remove_nodes rebuilds the compact graph
+        self.remove_nodes(&nodes);
+        (node_count - self.graph.node_count(), edge_count - self.graph.edge_count())
     }
-}
\ No newline at end of file
+
+    // the only successor of a unary node when the edge to it is below the weight limit
+    fn low_weight_successor(&self, node: NodeIndex<usize>, min_edge_weight: i32) -> Option<NodeIndex<usize>> {
+        let mut edges = self.graph.edges_directed(node, Outgoing);
+        match (edges.next(), edges.next()) {
+            (Some(edge), None) if *edge.weight() < min_edge_weight => Some(edge.target()),
+            _ => None,
+        }
+    }
+
+    // follow the chain of low weight edges from a source (or sink) and mark it when it joins
+    // a branching node within min_tip_len nodes
+    fn mark_tip(&self, start: NodeIndex<usize>, direction: Direction, min_edge_weight: i32, min_tip_len: usize, delete_nodes: &mut [bool]) {
+        let opposite = if direction == Outgoing { Incoming } else { Outgoing };
+        let mut chain = vec![start];
+        let mut current = start;
+        while chain.len() < min_tip_len {
+            let mut edges = self.graph.edges_directed(current, direction);
+            let next = match (edges.next(), edges.next()) {
+                (Some(edge), None) if *edge.weight() < min_edge_weight => if direction == Outgoing { edge.target() } else { edge.source() },
+                // fork or supported edge, not a tip
+                _ => return,
+            };
+            if self.graph.neighbors_directed(next, opposite).count() > 1 {
+                // joins the rest of the graph
+                for node in chain {
+                    delete_nodes[node.index()] = true;
+                }
+                return;
+            }
+            chain.push(next);
+            current = next;
+        }
+    }
+
+    // delete the given nodes, remapping the node indices moved by petgraph's swap removal
+    fn remove_nodes(&mut self, nodes: &[usize]) {
+        let node_count = self.graph.node_count();
+        // original index -> current index (usize::MAX when deleted) and its inverse
+        let mut position: Vec<usize> = (0..node_count).collect();
+        let mut occupant: Vec<usize> = (0..node_count).collect();
+        let mut current_count = node_count;
+        // largest first, so a node waiting to be deleted is never the one moved into a hole
+        for node in nodes.iter().rev() {
+            let last = current_count - 1;
+            self.graph.remove_node(NodeIndex::new(*node));
+            position[occupant[*node]] = usize::MAX;
+            if *node != last {
+                let moved = occupant[last];
+                occupant[*node] = moved;
+                position[moved] = *node;
+            }
+            current_count -= 1;
+        }
+        for path in self.sequences.iter_mut().flatten() {
+            let (node_count, edge_count) = (path.nodes.len(), path.edges.len());
+            path.nodes.retain(|node| position[*node] != usize::MAX);
+            for node in path.nodes.iter_mut() {
+                *node = position[*node];
+            }
+            path.edges.retain(|(from, to)| position[*from] != usize::MAX && position[*to] != usize::MAX);
+            // only pruning deletes nodes a sequence still runs through
+            if path.nodes.len() != node_count || path.edges.len() != edge_count {
+                path.truncated = true;
+            }
+            for (from, to) in path.edges.iter_mut() {
+                *from = position[*from];
+                *to = position[*to];
+            }
+        }
+        // removing nodes keeps the order topological
+        self.topo_order.retain(|node| position[*node] != usize::MAX);
+        for node in self.topo_order.iter_mut() {
+            *node = position[*node];
+        }
+        self.topo_rank = vec![0; self.topo_order.len()];
+        for (rank, node) in self.topo_order.iter().enumerate() {
+            self.topo_rank[*node] = rank;
+        }
+        self.compact.rebuild(&self.graph, &self.topo_order);
+        self.drop_chainers();
+        // node indices moved, the k-mer chains have to be found again
+        if let Some(kmer_index) = &mut self.kmer_index {
+            kmer_index.rebuild(&self.graph, &self.topo_order);
+        }
+    }
+
+    /// Return the consensus sequence generated from the POA graph.
+    pub fn consensus(&self) -> Vec<u8> {
+        let mut consensus: Vec<u8> = vec![];
+        let max_index = self.graph.node_count();
+        // without an edge there is no weight to follow
+        if max_index < 2 {
+            return consensus;
+        }
+        let mut weight_score_next_vec: Vec<(i32, i32, usize)> = vec![(0, 0, 0); max_index + 1];
+        let compact = &self.compact;
+        // go through the nodes topologically
+        for rank in 0..compact.node_count() {
+            let mut best_weight_score_next: (i32, i32, usize) = (0, 0, usize::MAX);
+            // go through the incoming neighbour nodes, parallel edges are already summed
+            for (neighbour_rank, weight) in compact.predecessors(rank).iter().zip(compact.predecessor_weights(rank)) {
+                let neighbour_index = compact.node_id(*neighbour_rank);
+                let neighbour_score = weight_score_next_vec[neighbour_index].1;
+                let weight = *weight;
+                let current_node_score = weight + neighbour_score;
+                // save the neighbour node with the highest weight and score as best
+                if (weight, current_node_score, neighbour_index) > best_weight_score_next {
+                    best_weight_score_next = (weight, current_node_score, neighbour_index);
+                }
+            }
+            weight_score_next_vec[compact.node_id(rank)] = best_weight_score_next;
+        }
+        // get the index of the max scored node (end of consensus)
+        let mut pos = weight_score_next_vec
+            .iter()
+            .enumerate()
+            .max_by_key(|(_, &value)| value.1)
+            .map(|(idx, _)| idx)
+            .unwrap();
+        // go through weight_score_next_vec appending to the consensus
+        while pos != usize::MAX {
+            consensus.push(self.graph.raw_nodes()[pos].weight);
+            pos = weight_score_next_vec[pos].2;
+        }
+        consensus.reverse();
+        consensus
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulate::get_random_sequences_from_generator;
+
+    fn random_reads(length: usize, count: usize, seed: usize) -> Vec<Vec<u8>> {
+        get_random_sequences_from_generator(length, count, seed).into_iter().map(|seq| seq.into_bytes()).collect()
+    }
+
+    // the cached order covers every node once, every edge goes forward in it and the
+    // compact graph is the one of the current graph
+    fn assert_topological(poa: &Poa) {
+        let n = poa.graph.node_count();
+        assert_eq!(poa.topo_order.len(), n);
+        assert_eq!(poa.topo_rank.len(), n);
+        for (rank, node) in poa.topo_order.iter().enumerate() {
+            assert_eq!(poa.topo_rank[*node], rank);
+        }
+        for edge in poa.graph.raw_edges() {
+            assert!(poa.topo_rank[edge.source().index()] < poa.topo_rank[edge.target().index()]);
+        }
+        let compact = CompactPoaGraph::from_poa_graph(&poa.graph, &poa.topo_order);
+        for rank in 0..n {
+            assert_eq!(poa.compact.predecessors(rank), compact.predecessors(rank));
+            assert_eq!(poa.compact.predecessor_weights(rank), compact.predecessor_weights(rank));
+        }
+    }
+
+    #[test]
+    fn from_reads_skips_reads_it_can_not_add() {
+        // some banded alignments of these batches are rejected by the graph
+        for seed in 0..40 {
+            let reads = random_reads(80, 8, seed);
+            let aligner = Aligner::from_reads(1, -1, -2, &reads, ReadOrder::Input, 4, 10).unwrap();
+            let (_, order) = aligner.read_order();
+            assert_eq!(order.len() + aligner.rejected_reads().len(), reads.len());
+            assert_eq!(aligner.poa.sequence_count(), order.len());
+        }
+        let mut reads = random_reads(80, 6, 0);
+        reads.insert(0, vec![]);
+        reads.insert(3, b"ACGTN".to_vec());
+        let aligner = Aligner::from_reads(1, -1, -2, &reads, ReadOrder::Input, 4, 10).unwrap();
+        assert_eq!(aligner.rejected_reads(), &vec![0, 3]);
+        assert_eq!(aligner.read_order().1, &vec![1, 2, 4, 5, 6, 7]);
+    }
+
+    #[test]
+    fn one_base_query() {
+        let reads = random_reads(30, 3, 0);
+        let aligner = Aligner::from_reads(1, -1, -2, &reads, ReadOrder::Input, 4, 10).unwrap();
+        for base in *b"ACGT" {
+            let query = vec![base];
+            let lcsk_path = aligner.poa.anchor_query(&query, 4).unwrap();
+            let check = crate::kernel_check::check_kernels(&aligner.poa, &query, &lcsk_path, 10).unwrap();
+            assert_eq!((check.simd_score, check.banded_score), (check.scalar_score, check.scalar_score));
+            assert!(check.add_errors.is_empty(), "{:?}", check.add_errors);
+        }
+    }
+
+    #[test]
+    fn kmer_sizes_above_packing() {
+        // k-mers longer than a packed key are matched as slices, only k 0 is rejected
+        let reads = random_reads(200, 4, 1);
+        let aligner = Aligner::from_reads(1, -1, -2, &reads, ReadOrder::BestBackbone, 40, 10).unwrap();
+        assert!(aligner.rejected_reads().is_empty());
+        assert!(!aligner.poa.anchor_query(&reads[0], 40).unwrap().is_empty());
+        assert!(matches!(aligner.poa.anchor_query(&reads[0], 0), Err(PoaError::InvalidKmerSize { k: 0, max: None })));
+        let mut poa = Poa::from_string(1, -1, -2, &reads[0]).unwrap();
+        assert!(matches!(poa.enable_kmer_index(40, 4), Err(PoaError::InvalidKmerSize { k: 40, max: Some(32) })));
+        assert!(Aligner::from_reads(1, -1, -2, &reads, ReadOrder::GuideTree, 40, 10).is_err());
+    }
+
+    #[test]
+    fn consensus_of_tiny_graphs() {
+        let mut poa = Poa::from_string(1, -1, -2, &b"A".to_vec()).unwrap();
+        assert!(poa.consensus().is_empty());
+        poa.remove_sequence(0).unwrap();
+        assert_eq!(poa.graph.node_count(), 0);
+        assert!(poa.consensus().is_empty());
+        assert_eq!(Poa::from_string(1, -1, -2, &b"AC".to_vec()).unwrap().consensus(), b"AC".to_vec());
+    }
+
+    #[test]
+    fn refine_keeps_every_read() {
+        for seed in 0..3 {
+            let reads = random_reads(100, 10, seed);
+            let mut aligner = Aligner::from_reads(1, -1, -2, &reads, ReadOrder::Input, 4, 10).unwrap();
+            let passes = aligner.refine(4, 10, 5).unwrap();
+            assert!((1..=5).contains(&passes));
+            assert_topological(&aligner.poa);
+            let (_, order) = aligner.read_order();
+            assert_eq!(aligner.poa.sequence_count(), order.len());
+            for (seq_id, read) in order.iter().enumerate() {
+                let path = aligner.poa.sequence_path(seq_id).unwrap();
+                assert_eq!(&path.sequence, &reads[*read]);
+                assert!(path.nodes.iter().all(|node| *node < aligner.graph().node_count()));
+            }
+        }
+    }
+
+    #[test]
+    fn remove_restores_the_graph() {
+        // bases by node index and the weighted edges, sorted
+        fn snapshot(poa: &Poa) -> (Vec<u8>, Vec<(usize, usize, i32)>) {
+            let bases = poa.graph.raw_nodes().iter().map(|node| node.weight).collect();
+            let mut edges: Vec<(usize, usize, i32)> = poa.graph.raw_edges().iter().map(|edge| (edge.source().index(), edge.target().index(), edge.weight)).collect();
+            edges.sort_unstable();
+            (bases, edges)
+        }
+        for seed in 0..5 {
+            let reads = random_reads(100, 8, seed);
+            let mut poa = Poa::from_string(1, -1, -2, &reads[0]).unwrap();
+            for read in &reads[1..7] {
+                let lcsk_path = poa.anchor_query(read, 4).unwrap();
+                let alignment = poa.custom_simd_indirect_address(read, &lcsk_path, 10);
+                poa.add_alignment(&alignment, read).unwrap();
+            }
+            let before = snapshot(&poa);
+            let consensus = poa.consensus();
+            let lcsk_path = poa.anchor_query(&reads[7], 4).unwrap();
+            let alignment = poa.custom_simd_indirect_address(&reads[7], &lcsk_path, 10);
+            poa.add_alignment(&alignment, &reads[7]).unwrap();
+            let seq_id = poa.sequence_count() - 1;
+            assert_eq!(poa.remove_sequence(seq_id).unwrap().sequence, reads[7]);
+            assert_eq!(snapshot(&poa), before);
+            assert_eq!(poa.consensus(), consensus);
+            assert!(poa.sequence_path(seq_id).is_none());
+            assert_topological(&poa);
+        }
+    }
+
+    #[test]
+    fn prune_keeps_the_graph_ordered_and_whole() {
+        let mut deleted = 0;
+        for seed in 0..5 {
+            let reads = random_reads(100, 10, seed);
+            let mut aligner = Aligner::new(1, -1, -2, &reads[0]).unwrap();
+            aligner.set_pruning(Some(Pruning { every_reads: 3, min_edge_weight: 2, min_tip_len: 5 }));
+            for read in &reads[1..] {
+                // a rejected alignment leaves the graph as it was
+                let _ = aligner.align_with_anchors(read);
+                assert_topological(&aligner.poa);
+            }
+            let (node_count, edge_count) = (aligner.graph().node_count(), aligner.graph().edge_count());
+            let components = petgraph::algo::connected_components(aligner.graph());
+            let (nodes, edges) = aligner.prune(3, 5);
+            assert_eq!((node_count - nodes, edge_count - edges), (aligner.graph().node_count(), aligner.graph().edge_count()));
+            assert!(aligner.graph().raw_edges().iter().all(|edge| edge.weight > 0));
+            // pruning never splits the graph
+            assert_eq!(petgraph::algo::connected_components(aligner.graph()), components);
+            assert_topological(&aligner.poa);
+            deleted += nodes;
+        }
+        assert!(deleted > 0);
+    }
+}
@@ -0,0 +1,192 @@
+//! Strategies for the order in which reads are added to the POA graph
+//!
+//! The graph quality depends on the read order, an early noisy read ends up as the
+//! backbone every later read is aligned to.
+use crate::bit_tree::MaxBitTreePool;
+use crate::lcsk::{better_find_kmer_matches, lcskpp_graph_pooled};
+use crate::packed_kmer::{check_kmer_size, check_match_kmer_size, PackedKmerIndex};
+use crate::error::Result;
+use std::cmp::Ordering;
+use std::fmt;
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum ReadOrder {
+    /// Reads are added in the order they were given
+    #[default]
+    Input,
+    /// Longest read first, ties keep the input order
+    LongestFirst,
+    /// Progressive order from a UPGMA guide tree built on k-mer similarity
+    GuideTree,
+    /// Longest read as the seed, then the rest by decreasing LCSk score against the seed
+    BestBackbone,
+}
+
+impl fmt::Display for ReadOrder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            ReadOrder::Input => "input",
+            ReadOrder::LongestFirst => "longest-first",
+            ReadOrder::GuideTree => "guide-tree",
+            ReadOrder::BestBackbone => "best-backbone",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl ReadOrder {
+    pub fn from_name(name: &str) -> Option<ReadOrder> {
+        match name {
+            "input" => Some(ReadOrder::Input),
+            "longest-first" => Some(ReadOrder::LongestFirst),
+            "guide-tree" => Some(ReadOrder::GuideTree),
+            "best-backbone" => Some(ReadOrder::BestBackbone),
+            _ => None,
+        }
+    }
+}
+
+/// Return the indices of `reads` in the order they should be added to the graph
+pub fn order_reads(reads: &[Vec<u8>], read_order: ReadOrder, k: usize) -> Result<Vec<usize>> {
+    let mut order: Vec<usize> = (0..reads.len()).collect();
+    if reads.len() < 2 {
+        return Ok(order);
+    }
+    match read_order {
+        ReadOrder::Input => {},
+        ReadOrder::LongestFirst => {
+            // stable sort keeps the input order for equal lengths
+            order.sort_by_key(|index| std::cmp::Reverse(reads[*index].len()));
+        },
+        ReadOrder::GuideTree => {
+            check_kmer_size(k)?;
+            order = guide_tree_order(reads, k);
+        },
+        ReadOrder::BestBackbone => {
+            check_match_kmer_size(k)?;
+            order = best_backbone_order(reads, k)?;
+        },
+    }
+    Ok(order)
+}
+
+// fraction of the distinct k-mers of the smaller read that are shared with the other read
+fn kmer_similarities(reads: &[Vec<u8>], k: usize) -> Vec<Vec<f64>> {
+    let kmer_sets: Vec<_> = reads.iter().map(|read| PackedKmerIndex::new(read, k, false)).collect();
+    let mut similarities = vec![vec![1.0; reads.len()]; reads.len()];
+    for a in 0..reads.len() {
+        for b in (a + 1)..reads.len() {
+            let (smaller, larger) = if kmer_sets[a].len() <= kmer_sets[b].len() { (&kmer_sets[a], &kmer_sets[b]) } else { (&kmer_sets[b], &kmer_sets[a]) };
+            let shared = smaller.keys().filter(|kmer| larger.get(**kmer).is_some()).count();
+            let similarity = if smaller.is_empty() { 0.0 } else { shared as f64 / smaller.len() as f64 };
+            similarities[a][b] = similarity;
+            similarities[b][a] = similarity;
+        }
+    }
+    similarities
+}
+
+// UPGMA on 1 - similarity, the leaves are then read out depth first taking the
+// tighter (lower merge height) subtree first so the most similar reads form the backbone
+fn guide_tree_order(reads: &[Vec<u8>], k: usize) -> Vec<usize> {
+    let similarities = kmer_similarities(reads, k);
+    // tree nodes, leaves are 0..n and internal nodes (left, right, height) come after
+    let mut children: Vec<Option<(usize, usize)>> = vec![None; reads.len()];
+    let mut heights: Vec<f64> = vec![0.0; reads.len()];
+    // active clusters as (tree node, members)
+    let mut clusters: Vec<(usize, Vec<usize>)> = (0..reads.len()).map(|index| (index, vec![index])).collect();
+    while clusters.len() > 1 {
+        let mut best = (f64::MAX, 0, 1);
+        for a in 0..clusters.len() {
+            for b in (a + 1)..clusters.len() {
+                let mut distance = 0.0;
+                for x in &clusters[a].1 {
+                    for y in &clusters[b].1 {
+                        distance += 1.0 - similarities[*x][*y];
+                    }
+                }
+                distance /= (clusters[a].1.len() * clusters[b].1.len()) as f64;
+                if distance < best.0 {
+                    best = (distance, a, b);
+                }
+            }
+        }
+        let (distance, a, b) = best;
+        let (right_node, right_members) = clusters.swap_remove(b);
+        let (left_node, mut members) = clusters.swap_remove(a);
+        members.extend(right_members);
+        children.push(Some((left_node, right_node)));
+        heights.push(distance);
+        clusters.push((children.len() - 1, members));
+    }
+    let mut order = vec![];
+    let mut stack = vec![clusters[0].0];
+    while let Some(node) = stack.pop() {
+        match children[node] {
+            None => order.push(node),
+            Some((left, right)) => {
+                let (first, second) = match heights[left].partial_cmp(&heights[right]) {
+                    Some(Ordering::Greater) => (right, left),
+                    _ => (left, right),
+                };
+                stack.push(second);
+                stack.push(first);
+            }
+        }
+    }
+    order
+}
+
+fn best_backbone_order(reads: &[Vec<u8>], k: usize) -> Result<Vec<usize>> {
+    // the longest read is the seed (first one on ties)
+    let seed = (0..reads.len()).fold(0, |best, index| if reads[index].len() > reads[best].len() { index } else { best });
+    // the seed as a linear graph, path positions are the topological indices
+    let seed_sequences = vec![reads[seed].clone()];
+    let seed_paths = vec![(0..reads[seed].len()).collect::<Vec<usize>>()];
+    let topo_indices: Vec<usize> = (0..reads[seed].len()).collect();
+    let mut scored: Vec<(u32, usize)> = vec![];
+    let mut pool = MaxBitTreePool::new();
+    for (index, read) in reads.iter().enumerate() {
+        if index == seed {
+            continue;
+        }
+        let (kmer_pos_vec, kmer_path_vec, kmers_previous_node_in_paths, kmer_graph_path) = better_find_kmer_matches(read, &seed_sequences, &seed_paths, k);
+        let (_, _, score) = lcskpp_graph_pooled(kmer_pos_vec, kmer_path_vec, kmers_previous_node_in_paths, 1, k, kmer_graph_path, &topo_indices, &mut pool)?;
+        scored.push((score, index));
+    }
+    // highest score first, ties keep the input order
+    scored.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+    let mut order = vec![seed];
+    order.extend(scored.into_iter().map(|(_, index)| index));
+    Ok(order)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::poa::Aligner;
+    use crate::simulate::get_random_sequences_from_generator;
+
+    #[test]
+    fn orders_are_permutations() {
+        let mut reads: Vec<Vec<u8>> = get_random_sequences_from_generator(120, 8, 0).into_iter().map(|seq| seq.into_bytes()).collect();
+        // lengths 120, 115, ... with two reads of the same length
+        for (index, read) in reads.iter_mut().enumerate() {
+            read.truncate(120 - 5 * index.min(6));
+        }
+        reads.swap(0, 5);
+        for read_order in [ReadOrder::Input, ReadOrder::LongestFirst, ReadOrder::GuideTree, ReadOrder::BestBackbone] {
+            let order = order_reads(&reads, read_order, 4).unwrap();
+            let mut sorted = order.clone();
+            sorted.sort_unstable();
+            assert_eq!(sorted, (0..reads.len()).collect::<Vec<usize>>(), "{}", read_order);
+            assert_eq!(ReadOrder::from_name(&read_order.to_string()), Some(read_order));
+            let aligner = Aligner::from_reads(1, -1, -2, &reads, read_order, 4, 10).unwrap();
+            assert_eq!(aligner.read_order(), (read_order, &order));
+        }
+        assert_eq!(order_reads(&reads, ReadOrder::Input, 4).unwrap(), vec![0, 1, 2, 3, 4, 5, 6, 7]);
+        // stable, the two shortest reads keep their input order
+        assert_eq!(order_reads(&reads, ReadOrder::LongestFirst, 4).unwrap(), vec![5, 1, 2, 3, 4, 0, 6, 7]);
+        assert_eq!(order_reads(&reads, ReadOrder::BestBackbone, 4).unwrap()[0], 5);
+    }
+}
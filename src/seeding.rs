@@ -0,0 +1,77 @@
+//! Seed selection for k-mer anchoring
+//!
+//! Indexing every k-mer of a long read is costly and with a small k most of the hits are
+//! noise. Minimizers keep the k-mer with the smallest hash in every window of `w`
+//! consecutive k-mers, syncmers keep a k-mer when its smallest s-mer starts at a fixed
+//! offset. Both pick the same k-mers in two sequences where they share enough context,
+//! so only the selected positions of the query and of the graph sequences are matched.
+use std::fmt;
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Seeding {
+    /// Every k-mer
+    #[default]
+    Exhaustive,
+    /// (w, k) minimizers, the smallest k-mer of every w consecutive k-mers
+    Minimizer { w: usize },
+    /// k-mers whose smallest s-mer starts at `offset` (0 gives open syncmers)
+    Syncmer { s: usize, offset: usize },
+}
+
+impl fmt::Display for Seeding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Seeding::Exhaustive => write!(f, "exhaustive"),
+            Seeding::Minimizer { w } => write!(f, "minimizer w={}", w),
+            Seeding::Syncmer { s, offset } => write!(f, "syncmer s={} offset={}", s, offset),
+        }
+    }
+}
+
+// order of the k-mers, a hash so runs of low bases are not always picked
+fn seed_hash(kmer: &[u8]) -> u64 {
+    fxhash::hash64(kmer)
+}
+
+/// Start positions of the selected k-mers of `seq`, in increasing order
+pub fn seed_positions(seq: &[u8], k: usize, seeding: Seeding) -> Vec<u32> {
+    if k == 0 || seq.len() < k {
+        return vec![];
+    }
+    let kmer_count = seq.len() - k + 1;
+    match seeding {
+        Seeding::Exhaustive => (0..kmer_count as u32).collect(),
+        Seeding::Minimizer { w } => {
+            let w = w.max(1);
+            let hashes: Vec<u64> = seq.windows(k).map(seed_hash).collect();
+            let mut positions: Vec<u32> = vec![];
+            // a window shorter than w at the end of short sequences still gets one minimizer
+            for start in 0..kmer_count.saturating_sub(w - 1).max(1) {
+                let end = (start + w).min(kmer_count);
+                // leftmost smallest hash
+                let mut best = start;
+                for position in start + 1..end {
+                    if hashes[position] < hashes[best] {
+                        best = position;
+                    }
+                }
+                // the minimizer of the next window is never to the left of this one
+                if positions.last() != Some(&(best as u32)) {
+                    positions.push(best as u32);
+                }
+            }
+            positions
+        }
+        Seeding::Syncmer { s, offset } => {
+            let s = s.clamp(1, k);
+            let smer_hashes: Vec<u64> = seq.windows(s).map(seed_hash).collect();
+            let smers_per_kmer = k - s + 1;
+            (0..kmer_count).filter(|start| {
+                let smers = &smer_hashes[*start..*start + smers_per_kmer];
+                // leftmost smallest s-mer
+                let smallest = (0..smers.len()).fold(0, |best, index| if smers[index] < smers[best] { index } else { best });
+                smallest == offset
+            }).map(|start| start as u32).collect()
+        }
+    }
+}
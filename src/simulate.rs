@@ -0,0 +1,404 @@
+//! Read simulation with a known truth
+//!
+//! `simulate` draws a random ancestral sequence and samples reads from it with an
+//! `ErrorProfile`, optionally from the reverse strand or joined from two pieces (chimeras).
+//! Every read keeps the pieces of the truth it came from and its true alignment to the
+//! truth, so a consensus or an alignment can be scored against what really happened.
+//! `get_random_sequences_from_generator` is the older generator the demos were written
+//! with: 5% substitutions and indels that only repeat the previous base, without truth.
+use rand::{Rng, SeedableRng, rngs::StdRng};
+use crate::lcsk::{reverse_complement, Orientation};
+
+const BASES: [u8; 4] = *b"ACGT";
+
+/// Per base error rates of a sequencing technology
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ErrorProfile {
+    pub substitution: f64,
+    pub insertion: f64,
+    pub deletion: f64,
+    /// mean length of an insertion or deletion, the lengths are geometric
+    pub mean_indel_length: f64,
+    /// factor on the indel rates inside a homopolymer run, insertions there extend the run
+    pub homopolymer_bias: f64,
+}
+
+impl ErrorProfile {
+    /// Single base errors at the given rates, homopolymers are not special
+    pub fn uniform(substitution: f64, insertion: f64, deletion: f64) -> ErrorProfile {
+        ErrorProfile { substitution, insertion, deletion, mean_indel_length: 1.0, homopolymer_bias: 1.0 }
+    }
+
+    /// Nanopore like: about 9% errors, mostly deletions and insertions in homopolymers
+    pub fn ont() -> ErrorProfile {
+        ErrorProfile { substitution: 0.02, insertion: 0.01, deletion: 0.015, mean_indel_length: 1.5, homopolymer_bias: 3.0 }
+    }
+
+    /// PacBio CLR like: about 11% errors, most of them insertions
+    pub fn pacbio_clr() -> ErrorProfile {
+        ErrorProfile { substitution: 0.01, insertion: 0.05, deletion: 0.02, mean_indel_length: 1.2, homopolymer_bias: 1.5 }
+    }
+
+    /// PacBio HiFi like: well under 1% errors, what is left sits in homopolymers
+    pub fn pacbio_hifi() -> ErrorProfile {
+        ErrorProfile { substitution: 0.001, insertion: 0.0005, deletion: 0.001, mean_indel_length: 1.0, homopolymer_bias: 4.0 }
+    }
+
+    pub fn from_name(name: &str) -> Option<ErrorProfile> {
+        match name {
+            "ont" => Some(ErrorProfile::ont()),
+            "clr" => Some(ErrorProfile::pacbio_clr()),
+            "hifi" => Some(ErrorProfile::pacbio_hifi()),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct SimulationConfig {
+    pub length: usize, // of the truth, non chimeric reads cover all of it
+    pub reads: usize,
+    pub profile: ErrorProfile,
+    pub reverse_fraction: f64, // chance a read (or a chimera piece) comes from the reverse strand
+    pub chimera_fraction: f64, // chance a read is joined from two pieces of the truth
+    pub seed: u64,
+}
+
+/// One column of a read's true alignment to the truth, positions are on the forward strand of
+/// the truth and in the read as it is given out
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TruthOp {
+    Match(usize, usize), // (truth position, read position)
+    Mismatch(usize, usize),
+    Ins(usize), // read position
+    Del(usize), // truth position
+}
+
+/// A piece of the truth a read came from, truth_start..truth_end became read_start..read_end
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Segment {
+    pub truth_start: usize,
+    pub truth_end: usize,
+    pub read_start: usize,
+    pub read_end: usize,
+    pub orientation: Orientation,
+}
+
+#[derive(Clone, Debug)]
+pub struct SimulatedRead {
+    pub seq: Vec<u8>,
+    pub segments: Vec<Segment>,
+    pub alignment: Vec<TruthOp>, // in read order
+}
+
+impl SimulatedRead {
+    pub fn is_chimeric(&self) -> bool {
+        self.segments.len() > 1
+    }
+
+    /// Orientation of the (first) piece of the truth the read came from
+    pub fn orientation(&self) -> Orientation {
+        self.segments[0].orientation
+    }
+
+    /// (substituted, inserted, deleted) bases
+    pub fn error_counts(&self) -> (usize, usize, usize) {
+        let mut counts = (0, 0, 0);
+        for op in &self.alignment {
+            match op {
+                TruthOp::Match(_, _) => {},
+                TruthOp::Mismatch(_, _) => counts.0 += 1,
+                TruthOp::Ins(_) => counts.1 += 1,
+                TruthOp::Del(_) => counts.2 += 1,
+            }
+        }
+        counts
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Simulation {
+    pub truth: Vec<u8>,
+    pub reads: Vec<SimulatedRead>,
+}
+
+impl Simulation {
+    pub fn sequences(&self) -> Vec<Vec<u8>> {
+        self.reads.iter().map(|read| read.seq.clone()).collect()
+    }
+
+    /// Identity of a consensus to the truth, see `identity`
+    pub fn identity(&self, consensus: &[u8]) -> f64 {
+        identity(consensus, &self.truth)
+    }
+}
+
+/// 1 - edit distance / truth length of a consensus, on the strand of the truth it matches best
+pub fn identity(consensus: &[u8], truth: &[u8]) -> f64 {
+    let distance = edit_distance(consensus, truth).min(edit_distance(&reverse_complement(consensus), truth));
+    1.0 - distance as f64 / truth.len().max(1) as f64
+}
+
+/// Draw the truth and the reads of a simulation, the same config gives the same simulation
+pub fn simulate(config: &SimulationConfig) -> Simulation {
+    let mut rng = StdRng::seed_from_u64(config.seed);
+    let truth: Vec<u8> = (0..config.length).map(|_| BASES[rng.gen_range(0..4)]).collect();
+    let mut reads = vec![];
+    for _ in 0..config.reads {
+        let pieces = if config.length >= 4 && rng.gen::<f64>() < config.chimera_fraction {
+            // two pieces that together are as long as the truth
+            let first_len = rng.gen_range(config.length / 4..=config.length * 3 / 4);
+            let first_start = rng.gen_range(0..=config.length - first_len);
+            let second_start = rng.gen_range(0..=first_len);
+            vec![(first_start, first_start + first_len), (second_start, second_start + config.length - first_len)]
+        } else {
+            vec![(0, config.length)]
+        };
+        let mut read = SimulatedRead { seq: vec![], segments: vec![], alignment: vec![] };
+        for (truth_start, truth_end) in pieces {
+            let orientation = if rng.gen::<f64>() < config.reverse_fraction { Orientation::Reverse } else { Orientation::Forward };
+            let (mut seq, mut alignment) = mutate(&truth, truth_start, truth_end, &config.profile, &mut rng);
+            if orientation == Orientation::Reverse {
+                seq = reverse_complement(&seq);
+                alignment = reverse_alignment(&alignment, seq.len());
+            }
+            let read_start = read.seq.len();
+            read.alignment.extend(alignment.into_iter().map(|op| shift_read_position(op, read_start)));
+            read.seq.extend(seq);
+            read.segments.push(Segment { truth_start, truth_end, read_start, read_end: read.seq.len(), orientation });
+        }
+        reads.push(read);
+    }
+    Simulation { truth, reads }
+}
+
+// errors drawn base by base over truth[start..end], the read and its true alignment
+fn mutate(truth: &Vec<u8>, start: usize, end: usize, profile: &ErrorProfile, rng: &mut StdRng) -> (Vec<u8>, Vec<TruthOp>) {
+    let mut seq = vec![];
+    let mut alignment = vec![];
+    let mut i = start;
+    while i < end {
+        let base = truth[i];
+        let in_run = (i > 0 && truth[i - 1] == base) || (i + 1 < truth.len() && truth[i + 1] == base);
+        let bias = if in_run { profile.homopolymer_bias } else { 1.0 };
+        if rng.gen::<f64>() < profile.deletion * bias {
+            let length = indel_length(profile.mean_indel_length, rng).min(end - i);
+            alignment.extend((i..i + length).map(TruthOp::Del));
+            i += length;
+            continue;
+        }
+        if rng.gen::<f64>() < profile.substitution {
+            let other = BASES.iter().copied().filter(|other| *other != base).nth(rng.gen_range(0..3)).unwrap();
+            alignment.push(TruthOp::Mismatch(i, seq.len()));
+            seq.push(other);
+        } else {
+            alignment.push(TruthOp::Match(i, seq.len()));
+            seq.push(base);
+        }
+        if rng.gen::<f64>() < profile.insertion * bias {
+            for _ in 0..indel_length(profile.mean_indel_length, rng) {
+                alignment.push(TruthOp::Ins(seq.len()));
+                seq.push(if in_run { base } else { BASES[rng.gen_range(0..4)] });
+            }
+        }
+        i += 1;
+    }
+    (seq, alignment)
+}
+
+// geometric on 1, 2, ... with the given mean
+fn indel_length(mean: f64, rng: &mut StdRng) -> usize {
+    let stop = 1.0 / mean.max(1.0);
+    let mut length = 1;
+    while rng.gen::<f64>() >= stop {
+        length += 1;
+    }
+    length
+}
+
+// alignment of the reverse complemented read, the truth positions stay on the forward strand
+fn reverse_alignment(alignment: &Vec<TruthOp>, read_len: usize) -> Vec<TruthOp> {
+    alignment.iter().rev().map(|op| match *op {
+        TruthOp::Match(truth_pos, read_pos) => TruthOp::Match(truth_pos, read_len - 1 - read_pos),
+        TruthOp::Mismatch(truth_pos, read_pos) => TruthOp::Mismatch(truth_pos, read_len - 1 - read_pos),
+        TruthOp::Ins(read_pos) => TruthOp::Ins(read_len - 1 - read_pos),
+        TruthOp::Del(truth_pos) => TruthOp::Del(truth_pos),
+    }).collect()
+}
+
+fn shift_read_position(op: TruthOp, offset: usize) -> TruthOp {
+    match op {
+        TruthOp::Match(truth_pos, read_pos) => TruthOp::Match(truth_pos, read_pos + offset),
+        TruthOp::Mismatch(truth_pos, read_pos) => TruthOp::Mismatch(truth_pos, read_pos + offset),
+        TruthOp::Ins(read_pos) => TruthOp::Ins(read_pos + offset),
+        TruthOp::Del(truth_pos) => TruthOp::Del(truth_pos),
+    }
+}
+
+/// Unit cost edit distance, two rows
+pub fn edit_distance(a: &[u8], b: &[u8]) -> usize {
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    let mut current = vec![0; b.len() + 1];
+    for i in 1..=a.len() {
+        current[0] = i;
+        for j in 1..=b.len() {
+            let diagonal = previous[j - 1] + (a[i - 1] != b[j - 1]) as usize;
+            current[j] = diagonal.min(previous[j] + 1).min(current[j - 1] + 1);
+        }
+        std::mem::swap(&mut previous, &mut current);
+    }
+    previous[b.len()]
+}
+
+pub fn get_random_sequences_from_generator(sequence_length: usize, num_of_sequences: usize, seed: usize) -> Vec<String> {
+    let mut rng = StdRng::seed_from_u64(seed as u64);
+    //generate the first sequence of random bases of length sequence_length
+    let mut firstseq: Vec<char> = vec![];
+    for _ in 0..sequence_length {
+        firstseq.push(match rng.gen_range(0..4) {
+            0 => 'A',
+            1 => 'C',
+            2 => 'G',
+            3 => 'T',
+            _ => 'X'
+        });
+    }
+    //randomvec.push(firstseq.iter().collect::<String>());
+    mutated_copies(&firstseq, num_of_sequences, &mut rng)
+}
+
+// same as get_random_sequences_from_generator, with the first repeat_length bases of the
+// template copied over it copies - 1 more times, evenly spaced
+pub fn get_repetitive_sequences_from_generator(sequence_length: usize, num_of_sequences: usize, seed: usize, repeat_length: usize, copies: usize) -> Vec<String> {
+    let mut rng = StdRng::seed_from_u64(seed as u64);
+    let mut firstseq: Vec<char> = (0..sequence_length).map(|_| ['A', 'C', 'G', 'T'][rng.gen_range(0..4)]).collect();
+    let spacing = sequence_length / copies;
+    for copy in 1..copies {
+        for i in 0..repeat_length {
+            firstseq[copy * spacing + i] = firstseq[i];
+        }
+    }
+    mutated_copies(&firstseq, num_of_sequences, &mut rng)
+}
+
+// reads with substitutions and geometric indels from the template
+fn mutated_copies(firstseq: &Vec<char>, num_of_sequences: usize, rng: &mut StdRng) -> Vec<String> {
+    //vector to save all the sequences 
+    let mut randomvec: Vec<String> = vec![];
+    //loop for 10 
+    for _ in 0..num_of_sequences {
+        //clone the sequence
+        let mut mutseq = firstseq.clone();
+        //mutate the all the bases with 0.05 chance
+        for i in 0..mutseq.len() {
+            match rng.gen_range(0..20) {
+                0 => {
+                    mutseq[i] = match rng.gen_range(0..4) {
+                        0 => 'A',
+                        1 => 'C',
+                        2 => 'G',
+                        3 => 'T',
+                        _ => 'X'
+                    }
+                },
+                _ => {}
+            }
+        }
+        //put indels at location with chance 0.1 
+        for i in 0..mutseq.len() {
+            let mean_value: f64 = 1.5; //2.0 before
+            //get length of the indel geometric distributed mean value 1.5
+            let indel_length: usize  = ((1.0 - rng.gen::<f64>()).ln() / (1.00 - (1.00 / mean_value) as f64).ln()).ceil() as usize;
+            match rng.gen_range(0..20) {
+                //insertion of elements
+                0 => {
+                    if i + indel_length < mutseq.len() {
+                        for _ in 0..indel_length{
+                            mutseq.insert(i + 1, mutseq[i]);
+                        }
+                    }
+                },
+                //deletion of elements
+                1 => {
+                    if i + indel_length < mutseq.len() {
+                        for _ in 0..indel_length{
+                            mutseq.remove(i);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        //println!("{:?}", mutseq.iter().collect::<String>());
+        //insert to vector
+        randomvec.push(mutseq.iter().collect::<String>());
+    }
+    randomvec
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(profile: ErrorProfile, seed: u64) -> SimulationConfig {
+        SimulationConfig { length: 400, reads: 20, profile, reverse_fraction: 0.3, chimera_fraction: 0.2, seed }
+    }
+
+    // every read base and every truth base of the read's pieces is in the true alignment once,
+    // and the aligned bases agree with the truth on the strand of their piece
+    fn assert_truth_matches(simulation: &Simulation) {
+        for read in &simulation.reads {
+            let mut read_positions = vec![];
+            let mut truth_positions = vec![];
+            for op in &read.alignment {
+                let (truth_pos, read_pos, matched) = match *op {
+                    TruthOp::Match(truth_pos, read_pos) => (truth_pos, read_pos, true),
+                    TruthOp::Mismatch(truth_pos, read_pos) => (truth_pos, read_pos, false),
+                    TruthOp::Ins(read_pos) => {
+                        read_positions.push(read_pos);
+                        continue;
+                    }
+                    TruthOp::Del(truth_pos) => {
+                        truth_positions.push(truth_pos);
+                        continue;
+                    }
+                };
+                read_positions.push(read_pos);
+                truth_positions.push(truth_pos);
+                let segment = read.segments.iter().find(|segment| (segment.read_start..segment.read_end).contains(&read_pos)).unwrap();
+                assert!((segment.truth_start..segment.truth_end).contains(&truth_pos));
+                let base = if segment.orientation == Orientation::Reverse { reverse_complement(&[simulation.truth[truth_pos]])[0] } else { simulation.truth[truth_pos] };
+                assert_eq!(read.seq[read_pos] == base, matched);
+            }
+            read_positions.sort_unstable();
+            assert_eq!(read_positions, (0..read.seq.len()).collect::<Vec<usize>>());
+            let mut expected: Vec<usize> = read.segments.iter().flat_map(|segment| segment.truth_start..segment.truth_end).collect();
+            expected.sort_unstable();
+            truth_positions.sort_unstable();
+            assert_eq!(truth_positions, expected);
+            assert_eq!(read.segments.last().map(|segment| segment.read_end), Some(read.seq.len()));
+        }
+    }
+
+    #[test]
+    fn seeded_and_true_to_the_reads() {
+        for (name, profile) in [("uniform", ErrorProfile::uniform(0.03, 0.03, 0.03)), ("ont", ErrorProfile::ont()), ("clr", ErrorProfile::pacbio_clr()), ("hifi", ErrorProfile::pacbio_hifi())] {
+            let simulation = simulate(&config(profile, 7));
+            let again = simulate(&config(profile, 7));
+            assert_eq!(simulation.truth, again.truth);
+            assert_eq!(simulation.sequences(), again.sequences());
+            for (read, other) in simulation.reads.iter().zip(&again.reads) {
+                assert_eq!((&read.segments, &read.alignment), (&other.segments, &other.alignment));
+            }
+            assert_ne!(simulation.sequences(), simulate(&config(profile, 8)).sequences(), "{}", name);
+            assert!(simulation.reads.iter().any(|read| read.is_chimeric()));
+            assert!(simulation.reads.iter().any(|read| read.orientation() == Orientation::Reverse));
+            assert_truth_matches(&simulation);
+        }
+        let errors = |profile: ErrorProfile| simulate(&config(profile, 7)).reads.iter().map(|read| {
+            let (substituted, inserted, deleted) = read.error_counts();
+            substituted + inserted + deleted
+        }).sum::<usize>();
+        assert!(errors(ErrorProfile::pacbio_hifi()) < errors(ErrorProfile::ont()));
+    }
+}